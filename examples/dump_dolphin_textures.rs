@@ -0,0 +1,13 @@
+// cargo run --example dump_dolphin_textures --features png -- <iso> [out_dir]
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let iso_path = args.next().unwrap_or_else(|| "/home/alex/melee/melee_vanilla.iso".to_string());
+    let out_dir = args.next().unwrap_or_else(|| "GALE01".to_string());
+
+    let mut files = dat_tools::open_iso(&iso_path).unwrap();
+    let dump = dat_tools::dump_dolphin_textures(&mut files, &out_dir).unwrap();
+    println!("saved {} textures to {}", dump.saved, out_dir);
+    for filename in dump.skipped.iter() {
+        println!("skipped {}", filename);
+    }
+}
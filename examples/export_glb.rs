@@ -0,0 +1,21 @@
+// cargo run --example export_glb --features gltf -- <iso> [out.glb]
+use slp_parser::Character;
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let iso_path = args.next().unwrap_or_else(|| "/home/alex/melee/melee_vanilla.iso".to_string());
+    let out_path = args.next().unwrap_or_else(|| "fox.glb".to_string());
+
+    let mut files = dat_tools::open_iso(&iso_path).unwrap();
+    let data = dat_tools::get_fighter_data(&mut files, Character::Fox.neutral()).unwrap();
+
+    let animations = (0..data.action_table.len())
+        .filter_map(|i| {
+            let name = data.action_table[i].name.as_deref().and_then(dat_tools::dat::demangle_anim_name)?;
+            Some((name, data.action_animation(i)?))
+        })
+        .collect::<Vec<_>>();
+
+    data.model.save_glb(&out_path, &animations).unwrap();
+    println!("saved {} with {} animations", out_path, animations.len());
+}
@@ -27,14 +27,7 @@ fn main() {
         let textures = &model.textures;
         for t in textures.iter() {
             println!("textures/texture{:02}.png", i);
-            lodepng::encode_file(
-                format!("textures/texture{:02}.png", i), 
-                &t.rgba_data,
-                t.width,
-                t.height,
-                lodepng::ColorType::BGRA, // TODO
-                8
-            ).unwrap();
+            t.save_png(format!("textures/texture{:02}.png", i)).unwrap();
             i += 1;
         }
     }
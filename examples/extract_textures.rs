@@ -22,14 +22,7 @@ fn main() {
     let mut i = 0;
     for t in data.model.textures.iter() {
         println!("textures/texture{:02}.png", i);
-        lodepng::encode_file(
-            format!("textures/texture{:02}.png", i), 
-            &t.rgba_data,
-            t.width,
-            t.height,
-            lodepng::ColorType::BGRA, // TODO
-            8
-        ).unwrap();
+        t.save_png(format!("textures/texture{:02}.png", i)).unwrap();
         i += 1;
     }
 
@@ -37,14 +30,7 @@ fn main() {
         if let Some(ref model) = t.model {
             for t in model.textures.iter() {
                 println!("textures/texture{:02}.png", i);
-                lodepng::encode_file(
-                    format!("textures/texture{:02}.png", i), 
-                    &t.rgba_data,
-                    t.width,
-                    t.height,
-                    lodepng::ColorType::BGRA, // TODO
-                    8
-                ).unwrap();
+                t.save_png(format!("textures/texture{:02}.png", i)).unwrap();
                 i += 1;
             }
         }
@@ -0,0 +1,19 @@
+use dat_tools::dat::*;
+use dat_tools::isoparser::*;
+
+fn main() {
+    let file = std::fs::File::open("/home/alex/melee/melee_vanilla.iso").unwrap();
+    let mut files = ISODatFiles::new(file).unwrap();
+
+    let anim_dat = files.read_file("PlFxAJ.dat").unwrap();
+    let archive = DatArchive::new(anim_dat);
+
+    for entry in archive.entries.iter() {
+        println!("{:08X} {:6X} {}", entry.offset, entry.size, entry.root_names.join(", "));
+    }
+
+    if let Some(entry) = archive.find("PlyFox5K_Share_ACTION_Wait1_figatree") {
+        let wait = archive.open_entry(entry);
+        println!("Wait1: {} structs", wait.struct_cache.len());
+    }
+}
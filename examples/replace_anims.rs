@@ -0,0 +1,27 @@
+use dat_tools::isoparser::ISODatFiles;
+use dat_tools::dat::DatFile;
+use slp_parser::Character;
+
+// usage: replace_anims <iso> <figatree dat>...
+// animations are matched to actions by their root name
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let iso = match args.next() {
+        Some(path) => path,
+        None => {
+            eprintln!("no iso path passed");
+            return;
+        }
+    };
+
+    let animations: Vec<DatFile> = args
+        .map(|path| DatFile {
+            filename: path.as_str().into(),
+            data: std::fs::read(&path).unwrap().into(),
+        })
+        .collect();
+
+    let file = std::fs::File::options().read(true).write(true).open(iso).unwrap();
+    let mut files = ISODatFiles::new(file).unwrap();
+    dat_tools::replace_fighter_animations(&mut files, Character::Fox, &animations).unwrap();
+}
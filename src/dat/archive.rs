@@ -0,0 +1,262 @@
+use crate::dat::{DatFile, DatExtractError, HSDRawFile, Stream};
+use crate::parse_string;
+use ahash::{HashMap, HashMapExt};
+
+/// A container of concatenated dat files, such as PlXxAJ.dat.
+#[derive(Debug, Clone)]
+pub struct DatArchive {
+    pub dat: DatFile,
+    pub entries: Box<[DatArchiveEntry]>,
+
+    /// root name -> index into `entries`
+    pub name_lookup: HashMap<Box<str>, usize>,
+}
+
+#[derive(Debug, Clone)]
+pub struct DatArchiveEntry {
+    /// Offset into the container. Same as the offset in the fighter action table.
+    pub offset: usize,
+    pub size: usize,
+    pub root_names: Box<[Box<str>]>,
+}
+
+// embedded dat files start 0x20 aligned
+const ARCHIVE_ALIGNMENT: usize = 0x20;
+
+impl DatArchive {
+    /// Scans the container for embedded dat headers.
+    /// Data between embedded files that does not parse as a dat header is skipped.
+    pub fn new(dat: DatFile) -> Self {
+        let mut entries = Vec::new();
+        let mut name_lookup = HashMap::new();
+
+        let data: &[u8] = &dat.data;
+        let mut offset = 0;
+        while offset + 0x20 <= data.len() {
+            match parse_embedded_header(&data[offset..]) {
+                Some((size, root_names)) => {
+                    for name in root_names.iter() {
+                        name_lookup.entry(name.clone()).or_insert(entries.len());
+                    }
+                    entries.push(DatArchiveEntry { offset, size, root_names });
+                    offset += size.next_multiple_of(ARCHIVE_ALIGNMENT);
+                }
+                None => offset += ARCHIVE_ALIGNMENT,
+            }
+        }
+
+        Self {
+            dat,
+            entries: entries.into_boxed_slice(),
+            name_lookup,
+        }
+    }
+
+    pub fn find(&self, root_name: &str) -> Option<&DatArchiveEntry> {
+        self.name_lookup.get(root_name).map(|&i| &self.entries[i])
+    }
+
+    pub fn entry_data(&self, entry: &DatArchiveEntry) -> &[u8] {
+        &self.dat.data[entry.offset..entry.offset + entry.size]
+    }
+
+    pub fn open_entry(&self, entry: &DatArchiveEntry) -> HSDRawFile<'_> {
+        HSDRawFile::open(Stream::new(self.entry_data(entry)))
+    }
+
+    /// Opens the embedded dat file that defines the root.
+    pub fn open(&self, root_name: &str) -> Option<HSDRawFile<'_>> {
+        self.find(root_name).map(|entry| self.open_entry(entry))
+    }
+
+    /// Copies the embedded dat file out of the container.
+    /// The filename is the first root name.
+    pub fn extract(&self, entry: &DatArchiveEntry) -> DatFile {
+        let filename = entry.root_names.first().map(|name| &**name).unwrap_or("");
+        DatFile {
+            filename: filename.into(),
+            data: self.entry_data(entry).into(),
+        }
+    }
+}
+
+/// Returns the file size and root names if the data starts with a valid dat header.
+fn parse_embedded_header(data: &[u8]) -> Option<(usize, Box<[Box<str>]>)> {
+    let word = |i: usize| -> Option<usize> {
+        let bytes = data.get(i..i+4)?;
+        Some(u32::from_be_bytes(bytes.try_into().unwrap()) as usize)
+    };
+
+    let fsize = word(0x00)?;
+    let data_size = word(0x04)?;
+    let reloc_count = word(0x08)?;
+    let root_count = word(0x0C)?;
+    let ref_count = word(0x10)?;
+
+    if fsize < 0x20 || fsize > data.len() || fsize % 4 != 0 { return None }
+    if root_count == 0 || data_size % 4 != 0 { return None }
+
+    let table_start = 0x20usize.checked_add(data_size)?.checked_add(reloc_count.checked_mul(4)?)?;
+    let string_start = table_start.checked_add(root_count.checked_add(ref_count)?.checked_mul(8)?)?;
+    if string_start > fsize { return None }
+
+    let strings = &data[string_start..fsize];
+    let mut root_names = Vec::with_capacity(root_count);
+    for i in 0..root_count {
+        let root_offset = word(table_start + i * 8)?;
+        if root_offset >= data_size { return None }
+
+        let string_offset = word(table_start + i * 8 + 4)?;
+        let name = parse_string(strings.get(string_offset..)?)?;
+        if name.is_empty() { return None }
+        root_names.push(name.into());
+    }
+
+    Some((fsize, root_names.into_boxed_slice()))
+}
+
+#[derive(Debug, Clone)]
+pub struct RebuiltAnimationArchive {
+    /// PlXx.dat with the action table offsets and sizes updated.
+    pub fighter_dat: DatFile,
+
+    /// The new PlXxAJ.dat.
+    pub anim_dat: DatFile,
+}
+
+/// Rebuilds PlXxAJ.dat, replacing animations with FigaTree dat files.
+///
+/// Replacements are matched to actions by root name, which is the name stored in the action table.
+/// Animations that are not replaced are copied from the old archive.
+/// Actions sharing an animation will still share it.
+pub fn rebuild_animation_archive(
+    fighter_dat: &DatFile,
+    anim_dat: &DatFile,
+    replacements: &[DatFile],
+) -> Result<RebuiltAnimationArchive, DatExtractError> {
+    // root name -> index into replacements
+    let mut replacement_lookup: HashMap<Box<str>, usize> = HashMap::new();
+    for (i, replacement) in replacements.iter().enumerate() {
+        let (_, root_names) = parse_embedded_header(&replacement.data)
+            .ok_or(DatExtractError::InvalidDatFile)?;
+        for name in root_names.into_vec() {
+            replacement_lookup.insert(name, i);
+        }
+    }
+
+    let fighter_hsd = HSDRawFile::new(fighter_dat);
+    let fighter_root = fighter_hsd.roots.first().ok_or(DatExtractError::InvalidDatFile)?;
+    let action_table_struct = fighter_root.hsd_struct.try_get_reference(0x0C)
+        .ok_or(DatExtractError::InvalidDatFile)?;
+    let action_table_offset = *fighter_hsd.struct_cache_to_offset.get(&action_table_struct)
+        .ok_or(DatExtractError::InvalidDatFile)?;
+
+    #[derive(Copy, Clone)]
+    enum Source { Old(usize, usize), Replacement(usize) }
+
+    let mut new_fighter_data = fighter_dat.data.to_vec();
+    let mut new_anim_data: Vec<u8> = Vec::with_capacity(anim_dat.data.len());
+    let mut written_old: HashMap<(usize, usize), (u32, u32)> = HashMap::new();
+    let mut written_replacements: HashMap<usize, (u32, u32)> = HashMap::new();
+
+    for i in 0..(action_table_struct.len() / 0x18) {
+        let action = action_table_struct.get_embedded_struct(i * 0x18, 0x18);
+        let old_offset = action.get_u32(0x04) as usize;
+        let old_size = action.get_u32(0x08) as usize;
+
+        let name = action.try_get_buffer(0x00).and_then(parse_string);
+        let source = match name.and_then(|n| replacement_lookup.get(n)) {
+            Some(&i) => Source::Replacement(i),
+            None if old_offset == 0 && old_size == 0 => continue,
+            None => Source::Old(old_offset, old_size),
+        };
+
+        let written = match source {
+            Source::Old(offset, size) => written_old.get(&(offset, size)).copied(),
+            Source::Replacement(i) => written_replacements.get(&i).copied(),
+        };
+
+        let (new_offset, new_size) = match written {
+            Some(written) => written,
+            None => {
+                let bytes = match source {
+                    Source::Old(offset, size) => anim_dat.data.get(offset..offset+size)
+                        .ok_or(DatExtractError::InvalidDatFile)?,
+                    Source::Replacement(i) => &replacements[i].data,
+                };
+
+                let new_offset = new_anim_data.len();
+                new_anim_data.extend_from_slice(bytes);
+                new_anim_data.resize(new_anim_data.len().next_multiple_of(ARCHIVE_ALIGNMENT), 0);
+
+                let written = (new_offset as u32, bytes.len() as u32);
+                match source {
+                    Source::Old(offset, size) => written_old.insert((offset, size), written),
+                    Source::Replacement(i) => written_replacements.insert(i, written),
+                };
+                written
+            }
+        };
+
+        let entry_offset = action_table_offset + i * 0x18;
+        new_fighter_data[entry_offset+0x04..entry_offset+0x08].copy_from_slice(&new_offset.to_be_bytes());
+        new_fighter_data[entry_offset+0x08..entry_offset+0x0C].copy_from_slice(&new_size.to_be_bytes());
+    }
+
+    Ok(RebuiltAnimationArchive {
+        fighter_dat: DatFile {
+            filename: fighter_dat.filename.clone(),
+            data: new_fighter_data.into_boxed_slice().into(),
+        },
+        anim_dat: DatFile {
+            filename: anim_dat.filename.clone(),
+            data: new_anim_data.into_boxed_slice().into(),
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dat::test_dat::{build_dat, write_u32};
+
+    #[test]
+    fn finds_embedded_dat_files() {
+        let mut data = vec![0u8; 0x24];
+        write_u32(&mut data, 0x00, 0xAAAAAAAA);
+        let first = build_dat(&data, &[], &[(0x00, "Wait1_a")], &[]);
+
+        let mut data = vec![0u8; 0x10];
+        write_u32(&mut data, 0x00, 0xBBBBBBBB);
+        write_u32(&mut data, 0x08, 0xCCCCCCCC);
+        let second = build_dat(&data, &[], &[(0x00, "Walk1_a"), (0x08, "Run1_ab")], &[]);
+
+        // first is padded to the alignment, then followed by a block of zeros that isn't a dat file
+        assert_eq!(first.data.len(), 0x54);
+        let mut container = first.data.to_vec();
+        container.resize(0x80, 0);
+        container.extend_from_slice(&second.data);
+        container.resize(container.len().next_multiple_of(ARCHIVE_ALIGNMENT), 0);
+
+        let archive = DatArchive::new(DatFile { filename: "PlTsAJ.dat".into(), data: container.into() });
+
+        let entries: Vec<(usize, usize, Vec<&str>)> = archive.entries.iter()
+            .map(|e| (e.offset, e.size, e.root_names.iter().map(|n| &**n).collect()))
+            .collect();
+        assert_eq!(entries, vec![
+            (0x00, first.data.len(), vec!["Wait1_a"]),
+            (0x80, second.data.len(), vec!["Walk1_a", "Run1_ab"]),
+        ]);
+
+        assert_eq!(archive.find("Run1_ab").unwrap().offset, 0x80);
+        assert!(archive.find("Jump1_a").is_none());
+
+        let extracted = archive.extract(archive.find("Walk1_a").unwrap());
+        assert_eq!(&*extracted.filename, "Walk1_a");
+        assert_eq!(&*extracted.data, &*second.data);
+
+        let run = archive.open("Run1_ab").unwrap();
+        assert_eq!(run.roots[1].root_string, "Run1_ab");
+        assert_eq!(run.roots[1].hsd_struct.get_u32(0x00), 0xCCCCCCCC);
+    }
+}
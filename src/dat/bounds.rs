@@ -0,0 +1,157 @@
+use crate::dat::{Animation, AnimationFrame, Model, skinning_transform, skinning::is_rigid};
+use glam::f32::{Mat4, Vec3};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct BoundingSphere {
+    pub centre: Vec3,
+    pub radius: f32,
+}
+
+impl Aabb {
+    /// Contains nothing. Extending it by a point gives a box around that point.
+    pub const EMPTY: Aabb = Aabb { min: Vec3::INFINITY, max: Vec3::NEG_INFINITY };
+
+    pub fn from_points(points: impl IntoIterator<Item=Vec3>) -> Self {
+        let mut aabb = Aabb::EMPTY;
+        for p in points { aabb.extend(p); }
+        aabb
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min.cmpgt(self.max).any()
+    }
+
+    pub fn extend(&mut self, point: Vec3) {
+        self.min = self.min.min(point);
+        self.max = self.max.max(point);
+    }
+
+    pub fn union(self, other: Aabb) -> Aabb {
+        Aabb { min: self.min.min(other.min), max: self.max.max(other.max) }
+    }
+
+    pub fn centre(&self) -> Vec3 {
+        (self.min + self.max) * 0.5
+    }
+
+    pub fn half_extents(&self) -> Vec3 {
+        (self.max - self.min) * 0.5
+    }
+
+    /// Box around the transformed corners.
+    pub fn transform(&self, transform: &Mat4) -> Aabb {
+        if self.is_empty() { return Aabb::EMPTY }
+
+        Aabb::from_points((0..8).map(|i| {
+            let corner = Vec3::new(
+                if i & 1 == 0 { self.min.x } else { self.max.x },
+                if i & 2 == 0 { self.min.y } else { self.max.y },
+                if i & 4 == 0 { self.min.z } else { self.max.z },
+            );
+            transform.transform_point3(corner)
+        }))
+    }
+
+    /// Sphere around the box. Zero radius if the box is empty.
+    pub fn bounding_sphere(&self) -> BoundingSphere {
+        if self.is_empty() {
+            return BoundingSphere { centre: Vec3::ZERO, radius: 0.0 };
+        }
+
+        BoundingSphere { centre: self.centre(), radius: self.half_extents().length() }
+    }
+}
+
+/// A box for each bone around the vertices it influences, in the bone's bind space.
+///
+/// Posed bounds are the union of the bone boxes moved by the posed bone transforms.
+/// Enveloped vertices lie between their bones' posed positions, so these bounds contain the skinned mesh.
+/// The exception is single bound vertices whose DOBJ's joint is animated, as that joint's motion is not boxed.
+#[derive(Clone, Debug)]
+pub struct ModelBounds {
+    pub bones: Box<[Aabb]>,
+}
+
+/// Bounds of every frame of an animation.
+#[derive(Clone, Debug)]
+pub struct AnimationBounds {
+    /// One for each integer frame from 0 to `Animation::end_frame`.
+    pub frames: Box<[Aabb]>,
+
+    /// Union of all frames.
+    pub max_extent: Aabb,
+}
+
+impl ModelBounds {
+    pub fn new(model: &Model) -> Self {
+        let bind_pose = AnimationFrame::new_default_pose(model);
+        let world_transforms = model.world_transforms(&bind_pose);
+        let owners = model.vertex_owners();
+        let mut bones = vec![Aabb::EMPTY; model.bones.len()].into_boxed_slice();
+        let bone_count = world_transforms.len().min(model.inv_world_transforms.len());
+
+        for (v, &owner) in model.vertex_attributes.iter().zip(owners.iter()) {
+            let transform = skinning_transform(v, owner as usize, &world_transforms, &model.inv_world_transforms);
+            let bind_pos = transform.transform_point3(v.pos);
+            let weights = v.weights;
+            let v_bones = v.bones;
+
+            let mut extend = |bone: usize| {
+                if let (Some(aabb), Some(inv_world)) = (bones.get_mut(bone), model.inv_world_transforms.get(bone)) {
+                    aabb.extend(inv_world.transform_point3(bind_pos));
+                }
+            };
+
+            if is_rigid(v, bone_count) {
+                extend(owner as usize);
+            } else {
+                for (&b, &w) in v_bones.iter().zip(weights.iter()) {
+                    if w != 0.0 { extend(b as usize); }
+                }
+            }
+        }
+
+        ModelBounds { bones }
+    }
+
+    /// Box of each bone in the pose.
+    pub fn posed_bones(&self, model: &Model, frame: &AnimationFrame) -> Box<[Aabb]> {
+        let world_transforms = model.world_transforms(frame);
+        self.bones.iter().zip(world_transforms.iter())
+            .map(|(aabb, world)| aabb.transform(world))
+            .collect()
+    }
+
+    /// Bounds of the whole model in the pose.
+    pub fn at_frame(&self, model: &Model, frame: &AnimationFrame) -> Aabb {
+        let world_transforms = model.world_transforms(frame);
+        self.bones.iter().zip(world_transforms.iter())
+            .fold(Aabb::EMPTY, |bounds, (aabb, world)| bounds.union(aabb.transform(world)))
+    }
+
+    pub fn animation(&self, model: &Model, animation: &Animation) -> AnimationBounds {
+        let frame_count = animation.end_frame().max(0.0).ceil() as usize + 1;
+        let mut frame = AnimationFrame::new_default_pose(model);
+
+        let frames: Box<[Aabb]> = (0..frame_count).map(|f| {
+            frame.apply_animation(model, animation, f as f32);
+            self.at_frame(model, &frame)
+        }).collect();
+        let max_extent = frames.iter().fold(Aabb::EMPTY, |bounds, &aabb| bounds.union(aabb));
+
+        AnimationBounds { frames, max_extent }
+    }
+}
+
+impl Model {
+    /// Exact bounds of the skinned mesh. See `ModelBounds` for faster, looser bounds.
+    pub fn skinned_bounds(&self, frame: &AnimationFrame) -> Aabb {
+        Aabb::from_points(self.skin(frame).into_iter().map(|(pos, _)| pos))
+    }
+}
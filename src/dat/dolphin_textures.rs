@@ -0,0 +1,104 @@
+use crate::dat::{InternalTextureFormat, TOBJ, tex_filter};
+use xxhash_rust::xxh64::xxh64;
+
+/// The name Dolphin uses to dump and load custom textures,
+/// `tex1_{w}x{h}[_m]_{texture hash}[_{tlut hash}]_{format}`.
+///
+/// Custom mip levels are named `{name}_mip{level}`.
+// Dolphin: VideoCommon/TextureInfo.cpp (CalculateTextureName)
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct DolphinTextureID {
+    pub width: usize,
+    pub height: usize,
+    pub mipmaps: bool,
+    pub texture_hash: u64,
+    pub tlut_hash: Option<u64>,
+    pub format: InternalTextureFormat,
+}
+
+impl DolphinTextureID {
+    /// `texture_data` is the raw image buffer. Only the base level is hashed.
+    /// `tlut_data` is the raw palette, required for CI4, CI8 and CI14X2.
+    pub fn new(
+        format: InternalTextureFormat,
+        width: usize,
+        height: usize,
+        mipmaps: bool,
+        texture_data: &[u8],
+        tlut_data: Option<&[u8]>,
+    ) -> Option<Self> {
+        let texture_data = texture_data.get(..format.encoded_size(width, height))?;
+
+        // Only the palette entries the texture uses are hashed.
+        let min_max = |(min, max): (usize, usize), i: usize| (min.min(i), max.max(i));
+        let used_range = match format {
+            InternalTextureFormat::CI4 => Some(texture_data.iter()
+                .flat_map(|&b| [b & 0xF, b >> 4])
+                .map(|i| i as usize)
+                .fold((usize::MAX, 0), min_max)),
+            InternalTextureFormat::CI8 => Some(texture_data.iter()
+                .map(|&i| i as usize)
+                .fold((usize::MAX, 0), min_max)),
+            InternalTextureFormat::CI14X2 => Some(texture_data.chunks_exact(2)
+                .map(|i| (u16::from_be_bytes([i[0], i[1]]) & 0x3FFF) as usize)
+                .fold((usize::MAX, 0), min_max)),
+            _ => None,
+        };
+
+        let tlut_hash = match used_range {
+            None => None,
+            Some((min, max)) => {
+                let tlut_data = tlut_data?;
+                let start = (2 * min).min(tlut_data.len());
+                let end = (2 * (max + 1)).min(tlut_data.len());
+                Some(xxh64(&tlut_data[start..end], 0))
+            }
+        };
+
+        Some(DolphinTextureID {
+            width,
+            height,
+            mipmaps,
+            texture_hash: xxh64(texture_data, 0),
+            tlut_hash,
+            format,
+        })
+    }
+
+    pub fn name(&self) -> String {
+        self.to_string()
+    }
+
+    pub fn mip_name(&self, level: usize) -> String {
+        format!("{}_mip{}", self, level)
+    }
+}
+
+impl std::fmt::Display for DolphinTextureID {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "tex1_{}x{}", self.width, self.height)?;
+        if self.mipmaps { write!(f, "_m")?; }
+        write!(f, "_{:016x}", self.texture_hash)?;
+        if let Some(tlut_hash) = self.tlut_hash { write!(f, "_{:016x}", tlut_hash)?; }
+        write!(f, "_{}", self.format as u32)
+    }
+}
+
+impl<'a> TOBJ<'a> {
+    /// None if the TOBJ has no image or the image buffer is too small.
+    pub fn dolphin_texture_id(&self) -> Option<DolphinTextureID> {
+        let hsd_image = self.hsd_struct.try_get_reference(0x4C)?;
+        let texture_data = hsd_image.try_get_buffer(0x00)?;
+        let width = hsd_image.get_u16(0x04) as usize;
+        let height = hsd_image.get_u16(0x06) as usize;
+        let format = InternalTextureFormat::new(hsd_image.get_u32(0x08))?;
+
+        // Dolphin uses the sampler's mip filter, which HSD sets from the TOBJ LOD
+        let mipmaps = self.lod().min_filter >= tex_filter::NEAR_MIP_NEAR;
+
+        let tlut_data = self.hsd_struct.try_get_reference(0x50)
+            .and_then(|tlut| tlut.try_get_buffer(0x00));
+
+        DolphinTextureID::new(format, width, height, mipmaps, texture_data, tlut_data)
+    }
+}
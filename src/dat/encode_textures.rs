@@ -0,0 +1,817 @@
+#![allow(clippy::upper_case_acronyms)]
+
+use crate::dat::{InternalTextureFormat, TLUTFormat, decode_palette};
+
+use ahash::{HashMap, HashMapExt};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+// Encoders are the inverse of the decode_*_image functions in textures.rs.
+// Pixels use the same packing as the decoders: r | g << 8 | b << 16 | a << 24.
+// Padding pixels outside the image repeat the nearest edge pixel.
+
+#[derive(Clone, Debug)]
+pub struct EncodedImage {
+    pub format: InternalTextureFormat,
+    pub width: usize,
+    pub height: usize,
+    pub data: Box<[u8]>,
+
+    /// Only present for paletted formats.
+    pub tlut: Option<EncodedTLUT>,
+}
+
+#[derive(Clone, Debug)]
+pub struct EncodedTLUT {
+    pub format: TLUTFormat,
+    pub colour_count: u16,
+    pub data: Box<[u8]>,
+}
+
+const KMEANS_ITERATIONS: usize = 4;
+const CMP_REFINE_ITERATIONS: usize = 2;
+
+/// `tlut_format` is only used for paletted formats. If None, it is chosen from the image.
+pub fn encode_data(
+    format: InternalTextureFormat,
+    width: usize,
+    height: usize,
+    rgba_data: &[u32],
+    tlut_format: Option<TLUTFormat>,
+) -> EncodedImage {
+    assert!(rgba_data.len() >= width * height);
+    let rgba_data = &rgba_data[..width * height];
+
+    let mut data = vec![0u8; format.encoded_size(width, height)].into_boxed_slice();
+    let mut tlut = None;
+
+    match format {
+        InternalTextureFormat::CMP => encode_compressed_image(rgba_data, width, height, &mut data),
+        InternalTextureFormat::I4 => encode_i4_image(rgba_data, width, height, &mut data),
+        InternalTextureFormat::I8 => encode_i8_image(rgba_data, width, height, &mut data),
+        InternalTextureFormat::IA4 => encode_ia4_image(rgba_data, width, height, &mut data),
+        InternalTextureFormat::IA8 => encode_ia8_image(rgba_data, width, height, &mut data),
+        InternalTextureFormat::RGBA8 => encode_rgba8_image(rgba_data, width, height, &mut data),
+        InternalTextureFormat::RGB565 => encode_rgb565_image(rgba_data, width, height, &mut data),
+        InternalTextureFormat::RGB5A3 => encode_rgb5a3_image(rgba_data, width, height, &mut data),
+        InternalTextureFormat::CI4 | InternalTextureFormat::CI8 | InternalTextureFormat::CI14X2 => {
+            let max_colours = match format {
+                InternalTextureFormat::CI4 => 16,
+                InternalTextureFormat::CI8 => 256,
+                _ => 0x4000,
+            };
+            let tlut_format = tlut_format.unwrap_or_else(|| choose_tlut_format(rgba_data));
+
+            let palette = quantize_palette(rgba_data, max_colours);
+            let tlut_data = encode_palette(&palette, tlut_format);
+
+            // match pixels against the palette the game will see
+            let decoded_palette = decode_palette(palette.len(), tlut_format, &tlut_data);
+            match format {
+                InternalTextureFormat::CI4 => encode_ci4_image(rgba_data, &decoded_palette, width, height, &mut data),
+                InternalTextureFormat::CI8 => encode_ci8_image(rgba_data, &decoded_palette, width, height, &mut data),
+                _ => encode_ci14x2_image(rgba_data, &decoded_palette, width, height, &mut data),
+            }
+
+            tlut = Some(EncodedTLUT {
+                format: tlut_format,
+                colour_count: palette.len() as u16,
+                data: tlut_data,
+            });
+        }
+    }
+
+    EncodedImage { format, width, height, data, tlut }
+}
+
+/// IA8 for greyscale images, RGB565 for opaque images, RGB5A3 otherwise.
+pub fn choose_tlut_format(rgba_data: &[u32]) -> TLUTFormat {
+    let greyscale = rgba_data.iter().all(|&c| {
+        let [r, g, b, _] = components(c);
+        r == g && g == b
+    });
+    let opaque = rgba_data.iter().all(|&c| c >> 24 == 0xFF);
+
+    if greyscale {
+        TLUTFormat::IA8
+    } else if opaque {
+        TLUTFormat::RGB565
+    } else {
+        TLUTFormat::RGB5A3
+    }
+}
+
+// inverse of decode_palette
+pub fn encode_palette(palette: &[u32], format: TLUTFormat) -> Box<[u8]> {
+    let mut data = Vec::with_capacity(palette.len() * 2);
+
+    for &colour in palette.iter() {
+        let pixel = match format {
+            TLUTFormat::IA8 => {
+                let [_, _, _, a] = components(colour);
+                (a << 8) | intensity(colour)
+            }
+            TLUTFormat::RGB565 => encode_rgb565(colour),
+            TLUTFormat::RGB5A3 => encode_rgb5a3(colour),
+        };
+
+        data.extend_from_slice(&(pixel as u16).to_be_bytes());
+    }
+
+    data.into_boxed_slice()
+}
+
+/// Median cut followed by k-means refinement.
+/// Images with at most `max_colours` unique colours are not changed.
+pub fn quantize_palette(rgba_data: &[u32], max_colours: usize) -> Box<[u32]> {
+    let mut counts: HashMap<u32, u64> = HashMap::new();
+    for &c in rgba_data.iter() {
+        *counts.entry(c).or_insert(0) += 1;
+    }
+
+    let mut colours: Vec<(u32, u64)> = counts.into_iter().collect();
+    colours.sort_unstable();
+
+    if colours.len() <= max_colours {
+        return colours.iter().map(|&(c, _)| c).collect();
+    }
+
+    // median cut: split the box with the widest channel until there are enough boxes
+    let mut boxes: Vec<std::ops::Range<usize>> = Vec::with_capacity(max_colours);
+    boxes.push(0..colours.len());
+
+    // (channel range, box index, channel) of each box with colours to split,
+    // widest first, then the earliest box
+    let mut splittable = BinaryHeap::new();
+    splittable.push(widest_channel(&colours, 0, 0..colours.len()));
+
+    while boxes.len() < max_colours {
+        let (_, Reverse(i), channel) = match splittable.pop() {
+            Some(widest) => widest,
+            None => break,
+        };
+
+        let b = boxes[i].clone();
+        colours[b.clone()].sort_unstable_by_key(|&(c, _)| components(c)[channel]);
+
+        let total: u64 = colours[b.clone()].iter().map(|&(_, n)| n).sum();
+        let mut count = 0;
+        let mut split = b.end - 1;
+        for j in b.clone() {
+            count += colours[j].1;
+            if count * 2 >= total {
+                split = j + 1;
+                break;
+            }
+        }
+        let split = split.clamp(b.start + 1, b.end - 1);
+
+        boxes[i] = b.start..split;
+        boxes.push(split..b.end);
+        for j in [i, boxes.len() - 1] {
+            if boxes[j].len() >= 2 { splittable.push(widest_channel(&colours, j, boxes[j].clone())); }
+        }
+    }
+
+    let mut palette: Vec<[f64; 4]> = boxes.iter()
+        .map(|b| {
+            let mut sum = [0.0; 4];
+            let mut total = 0.0;
+            for &(c, n) in colours[b.clone()].iter() {
+                let c = components(c);
+                for k in 0..4 { sum[k] += c[k] as f64 * n as f64; }
+                total += n as f64;
+            }
+            sum.map(|s| s / total)
+        })
+        .collect();
+
+    for _ in 0..KMEANS_ITERATIONS {
+        let mut sums = vec![([0.0f64; 4], 0.0f64); palette.len()];
+        let tree = PaletteTree::new(palette.iter().map(|p| p.map(|v| v as f32)));
+
+        for &(c, n) in colours.iter() {
+            let nearest = tree.nearest(components_f32(c));
+            let c = components(c).map(|v| v as f64);
+
+            for (sum, v) in sums[nearest].0.iter_mut().zip(c) { *sum += v * n as f64; }
+            sums[nearest].1 += n as f64;
+        }
+
+        for (p, (sum, total)) in palette.iter_mut().zip(sums) {
+            if total > 0.0 { *p = sum.map(|s| s / total); }
+        }
+    }
+
+    palette.iter()
+        .map(|p| {
+            let [r, g, b, a] = p.map(|v| v.round().clamp(0.0, 255.0) as u32);
+            pack(r, g, b, a)
+        })
+        .collect()
+}
+
+// -> (channel range, Reverse(box index), channel) for the widest channel, the first on ties
+fn widest_channel(colours: &[(u32, u64)], box_index: usize, b: std::ops::Range<usize>) -> (u32, Reverse<usize>, usize) {
+    let (range, channel) = (0..4)
+        .map(|channel| {
+            let (min, max) = colours[b.clone()].iter()
+                .map(|&(c, _)| components(c)[channel])
+                .fold((255, 0), |(min, max), v| (min.min(v), max.max(v)));
+            (max - min, channel)
+        })
+        .fold((0, 0), |widest, (range, channel)| if range > widest.0 { (range, channel) } else { widest });
+
+    (range, Reverse(box_index), channel)
+}
+
+/// Nearest colour lookup for palettes, a k-d tree over the RGBA components.
+struct PaletteTree {
+    // (palette index, colour), each range is split by its median on the axis for its depth
+    nodes: Vec<(usize, [f32; 4])>,
+}
+
+impl PaletteTree {
+    fn new(palette: impl Iterator<Item=[f32; 4]>) -> Self {
+        fn build(nodes: &mut [(usize, [f32; 4])], depth: usize) {
+            if nodes.len() <= 1 { return }
+
+            let axis = depth % 4;
+            let mid = nodes.len() / 2;
+            nodes.select_nth_unstable_by(mid, |a, b| a.1[axis].total_cmp(&b.1[axis]));
+
+            let (left, right) = nodes.split_at_mut(mid);
+            build(left, depth + 1);
+            build(&mut right[1..], depth + 1);
+        }
+
+        let mut nodes: Vec<(usize, [f32; 4])> = palette.enumerate().collect();
+        build(&mut nodes, 0);
+        PaletteTree { nodes }
+    }
+
+    /// Index of the closest colour by squared distance, the lowest index on ties.
+    /// 0 if the palette is empty.
+    fn nearest(&self, colour: [f32; 4]) -> usize {
+        fn search(nodes: &[(usize, [f32; 4])], depth: usize, colour: [f32; 4], best: &mut (f32, usize)) {
+            if nodes.is_empty() { return }
+
+            let axis = depth % 4;
+            let mid = nodes.len() / 2;
+            let (i, p) = nodes[mid];
+            let dist: f32 = (0..4).map(|k| (p[k] - colour[k]).powi(2)).sum();
+            if dist < best.0 || (dist == best.0 && i < best.1) { *best = (dist, i); }
+
+            let diff = colour[axis] - p[axis];
+            let (near, far) = if diff < 0.0 {
+                (&nodes[..mid], &nodes[mid + 1..])
+            } else {
+                (&nodes[mid + 1..], &nodes[..mid])
+            };
+            search(near, depth + 1, colour, best);
+            if diff * diff <= best.0 { search(far, depth + 1, colour, best); }
+        }
+
+        let mut best = (f32::INFINITY, 0);
+        search(&self.nodes, 0, colour, &mut best);
+        best.1
+    }
+}
+
+// inverse of decode_rgba8_image
+pub fn encode_rgba8_image(rgba_buffer: &[u32], width: usize, height: usize, data: &mut [u8]) {
+    for (i, pixel) in tiled_pixels(rgba_buffer, width, height, InternalTextureFormat::RGBA8).enumerate() {
+        let [r, g, b, a] = components(pixel);
+
+        // each block is 16 AR pairs followed by 16 GB pairs
+        let out = (i / 16) * 64 + (i % 16) * 2;
+        data[out] = a as u8;
+        data[out + 0x01] = r as u8;
+        data[out + 0x20] = g as u8;
+        data[out + 0x21] = b as u8;
+    }
+}
+
+// inverse of decode_rgb565_image
+pub fn encode_rgb565_image(rgba_buffer: &[u32], width: usize, height: usize, data: &mut [u8]) {
+    for (i, pixel) in tiled_pixels(rgba_buffer, width, height, InternalTextureFormat::RGB565).enumerate() {
+        data[i*2..i*2+2].copy_from_slice(&(encode_rgb565(pixel) as u16).to_be_bytes());
+    }
+}
+
+// inverse of decode_rgb5a3_image
+pub fn encode_rgb5a3_image(rgba_buffer: &[u32], width: usize, height: usize, data: &mut [u8]) {
+    for (i, pixel) in tiled_pixels(rgba_buffer, width, height, InternalTextureFormat::RGB5A3).enumerate() {
+        data[i*2..i*2+2].copy_from_slice(&(encode_rgb5a3(pixel) as u16).to_be_bytes());
+    }
+}
+
+// inverse of decode_i4_image
+pub fn encode_i4_image(rgba_buffer: &[u32], width: usize, height: usize, data: &mut [u8]) {
+    for (i, pixel) in tiled_pixels(rgba_buffer, width, height, InternalTextureFormat::I4).enumerate() {
+        let i4 = quantize(intensity(pixel), 4) as u8;
+        if i % 2 == 0 {
+            data[i / 2] = i4 << 4;
+        } else {
+            data[i / 2] |= i4;
+        }
+    }
+}
+
+// inverse of decode_i8_image
+pub fn encode_i8_image(rgba_buffer: &[u32], width: usize, height: usize, data: &mut [u8]) {
+    for (i, pixel) in tiled_pixels(rgba_buffer, width, height, InternalTextureFormat::I8).enumerate() {
+        data[i] = intensity(pixel) as u8;
+    }
+}
+
+// inverse of decode_ia4_image
+pub fn encode_ia4_image(rgba_buffer: &[u32], width: usize, height: usize, data: &mut [u8]) {
+    for (i, pixel) in tiled_pixels(rgba_buffer, width, height, InternalTextureFormat::IA4).enumerate() {
+        let a = quantize(pixel >> 24, 4);
+        let i4 = quantize(intensity(pixel), 4);
+        data[i] = ((a << 4) | i4) as u8;
+    }
+}
+
+// inverse of decode_ia8_image
+pub fn encode_ia8_image(rgba_buffer: &[u32], width: usize, height: usize, data: &mut [u8]) {
+    for (i, pixel) in tiled_pixels(rgba_buffer, width, height, InternalTextureFormat::IA8).enumerate() {
+        data[i*2] = (pixel >> 24) as u8;
+        data[i*2+1] = intensity(pixel) as u8;
+    }
+}
+
+// inverse of decode_ci4_image
+pub fn encode_ci4_image(rgba_buffer: &[u32], palette: &[u32], width: usize, height: usize, data: &mut [u8]) {
+    let tree = PaletteTree::new(palette.iter().map(|&p| components_f32(p)));
+    let mut nearest_cache = HashMap::new();
+    for (i, pixel) in tiled_pixels(rgba_buffer, width, height, InternalTextureFormat::CI4).enumerate() {
+        let idx = *nearest_cache.entry(pixel).or_insert_with(|| tree.nearest(components_f32(pixel))) as u8;
+        if i % 2 == 0 {
+            data[i / 2] = idx << 4;
+        } else {
+            data[i / 2] |= idx & 0x0F;
+        }
+    }
+}
+
+// inverse of decode_ci8_image
+pub fn encode_ci8_image(rgba_buffer: &[u32], palette: &[u32], width: usize, height: usize, data: &mut [u8]) {
+    let tree = PaletteTree::new(palette.iter().map(|&p| components_f32(p)));
+    let mut nearest_cache = HashMap::new();
+    for (i, pixel) in tiled_pixels(rgba_buffer, width, height, InternalTextureFormat::CI8).enumerate() {
+        data[i] = *nearest_cache.entry(pixel).or_insert_with(|| tree.nearest(components_f32(pixel))) as u8;
+    }
+}
+
+// inverse of decode_ci14x2_image
+pub fn encode_ci14x2_image(rgba_buffer: &[u32], palette: &[u32], width: usize, height: usize, data: &mut [u8]) {
+    let tree = PaletteTree::new(palette.iter().map(|&p| components_f32(p)));
+    let mut nearest_cache = HashMap::new();
+    for (i, pixel) in tiled_pixels(rgba_buffer, width, height, InternalTextureFormat::CI14X2).enumerate() {
+        let idx = *nearest_cache.entry(pixel).or_insert_with(|| tree.nearest(components_f32(pixel))) as u16;
+        data[i*2..i*2+2].copy_from_slice(&(idx & 0x3FFF).to_be_bytes());
+    }
+}
+
+// inverse of decode_compressed_image
+//
+// 8x8 blocks of four DXT1 blocks, with big endian colours and reversed index order.
+// Pixels with alpha below 128 are made transparent.
+pub fn encode_compressed_image(rgba_buffer: &[u32], width: usize, height: usize, data: &mut [u8]) {
+    let mut out = 0;
+
+    for y in (0..height).step_by(8) {
+        for x in (0..width).step_by(8) {
+            for (sub_x, sub_y) in [(0, 0), (4, 0), (0, 4), (4, 4)] {
+                let mut block = [0u32; 16];
+                for (i, pixel) in block.iter_mut().enumerate() {
+                    let px = x + sub_x + i % 4;
+                    let py = y + sub_y + i / 4;
+                    *pixel = pixel_at(rgba_buffer, width, height, px, py);
+                }
+
+                data[out..out+8].copy_from_slice(&encode_cmp_block(&block));
+                out += 8;
+            }
+        }
+    }
+}
+
+fn encode_cmp_block(pixels: &[u32; 16]) -> [u8; 8] {
+    let opaque: Vec<[f32; 3]> = pixels.iter()
+        .filter(|&&c| c >> 24 >= 128)
+        .map(|&c| {
+            let [r, g, b, _] = components(c);
+            [r as f32, g as f32, b as f32]
+        })
+        .collect();
+
+    // fully transparent: three colour mode, every index is 3
+    if opaque.is_empty() { return [0, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF] }
+    let has_transparent = opaque.len() < 16;
+
+    // initial endpoints along the principal axis
+    let (e0, e1) = principal_endpoints(&opaque);
+    let mut endpoints = [to_rgb565(e0), to_rgb565(e1)];
+    let (mut best_error, mut best_block) = best_cmp_encoding(pixels, endpoints, has_transparent);
+
+    // least squares fit of the endpoints to the chosen indices
+    for _ in 0..CMP_REFINE_ITERATIONS {
+        let (fit0, fit1) = match least_squares_endpoints(pixels, &best_block) {
+            Some(fit) => fit,
+            None => break,
+        };
+        let fitted = [to_rgb565(fit0), to_rgb565(fit1)];
+        let (error, block) = best_cmp_encoding(pixels, fitted, has_transparent);
+        if error >= best_error { break }
+
+        endpoints = fitted;
+        best_error = error;
+        best_block = block;
+    }
+
+    // nudge each endpoint channel while it improves
+    let max = [31, 63, 31];
+    let mut improved = true;
+    while improved && best_error > 0 {
+        improved = false;
+        for endpoint in 0..2 {
+            for channel in 0..3 {
+                for delta in [-1i32, 1] {
+                    let v = endpoints[endpoint][channel] as i32 + delta;
+                    if v < 0 || v > max[channel] { continue }
+
+                    let mut candidate = endpoints;
+                    candidate[endpoint][channel] = v as u32;
+                    let (error, block) = best_cmp_encoding(pixels, candidate, has_transparent);
+                    if error < best_error {
+                        endpoints = candidate;
+                        best_error = error;
+                        best_block = block;
+                        improved = true;
+                    }
+                }
+            }
+        }
+    }
+
+    best_block
+}
+
+// tries both colour modes that the endpoints allow
+fn best_cmp_encoding(pixels: &[u32; 16], endpoints: [[u32; 3]; 2], has_transparent: bool) -> (u64, [u8; 8]) {
+    let a = pack_rgb565(endpoints[0]);
+    let b = pack_rgb565(endpoints[1]);
+
+    // c0 <= c1 selects three colour mode with transparency
+    let three_colour = evaluate_cmp_block(pixels, a.min(b), a.max(b));
+    if has_transparent || a == b { return three_colour }
+
+    let four_colour = evaluate_cmp_block(pixels, a.max(b), a.min(b));
+    if four_colour.0 <= three_colour.0 { four_colour } else { three_colour }
+}
+
+// matches the palette built by decode_compressed_image
+fn evaluate_cmp_block(pixels: &[u32; 16], c0: u16, c1: u16) -> (u64, [u8; 8]) {
+    let p0 = expand_rgb565(c0);
+    let p1 = expand_rgb565(c1);
+    let four_colour = c0 > c1;
+
+    let palette: [[u32; 3]; 4] = if four_colour {
+        [
+            p0, p1,
+            std::array::from_fn(|k| (2 * p0[k] + p1[k]) / 3),
+            std::array::from_fn(|k| (2 * p1[k] + p0[k]) / 3),
+        ]
+    } else {
+        [p0, p1, std::array::from_fn(|k| (p0[k] + p1[k]) / 2), [0; 3]]
+    };
+    let usable = if four_colour { 4 } else { 3 };
+
+    let mut error = 0u64;
+    let mut indices = 0u32;
+    for (i, &pixel) in pixels.iter().enumerate() {
+        let [r, g, b, a] = components(pixel);
+
+        let (idx, err) = if !four_colour && a < 128 {
+            (3, 0)
+        } else {
+            let (idx, mut err) = palette[..usable].iter()
+                .map(|p| {
+                    let dr = p[0] as i64 - r as i64;
+                    let dg = p[1] as i64 - g as i64;
+                    let db = p[2] as i64 - b as i64;
+                    (dr * dr + dg * dg + db * db) as u64
+                })
+                .enumerate()
+                .min_by_key(|&(_, err)| err)
+                .unwrap();
+
+            // transparent pixel in a block without transparency
+            if a < 128 { err += (a as u64).pow(2) + 255 * 255; }
+            (idx as u32, err)
+        };
+
+        error += err;
+        indices |= idx << (30 - 2 * i);
+    }
+
+    let mut block = [0u8; 8];
+    block[0..2].copy_from_slice(&c0.to_be_bytes());
+    block[2..4].copy_from_slice(&c1.to_be_bytes());
+    block[4..8].copy_from_slice(&indices.to_be_bytes());
+    (error, block)
+}
+
+fn least_squares_endpoints(pixels: &[u32; 16], block: &[u8; 8]) -> Option<([f32; 3], [f32; 3])> {
+    let c0 = u16::from_be_bytes([block[0], block[1]]);
+    let c1 = u16::from_be_bytes([block[2], block[3]]);
+    let indices = u32::from_be_bytes(block[4..8].try_into().unwrap());
+
+    // weight of the first endpoint for each index
+    let weights: [Option<f32>; 4] = if c0 > c1 {
+        [Some(1.0), Some(0.0), Some(2.0 / 3.0), Some(1.0 / 3.0)]
+    } else {
+        [Some(1.0), Some(0.0), Some(0.5), None]
+    };
+
+    let (mut aa, mut ab, mut bb) = (0.0f32, 0.0f32, 0.0f32);
+    let mut ax = [0.0f32; 3];
+    let mut bx = [0.0f32; 3];
+    for (i, &pixel) in pixels.iter().enumerate() {
+        let idx = (indices >> (30 - 2 * i)) & 0x03;
+        let w = match weights[idx as usize] {
+            Some(w) => w,
+            None => continue,
+        };
+        let [r, g, b, _] = components(pixel);
+
+        aa += w * w;
+        ab += w * (1.0 - w);
+        bb += (1.0 - w) * (1.0 - w);
+        for (k, v) in [r, g, b].into_iter().enumerate() {
+            ax[k] += w * v as f32;
+            bx[k] += (1.0 - w) * v as f32;
+        }
+    }
+
+    let det = aa * bb - ab * ab;
+    if det.abs() < 1e-6 { return None }
+
+    let e0 = std::array::from_fn(|k| ((bb * ax[k] - ab * bx[k]) / det).clamp(0.0, 255.0));
+    let e1 = std::array::from_fn(|k| ((aa * bx[k] - ab * ax[k]) / det).clamp(0.0, 255.0));
+    Some((e0, e1))
+}
+
+// extremes of the colours projected onto their principal axis
+fn principal_endpoints(colours: &[[f32; 3]]) -> ([f32; 3], [f32; 3]) {
+    let n = colours.len() as f32;
+    let mean: [f32; 3] = std::array::from_fn(|k| colours.iter().map(|c| c[k]).sum::<f32>() / n);
+
+    let mut covariance = [[0.0f32; 3]; 3];
+    for c in colours.iter() {
+        for i in 0..3 {
+            for j in 0..3 {
+                covariance[i][j] += (c[i] - mean[i]) * (c[j] - mean[j]);
+            }
+        }
+    }
+
+    // power iteration
+    let mut axis = [1.0f32, 1.0, 1.0];
+    for _ in 0..8 {
+        let next: [f32; 3] = std::array::from_fn(|i| (0..3).map(|j| covariance[i][j] * axis[j]).sum());
+        let len = next.iter().map(|v| v * v).sum::<f32>().sqrt();
+        if len < 1e-6 { return (mean, mean) }
+        axis = next.map(|v| v / len);
+    }
+
+    let (mut min, mut max) = (f32::MAX, f32::MIN);
+    for c in colours.iter() {
+        let t: f32 = (0..3).map(|k| (c[k] - mean[k]) * axis[k]).sum();
+        min = min.min(t);
+        max = max.max(t);
+    }
+
+    let e0 = std::array::from_fn(|k| (mean[k] + axis[k] * max).clamp(0.0, 255.0));
+    let e1 = std::array::from_fn(|k| (mean[k] + axis[k] * min).clamp(0.0, 255.0));
+    (e0, e1)
+}
+
+fn to_rgb565(c: [f32; 3]) -> [u32; 3] {
+    [
+        (c[0] * 31.0 / 255.0).round() as u32,
+        (c[1] * 63.0 / 255.0).round() as u32,
+        (c[2] * 31.0 / 255.0).round() as u32,
+    ]
+}
+
+fn pack_rgb565(c: [u32; 3]) -> u16 {
+    ((c[0] << 11) | (c[1] << 5) | c[2]) as u16
+}
+
+// same bit replication as decode_compressed_image
+fn expand_rgb565(c: u16) -> [u32; 3] {
+    let c = c as u32;
+    let r = (c >> 11) & 0x1F;
+    let g = (c >> 5) & 0x3F;
+    let b = c & 0x1F;
+    [(r << 3) | (r >> 2), (g << 2) | (g >> 4), (b << 3) | (b >> 2)]
+}
+
+// decode_rgb565_image expands by shifting
+fn encode_rgb565(colour: u32) -> u32 {
+    let [r, g, b, _] = components(colour);
+    let r = ((r + 4) >> 3).min(0x1F);
+    let g = ((g + 2) >> 2).min(0x3F);
+    let b = ((b + 4) >> 3).min(0x1F);
+    (r << 11) | (g << 5) | b
+}
+
+fn encode_rgb5a3(colour: u32) -> u32 {
+    let [r, g, b, a] = components(colour);
+    let a3 = quantize(a, 3);
+
+    // opaque RGB4A3 colours can't always be stored as RGB555, keep them exact
+    let exact = |bits: u32| [r, g, b].iter().all(|&v| expand(quantize(v, bits), bits) == v);
+
+    if a3 == 0x07 && (exact(5) || !exact(4)) {
+        // RGB555
+        0x8000 | (quantize(r, 5) << 10) | (quantize(g, 5) << 5) | quantize(b, 5)
+    } else {
+        // RGB4A3
+        (a3 << 12) | (quantize(r, 4) << 8) | (quantize(g, 4) << 4) | quantize(b, 4)
+    }
+}
+
+// Visits every pixel of the image padded to whole blocks, in GX block order.
+fn tiled_pixels(
+    rgba_buffer: &[u32],
+    width: usize,
+    height: usize,
+    format: InternalTextureFormat,
+) -> impl Iterator<Item=u32> + '_ {
+    tiled_coords(format, width, height).map(move |(x, y)| pixel_at(rgba_buffer, width, height, x, y))
+}
+
+/// Pixel coordinates in the order they are stored, including padding outside the image.
+pub(crate) fn tiled_coords(format: InternalTextureFormat, width: usize, height: usize) -> impl Iterator<Item=(usize, usize)> {
+    let (block_width, block_height) = format.block_dimensions();
+    let padded_width = width.next_multiple_of(block_width);
+    let padded_height = height.next_multiple_of(block_height);
+
+    (0..padded_height).step_by(block_height).flat_map(move |y| {
+        (0..padded_width).step_by(block_width).flat_map(move |x| {
+            (y..y+block_height).flat_map(move |y1| {
+                (x..x+block_width).map(move |x1| (x1, y1))
+            })
+        })
+    })
+}
+
+fn pixel_at(rgba_buffer: &[u32], width: usize, height: usize, x: usize, y: usize) -> u32 {
+    rgba_buffer[y.min(height - 1) * width + x.min(width - 1)]
+}
+
+// n bit value to 8 bits, same as the decoders
+fn expand(v: u32, bits: u32) -> u32 {
+    let max = (1 << bits) - 1;
+    (v * 255) / max
+}
+
+// 8 bit value to n bits, inverse of (v * 255) / max
+fn quantize(v: u32, bits: u32) -> u32 {
+    let max = (1 << bits) - 1;
+    (v * max + 127) / 255
+}
+
+fn intensity(colour: u32) -> u32 {
+    let [r, g, b, _] = components(colour);
+    (r * 299 + g * 587 + b * 114 + 500) / 1000
+}
+
+fn components(colour: u32) -> [u32; 4] {
+    [colour & 0xFF, (colour >> 8) & 0xFF, (colour >> 16) & 0xFF, colour >> 24]
+}
+
+fn components_f32(colour: u32) -> [f32; 4] {
+    components(colour).map(|v| v as f32)
+}
+
+fn pack(r: u32, g: u32, b: u32, a: u32) -> u32 {
+    r | (g << 8) | (b << 16) | (a << 24)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dat::*;
+
+    const SIZE: usize = 32;
+
+    #[derive(Copy, Clone)]
+    enum Gradient { Colour, Greyscale, Intensity }
+
+    fn gradient(kind: Gradient) -> Vec<u32> {
+        (0..SIZE * SIZE).map(|i| {
+            let (x, y) = ((i % SIZE) as u32, (i / SIZE) as u32);
+            let r = x * 255 / (SIZE as u32 - 1);
+            let g = y * 255 / (SIZE as u32 - 1);
+            match kind {
+                Gradient::Colour => pack(r, g, (r + g) / 2, 0xFF),
+                Gradient::Greyscale => pack(r, r, r, 0xFF),
+
+                // I4 and I8 store intensity as alpha as well
+                Gradient::Intensity => pack(r, r, r, r),
+            }
+        }).collect()
+    }
+
+    fn round_trip_error(format: InternalTextureFormat, rgba_data: &[u32]) -> u32 {
+        let encoded = encode_data(format, SIZE, SIZE, rgba_data, None);
+        let palette = encoded.tlut.as_ref()
+            .map(|tlut| decode_palette(tlut.colour_count as usize, tlut.format, &tlut.data));
+
+        let mut decoded = vec![0u32; SIZE * SIZE];
+        match format {
+            InternalTextureFormat::CMP => decode_compressed_image(&encoded.data, SIZE, SIZE, &mut decoded),
+            InternalTextureFormat::I4 => decode_i4_image(&encoded.data, SIZE, SIZE, &mut decoded),
+            InternalTextureFormat::I8 => decode_i8_image(&encoded.data, SIZE, SIZE, &mut decoded),
+            InternalTextureFormat::IA4 => decode_ia4_image(&encoded.data, SIZE, SIZE, &mut decoded),
+            InternalTextureFormat::IA8 => decode_ia8_image(&encoded.data, SIZE, SIZE, &mut decoded),
+            InternalTextureFormat::RGBA8 => decode_rgba8_image(&encoded.data, SIZE, SIZE, &mut decoded),
+            InternalTextureFormat::RGB565 => decode_rgb565_image(&encoded.data, SIZE, SIZE, &mut decoded),
+            InternalTextureFormat::RGB5A3 => decode_rgb5a3_image(&encoded.data, SIZE, SIZE, &mut decoded),
+            InternalTextureFormat::CI4 => decode_ci4_image(&encoded.data, &palette.unwrap(), SIZE, SIZE, &mut decoded),
+            InternalTextureFormat::CI8 => decode_ci8_image(&encoded.data, &palette.unwrap(), SIZE, SIZE, &mut decoded),
+            InternalTextureFormat::CI14X2 => decode_ci14x2_image(&encoded.data, &palette.unwrap(), SIZE, SIZE, &mut decoded),
+        }
+
+        rgba_data.iter().zip(decoded.iter())
+            .flat_map(|(&a, &b)| {
+                let (a, b) = (components(a), components(b));
+                (0..4).map(move |k| a[k].abs_diff(b[k]))
+            })
+            .max()
+            .unwrap()
+    }
+
+    #[test]
+    fn encoders_round_trip() {
+        use InternalTextureFormat::*;
+
+        let colour = gradient(Gradient::Colour);
+        let greyscale = gradient(Gradient::Greyscale);
+        let intensity = gradient(Gradient::Intensity);
+
+        // decode_rgb565_image expands by shifting, so 255 comes back as 248.
+        // That also applies to the RGB565 palette chosen for opaque paletted images.
+        for (format, rgba_data, tolerance) in [
+            (RGBA8, &colour, 0),
+            (RGB565, &colour, 7),
+            (RGB5A3, &colour, 5),
+            (I4, &intensity, 9),
+            (I8, &intensity, 0),
+            (IA4, &greyscale, 9),
+            (IA8, &greyscale, 0),
+            (CI4, &greyscale, 9),
+            (CI8, &colour, 9),
+            (CI14X2, &colour, 7),
+            (CMP, &colour, 17),
+        ] {
+            let error = round_trip_error(format, rgba_data);
+            assert!(error <= tolerance, "{:?} max error {} over {}", format, error, tolerance);
+        }
+    }
+
+    #[test]
+    fn palette_tree_matches_linear_search() {
+        // xorshift, so the colours spread over every channel
+        let mut state = 0x2545F491u32;
+        let mut random = move || {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state
+        };
+
+        // duplicates check the lowest index wins ties
+        let mut palette: Vec<u32> = (0..512).map(|_| random()).collect();
+        palette.extend_from_within(100..200);
+        let tree = PaletteTree::new(palette.iter().map(|&p| components_f32(p)));
+
+        for _ in 0..4096 {
+            let colour = random();
+            let c = components(colour);
+            let expected = (0..palette.len())
+                .min_by_key(|&i| {
+                    let p = components(palette[i]);
+                    (0..4).map(|k| p[k].abs_diff(c[k]).pow(2)).sum::<u32>()
+                })
+                .unwrap();
+            assert_eq!(tree.nearest(components_f32(colour)), expected, "colour {:08x}", colour);
+        }
+    }
+}
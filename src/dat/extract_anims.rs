@@ -21,6 +21,9 @@ pub struct AnimationFrame {
     // one for each dobj
     pub phongs: Box<[PhongF32]>,
     pub tex_transforms: Box<[TexTransform]>,
+
+    /// One for each `Model::morph_sets`, with a weight for each target.
+    pub morph_weights: Box<[Box<[f32]>]>,
 }
 
 impl AnimationFrame {
@@ -28,11 +31,15 @@ impl AnimationFrame {
         let transforms = model.base_transforms.to_vec().into_boxed_slice();
         let phongs = model.phongs.iter().map(|&p| p.into()).collect::<Vec<PhongF32>>();
         let tex_transforms = vec![TexTransform::default(); model.phongs.len()];
+        let morph_weights = model.morph_sets.iter()
+            .map(|morph_set| morph_set.blend_weights(&morph_set.default_blend))
+            .collect();
 
         AnimationFrame {
             transforms,
             phongs: phongs.into_boxed_slice(),
             tex_transforms: tex_transforms.into_boxed_slice(),
+            morph_weights,
         }
     }
 
@@ -42,6 +49,9 @@ impl AnimationFrame {
             self.phongs[i] = model.phongs[i].into();
         }
         self.tex_transforms.fill(TexTransform::default());
+        for (weights, morph_set) in self.morph_weights.iter_mut().zip(model.morph_sets.iter()) {
+            *weights = morph_set.blend_weights(&morph_set.default_blend);
+        }
     }
 
     pub fn apply_animation(&mut self, model: &Model, anim: &Animation, frame: f32) {
@@ -60,6 +70,18 @@ impl AnimationFrame {
             self.phongs[dobj_index] = animated_material.phong;
             self.tex_transforms[dobj_index] = animated_material.tex_transform;
         }
+
+        for transform in anim.shape_transforms.iter() {
+            let morph_idx = model.morph_sets.iter().position(|m| {
+                m.dobj_idx as usize == transform.dobj_index && m.pobj_idx as usize == transform.pobj_index
+            });
+
+            if let Some(morph_idx) = morph_idx {
+                let morph_set = &model.morph_sets[morph_idx];
+                let blend = transform.compute_blend_at(frame, &morph_set.default_blend);
+                self.morph_weights[morph_idx] = morph_set.blend_weights(&blend);
+            }
+        }
     }
 
     // progress: 0 => self, 1 => other
@@ -185,6 +207,7 @@ pub struct AnimDatFile<'a> {
 pub struct Animation {
     pub bone_transforms: Vec<AnimTransformBone>,
     pub material_transforms: Vec<AnimTransformMaterial>,
+    pub shape_transforms: Vec<AnimTransformShape>,
 }
 
 pub type AOBJFlags = u32;
@@ -216,6 +239,18 @@ pub struct AnimTransformMaterial {
     pub dobj_index: usize,
 }
 
+/// Animates the blend of one POBJ's shape set.
+#[derive(Clone, Debug)]
+pub struct AnimTransformShape {
+    pub tracks: Box<[AnimTrack<TrackTypeShape>]>,
+    pub flags: AOBJFlags,
+    pub end_frame: f32,
+    pub dobj_index: usize,
+
+    /// Index of the POBJ in its DOBJ
+    pub pobj_index: usize,
+}
+
 #[derive(Clone, Debug)]
 pub struct AnimTransformTexture {
     pub tracks: Box<[AnimTrack<TrackTypeTexture>]>,
@@ -343,6 +378,54 @@ pub fn parse_joint_anim(
     }
 }
 
+/// HSD_ShapeAnimJoint -> Animation
+///
+/// `root_jobj` is the model the animation is for. The shape anim joint tree mirrors its joints,
+/// which gives the index of each DOBJ even when joints without shape animations are skipped.
+pub fn parse_shape_anim(
+    prev: &mut Animation,
+    shape_anim_joint: HSDStruct<'_>,
+    root_jobj: JOBJ<'_>,
+) {
+    // HSD_ShapeAnimJoint, walked in the same order as the JOBJs
+    let jobjs = root_jobj.hsd_struct.iter_joint_tree(0x08, 0x0C).map(JOBJ::new);
+    let mut dobj_start = 0;
+    for (shape_anim_joint, jobj) in shape_anim_joint.iter_joint_tree(0x00, 0x04).zip(jobjs) {
+        let dobj_count = jobj.get_dobj().map_or(0, |dobj| dobj.siblings().count());
+
+        // HSD_ShapeAnimDObj, one for each DOBJ
+        let shape_anim_dobjs = shape_anim_joint.try_get_reference(0x08).into_iter()
+            .flat_map(|shape_anim_dobj| shape_anim_dobj.iter_joint_list(0x00))
+            .take(dobj_count);
+
+        for (i, shape_anim_dobj) in shape_anim_dobjs.enumerate() {
+            // HSD_ShapeAnim, one for each POBJ
+            let shape_anim = match shape_anim_dobj.try_get_reference(0x04) {
+                Some(shape_anim) => shape_anim,
+                None => continue,
+            };
+
+            for (pobj_index, shape_anim) in shape_anim.iter_joint_list(0x00).enumerate() {
+                let aobj = match shape_anim.try_get_reference(0x04) {
+                    Some(aobj) => aobj,
+                    None => continue,
+                };
+
+                let (tracks, flags, end_frame) = parse_aobj::<TrackTypeShape>(aobj);
+                prev.shape_transforms.push(AnimTransformShape {
+                    tracks,
+                    flags,
+                    end_frame,
+                    dobj_index: dobj_start + i,
+                    pobj_index,
+                });
+            }
+        }
+
+        dobj_start += dobj_count;
+    }
+}
+
 fn parse_aobj<T: TrackType>(aobj: HSDStruct) -> (Box<[AnimTrack<T>]>, AOBJFlags, f32) {
     let flags: AOBJFlags = aobj.get_u32(0x00);
     let end_frame = aobj.get_f32(0x04);
@@ -370,6 +453,15 @@ pub fn extract_anim_from_action(
 ) -> Option<Animation> {
     let offset = fighter_action_struct.get_u32(0x04) as usize;
     let size = fighter_action_struct.get_u32(0x08) as usize;
+    extract_anim_from_aj(aj_dat, offset, size)
+}
+
+/// Offset and size are the values in the fighter action table (0x04 and 0x08).
+pub fn extract_anim_from_aj(
+    aj_dat: &DatFile,
+    offset: usize,
+    size: usize,
+) -> Option<Animation> {
     if offset == 0 && size == 0 { return None; }
     let anim_data = &aj_dat.data[offset..offset+size];
 
@@ -384,6 +476,7 @@ pub fn extract_anim_from_action(
     Some(Animation {
         bone_transforms,
         material_transforms: Vec::new(),
+        shape_transforms: Vec::new(),
     })
 }
 
@@ -471,6 +564,10 @@ impl Animation {
             end_frame = end_frame.max(t.material_end_frame);
         }
 
+        for t in self.shape_transforms.iter() {
+            end_frame = end_frame.max(t.end_frame);
+        }
+
         end_frame
     }
 }
@@ -614,6 +711,24 @@ impl AnimTransformMaterial {
     }
 }
 
+impl AnimTransformShape {
+    /// Shape set blend values, starting from the unanimated values.
+    /// Each track sets the value at its index, see `MorphSet::blend_weights`.
+    pub fn compute_blend_at(&self, frame: f32, default_blend: &[f32]) -> Box<[f32]> {
+        let effective_frame = effective_frame(frame, self.flags, self.end_frame);
+
+        let mut blend = default_blend.to_vec();
+        if blend.len() < self.tracks.len() { blend.resize(self.tracks.len(), 0.0); }
+
+        for (value, track) in blend.iter_mut().zip(self.tracks.iter()) {
+            if effective_frame < track.start_frame { continue; }
+            *value = track.get_value(effective_frame - track.start_frame);
+        }
+
+        blend.into_boxed_slice()
+    }
+}
+
 fn extract_figatree_transforms(figatree: FigaTree) -> Vec<AnimTransformBone> {
     let mut transforms = Vec::new();
 
@@ -1045,6 +1160,11 @@ pub enum TrackTypeMaterial {
     Alpha,
 }
 
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum TrackTypeShape {
+    Blend,
+}
+
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub enum TrackTypeTexture {
     //TImg, //
@@ -1150,3 +1270,42 @@ impl TrackType for TrackTypeTexture {
         })
     }       
 }           
+
+impl TrackType for TrackTypeShape {
+    fn from_u8(_n: u8) -> Option<Self> {
+        // shape anims only animate the blend, the track type is not checked
+        Some(TrackTypeShape::Blend)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dat::test_dat::{build_dat, write_u32};
+
+    #[test]
+    fn shape_anim_dobj_index_counts_joints_without_shape_anims() {
+        // JOBJ @0x00 with one DOBJ @0x80, child JOBJ @0x40 with DOBJs @0x90 and @0xA0.
+        // The shape anim joint @0xB0 for the root has no HSD_ShapeAnimDObj,
+        // its child @0xC0 animates the second DOBJ of the child JOBJ.
+        let mut data = vec![0u8; 0x124];
+        let pointers = [
+            (0x08, 0x40), (0x10, 0x80), (0x50, 0x90), (0x94, 0xA0),
+            (0xB0, 0xC0), (0xC8, 0xD0), (0xD0, 0xD8), (0xDC, 0xE0),
+            (0xE4, 0xF0), (0xF8, 0x100), (0x110, 0x120),
+        ];
+        for (offset, target) in pointers {
+            write_u32(&mut data, offset, target);
+        }
+
+        let relocs: Vec<u32> = pointers.iter().map(|&(offset, _)| offset as u32).collect();
+        let dat = build_dat(&data, &relocs, &[(0x00, "joint"), (0xB0, "shape_anim")], &[]);
+        let hsd = HSDRawFile::new(&dat);
+
+        let mut anim = Animation::default();
+        parse_shape_anim(&mut anim, hsd.roots[1].hsd_struct.clone(), JOBJ::new(hsd.roots[0].hsd_struct.clone()));
+
+        assert_eq!(anim.shape_transforms.len(), 1);
+        assert_eq!((anim.shape_transforms[0].dobj_index, anim.shape_transforms[0].pobj_index), (2, 0));
+    }
+}
@@ -1,6 +1,6 @@
 use crate::dat::{InternalTextureFormat, HSDStruct, Image, TLUTFormat, Animation,
     JOBJ, extract_model_from_jobj, decode_palette, Model, decode_data,
-    parse_joint_anim, parse_mat_anim};
+    parse_joint_anim, parse_mat_anim, parse_shape_anim};
 
 // Melee/Ef/SBM_EffectTable.cs (SBM_EffectTable)
 #[derive(Clone, Debug)]
@@ -50,7 +50,7 @@ impl<'a> EffectTable<'a> {
                 Some(j) => JOBJ::new(j),
                 None => continue,
             };
-            let model = extract_model_from_jobj(jobj, None).unwrap();
+            let model = extract_model_from_jobj(jobj.clone(), None).unwrap();
 
             let mut anim = Animation::default();
 
@@ -62,6 +62,10 @@ impl<'a> EffectTable<'a> {
                 parse_mat_anim(&mut anim, mat_anim_joint);
             }
 
+            if let Some(shape_anim_joint) = model_struct.try_get_reference(0x10) {
+                parse_shape_anim(&mut anim, shape_anim_joint, jobj);
+            }
+
             models.push((model, anim));
         }
 
@@ -93,6 +97,10 @@ impl<'a> EffectTable<'a> {
             parse_mat_anim(&mut anim, mat_anim_joint);
         }
 
+        if let (Some(shape_anim_joint), Some(jobj)) = (model_struct.try_get_reference(0x10), model_struct.try_get_reference(0x04)) {
+            parse_shape_anim(&mut anim, shape_anim_joint, JOBJ::new(jobj));
+        }
+
         Some(anim)
     }
 
@@ -142,7 +150,7 @@ impl<'a> EffectTable<'a> {
 
             // HSD_ShapeAnimJoint
             match model_struct.try_get_reference(0x10) {
-                Some(shape_anim_joint) => extract_mat_anim_joint_models(&mut models, shape_anim_joint),
+                Some(shape_anim_joint) => extract_shape_anim_joint_models(&mut models, shape_anim_joint),
                 None => (),
             };
         }
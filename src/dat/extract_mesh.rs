@@ -1,7 +1,8 @@
 use crate::dat::{
     HSDStruct, HSDRawFile, JOBJ, ModelBoneIndices, DatExtractError, 
-    textures::{try_decode_texture, Texture},
-    Animation, parse_joint_anim, parse_mat_anim, Phong, RenderModeFlags
+    textures::{try_decode_texture_stages, TextureStage}, TextureHandle,
+    Animation, parse_joint_anim, parse_mat_anim, parse_shape_anim, Phong, Material, RenderModeFlags,
+    StageCollision,
 };
 use glam::f32::{Mat4, Vec3, Vec4, Vec2};
 
@@ -16,11 +17,29 @@ pub struct Bone {
 
 #[derive(Copy, Clone, Default, Debug)]
 pub struct PrimitiveGroup {
+    /// Texture of the first texture stage
     pub texture_idx: Option<u16>,
+    pub texture_stages_start: u16,
+    pub texture_stages_len: u16, // zero if none
+
+    /// Range in `Model::indices`
+    pub indices_start: u32,
+    pub indices_len: u32,
+
+    /// Range in `Model::line_indices`
+    pub line_indices_start: u32,
+    pub line_indices_len: u32,
+
+    /// Range in `Model::point_indices`
+    pub point_indices_start: u32,
+    pub point_indices_len: u32,
 
-    pub indices_start: u16,
-    pub indices_len: u16,
     pub model_group_idx: u8,
+
+    /// Index of the DOBJ in the joint tree, counting DOBJs that were skipped.
+    /// Same as the indices in `ModelVisibility`.
+    pub dobj_idx: u16,
+
     pub mobj_render_flags: RenderModeFlags,
 }
 
@@ -30,18 +49,21 @@ pub struct ModelGroup {
     pub prim_group_len: u16,
 }
 
+// GX/Enums.cs (GXPrimitiveType)
 #[derive(Copy, Clone, Debug)]
 pub enum PrimitiveType {
-    //Points = 0xB8,
-    //Lines = 0xA8,
-    //LineStrip = 0xB0,
+    Points = 0xB8,
+    Lines = 0xA8,
+    LineStrip = 0xB0,
     Triangles = 0x90,
     TriangleStrip = 0x98,
-    //TriangleFan = 0xA0,
+    TriangleFan = 0xA0,
     Quads = 0x80
 }
 
 /// I hate messing with wgsl <-> rust alignment
+///
+/// Same as `VertexLayout::full`. Use `Model::pack_vertices` for smaller layouts.
 #[repr(C)]
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub struct Vertex {
@@ -123,10 +145,120 @@ impl Vertex {
 
 unsafe impl bytemuck::NoUninit for Vertex {}
 
+/// Every attribute a POBJ display list can hold. `Vertex` is packed from these.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct VertexAttributes {
+    pub pos: Vec3,
+    pub normal: Vec3,
+
+    /// From GX_VA_NBT, zero if the POBJ has no NBT attribute.
+    pub binormal: Vec3,
+    pub tangent: Vec3,
+
+    /// GX_VA_CLR0 and GX_VA_CLR1
+    pub colours: [Vec4; 2],
+
+    /// GX_VA_TEX0 to GX_VA_TEX7
+    pub uvs: [Vec2; 8],
+
+    pub weights: [f32; 6],
+    pub bones: [u32; 6],
+
+    /// Raw GX_VA_PNMTXIDX, three times the envelope index.
+    pub pnmtx_idx: u8,
+
+    /// Raw GX_VA_TEX0MTXIDX to GX_VA_TEX7MTXIDX.
+    pub tex_mtx_idx: [u8; 8],
+}
+
+impl VertexAttributes {
+    pub fn vertex(&self) -> Vertex {
+        Vertex::from_parts(
+            self.pos.to_array(),
+            self.uvs[0].to_array(),
+            self.normal.to_array(),
+            self.weights,
+            self.bones,
+            self.colours[0].to_array(),
+        )
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct MeshBuilder {
-    pub indices: Vec<u16>,
-    pub vertices: Vec<Vertex>,
+    /// Triangle list
+    pub indices: Vec<u32>,
+
+    /// Line list, two indices per line
+    pub line_indices: Vec<u32>,
+    pub point_indices: Vec<u32>,
+
+    pub vertex_attributes: Vec<VertexAttributes>,
+
+    /// Vertices before this are not reused by later primitives.
+    /// Moved past the vertices of each shape set, so geometry outside it doesn't morph.
+    pub reuse_start: usize,
+}
+
+/// Indices into `Model::vertex_attributes`.
+/// Stored as u16 unless a model has more vertices than u16 can index.
+#[derive(Debug, Clone)]
+pub enum IndexBuffer {
+    U16(Box<[u16]>),
+    U32(Box<[u32]>),
+}
+
+impl IndexBuffer {
+    pub fn new(indices: Vec<u32>) -> Self {
+        if indices.iter().all(|&i| i <= u16::MAX as u32) {
+            IndexBuffer::U16(indices.into_iter().map(|i| i as u16).collect())
+        } else {
+            IndexBuffer::U32(indices.into_boxed_slice())
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            IndexBuffer::U16(indices) => indices.len(),
+            IndexBuffer::U32(indices) => indices.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, i: usize) -> Option<u32> {
+        match self {
+            IndexBuffer::U16(indices) => indices.get(i).map(|&i| i as u32),
+            IndexBuffer::U32(indices) => indices.get(i).copied(),
+        }
+    }
+
+    /// Panics if the range is out of bounds.
+    pub fn range(&self, range: std::ops::Range<usize>) -> impl Iterator<Item=u32> + '_ {
+        let (u16_indices, u32_indices): (&[u16], &[u32]) = match self {
+            IndexBuffer::U16(indices) => (&indices[range], &[]),
+            IndexBuffer::U32(indices) => (&[], &indices[range]),
+        };
+        u16_indices.iter().map(|&i| i as u32).chain(u32_indices.iter().copied())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=u32> + '_ {
+        self.range(0..self.len())
+    }
+
+    pub fn is_u32(&self) -> bool {
+        matches!(self, IndexBuffer::U32(_))
+    }
+
+    /// For uploading to the GPU.
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            IndexBuffer::U16(indices) => bytemuck::cast_slice(indices),
+            IndexBuffer::U32(indices) => bytemuck::cast_slice(indices),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -138,11 +270,84 @@ pub struct Model {
 
     // one for each dobj
     pub phongs: Box<[Phong]>,
+    pub materials: Box<[Material]>,
     pub primitive_groups: Box<[PrimitiveGroup]>,
-    pub textures: Box<[Texture]>,
+    pub textures: Box<[TextureHandle]>,
+    pub texture_stages: Box<[TextureStage]>,
+
+    /// Triangle list
+    pub indices: IndexBuffer,
+
+    /// Line list from GX lines and line strips, two indices per line
+    pub line_indices: IndexBuffer,
+    pub point_indices: IndexBuffer,
 
-    pub indices: Box<[u16]>,
+    /// `vertex_attributes` packed into `Vertex`, in the same order.
     pub vertices: Box<[Vertex]>,
+
+    /// Every decoded attribute of each vertex.
+    /// Use `vertices` or `pack_vertices` for smaller vertices to upload.
+    pub vertex_attributes: Box<[VertexAttributes]>,
+
+    /// One for each POBJ with a shape set
+    pub morph_sets: Box<[MorphSet]>,
+}
+
+/// Blend targets decoded from a POBJ's shape set.
+/// Stored vertex positions and normals are the first target's.
+#[derive(Clone, Debug)]
+pub struct MorphSet {
+    pub dobj_idx: u16,
+
+    /// Index of the POBJ in its DOBJ
+    pub pobj_idx: u16,
+
+    /// The POBJ's vertices in `Model::vertex_attributes`
+    pub vertex_start: u32,
+    pub vertex_len: u32,
+
+    pub targets: Box<[MorphTarget]>,
+
+    /// Blend values when not animated. See `MorphSet::blend_weights`.
+    pub default_blend: Box<[f32]>,
+    pub additive: bool,
+}
+
+/// `MorphSet::vertex_len` positions and normals.
+#[derive(Clone, Debug)]
+pub struct MorphTarget {
+    pub positions: Box<[Vec3]>,
+    pub normals: Box<[Vec3]>,
+}
+
+impl MorphSet {
+    /// Weight of each target from shape set blend values.
+    ///
+    /// Average sets blend between neighbouring targets with the first value,
+    /// so 1.25 is a quarter of the way from the second target to the third.
+    /// Additive sets have a value for each target, adding its offset from the first target.
+    pub fn blend_weights(&self, blend: &[f32]) -> Box<[f32]> {
+        let mut weights = vec![0.0; self.targets.len()].into_boxed_slice();
+        if weights.is_empty() { return weights }
+
+        if self.additive {
+            let mut base = 1.0;
+            for (w, &b) in weights.iter_mut().zip(blend.iter()).skip(1) {
+                *w = b;
+                base -= b;
+            }
+            weights[0] = base;
+        } else {
+            let last = (weights.len() - 1) as f32;
+            let blend = blend.first().copied().unwrap_or(0.0).clamp(0.0, last);
+            let i = (blend.floor() as usize).min(weights.len() - 1);
+            let t = blend - i as f32;
+            weights[i] = 1.0 - t;
+            if t > 0.0 { weights[i + 1] = t; }
+        }
+
+        weights
+    }
 }
 
 pub fn extract_character_model<'a>(
@@ -195,18 +400,24 @@ pub fn extract_model_from_jobj<'a>(
     // get meshes / primitives / vertices ------------------------------------------------------
     let mut builder = MeshBuilder {
         indices: Vec::with_capacity(8192),
-        vertices: Vec::with_capacity(8192),
+        line_indices: Vec::new(),
+        point_indices: Vec::new(),
+        vertex_attributes: Vec::with_capacity(8192),
+        reuse_start: 0,
     };
 
+    let mut morph_sets = Vec::new();
     let mut pgroups = Vec::with_capacity(128);
     let mut textures = Vec::with_capacity(64);
+    let mut texture_stages = Vec::with_capacity(128);
 
     // cache image data ptrs to prevent decoding textures multiple times
     let mut texture_cache = HashMap::with_capacity(64);
 
     let mut phongs = Vec::with_capacity(128);
+    let mut materials = Vec::with_capacity(128);
 
-    let mut dobj_idx = 0;
+    let mut dobj_idx: u16 = 0;
     //let t = std::time::Instant::now();
     for (i, jobj) in bone_jobjs.iter().enumerate() {
         let pgroup_start = pgroups.len() as _;
@@ -220,7 +431,7 @@ pub fn extract_model_from_jobj<'a>(
                     Some(ref high_poly_bone_indices) => {
                         let dobj_idx_idx = match high_poly_bone_indices.indices.iter()
                             .copied()
-                            .position(|idx| idx == dobj_idx)
+                            .position(|idx| idx as u16 == dobj_idx)
                         {
                             Some(idx) => idx as u16,
                             None => {
@@ -241,32 +452,55 @@ pub fn extract_model_from_jobj<'a>(
                     }
                 };
 
+                let pgroup_dobj_idx = dobj_idx;
                 dobj_idx += 1;
                 pgroup_len += 1;
 
-                let indices_start = builder.indices.len() as u16;
+                let indices_start = builder.indices.len() as u32;
+                let line_indices_start = builder.line_indices.len() as u32;
+                let point_indices_start = builder.point_indices.len() as u32;
 
                 if let Some(pobj) = dobj.get_pobj() {
-                    for pobj in pobj.siblings() {
-                        pobj.decode_primitives(&mut builder, &bone_jobjs);
+                    for (pobj_idx, pobj) in pobj.siblings().enumerate() {
+                        if let Some(mut morph_set) = pobj.decode_primitives(&mut builder, &bone_jobjs) {
+                            morph_set.dobj_idx = pgroup_dobj_idx;
+                            morph_set.pobj_idx = pobj_idx as u16;
+                            morph_sets.push(morph_set);
+                        }
                     }
                 }
 
-                let (phong, mobj_render_flags) = dobj.get_mobj()
-                    .map(|m| (m.get_phong(), m.flags()))
-                    .unwrap_or((Phong::default(), 0));
-                let texture_idx = try_decode_texture(&mut texture_cache, &mut textures, dobj);
-
-                let indices_len = builder.indices.len() as u16 - indices_start;
+                let material = dobj.get_mobj()
+                    .map(|m| m.material())
+                    .unwrap_or_default();
+                let (phong, mobj_render_flags) = (material.phong, material.render_mode);
+                let texture_stages_start = texture_stages.len() as u16;
+                let texture_stages_len = try_decode_texture_stages(
+                    &mut texture_cache, &mut textures, &mut texture_stages, dobj
+                ) as u16;
+                let texture_idx = texture_stages.get(texture_stages_start as usize)
+                    .map(|stage| stage.texture_idx);
+
+                let indices_len = builder.indices.len() as u32 - indices_start;
+                let line_indices_len = builder.line_indices.len() as u32 - line_indices_start;
+                let point_indices_len = builder.point_indices.len() as u32 - point_indices_start;
 
                 pgroups.push(PrimitiveGroup {
                     model_group_idx,
+                    dobj_idx: pgroup_dobj_idx,
                     texture_idx,
+                    texture_stages_start,
+                    texture_stages_len,
                     indices_start,
                     indices_len,
+                    line_indices_start,
+                    line_indices_len,
+                    point_indices_start,
+                    point_indices_len,
                     mobj_render_flags,
                 });
                 phongs.push(phong);
+                materials.push(material);
             }
         }
 
@@ -303,11 +537,17 @@ pub fn extract_model_from_jobj<'a>(
         bones: bones.into_boxed_slice(),
         base_transforms: base_transforms.into_boxed_slice(),
         phongs: phongs.into_boxed_slice(),
+        materials: materials.into_boxed_slice(),
         inv_world_transforms: inv_world_transforms.into_boxed_slice(),
         primitive_groups: pgroups.into_boxed_slice(),
         textures: textures.into_boxed_slice(),
-        indices: builder.indices.into_boxed_slice(),
-        vertices: builder.vertices.into_boxed_slice(),
+        texture_stages: texture_stages.into_boxed_slice(),
+        indices: IndexBuffer::new(builder.indices),
+        line_indices: IndexBuffer::new(builder.line_indices),
+        point_indices: IndexBuffer::new(builder.point_indices),
+        vertices: builder.vertex_attributes.iter().map(VertexAttributes::vertex).collect(),
+        vertex_attributes: builder.vertex_attributes.into_boxed_slice(),
+        morph_sets: morph_sets.into_boxed_slice(),
     };
 
     Ok(model)
@@ -364,6 +604,16 @@ impl<'a> MapGOBJ<'a> {
             }
         }
 
+        if let Some(iter) = self.hsd_struct.try_get_null_ptr_array(0x0C) {
+            for (i, shape_anim_joint) in iter.enumerate() {
+                while anims.len() <= i {
+                    anims.push(Animation::default());
+                }
+
+                parse_shape_anim(&mut anims[i], shape_anim_joint, self.root_jobj());
+            }
+        }
+
         anims
     }
 }
@@ -372,6 +622,9 @@ impl<'a> MapGOBJ<'a> {
 pub struct StageData {
     pub sections: Vec<StageSection>,
     pub scale: f32,
+
+    /// None if the stage has no `coll_data`
+    pub collision: Option<StageCollision>,
 }
 
 #[derive(Clone, Debug)]
@@ -404,9 +657,12 @@ pub fn extract_stage<'a>(parsed_stage_dat: &HSDRawFile<'a>) -> Result<StageData,
             StageSection { model, animations }
         }).collect();
 
+    let collision = StageCollision::from_stage_dat(parsed_stage_dat);
+
     Ok(StageData {
         sections,
-        scale
+        scale,
+        collision,
     })
 }
 
@@ -448,15 +704,54 @@ pub fn extract_stage<'a>(parsed_stage_dat: &HSDRawFile<'a>) -> Result<StageData,
 impl PrimitiveType {
     pub fn from_u8(n: u8) -> Option<Self> {
         Some(match n {
-            //0xB8 => Self::Points       ,
-            //0xA8 => Self::Lines        ,
-            //0xB0 => Self::LineStrip    ,
+            0xB8 => Self::Points       ,
+            0xA8 => Self::Lines        ,
+            0xB0 => Self::LineStrip    ,
             0x90 => Self::Triangles    ,
             0x98 => Self::TriangleStrip,
-            //0xA0 => Self::TriangleFan  ,
+            0xA0 => Self::TriangleFan  ,
             0x80 => Self::Quads        ,
             _ => return None
         })
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn morph_set(target_count: usize, additive: bool) -> MorphSet {
+        let target = MorphTarget { positions: Box::default(), normals: Box::default() };
+        MorphSet {
+            dobj_idx: 0,
+            pobj_idx: 0,
+            vertex_start: 0,
+            vertex_len: 0,
+            targets: vec![target; target_count].into_boxed_slice(),
+            default_blend: Box::default(),
+            additive,
+        }
+    }
+
+    #[test]
+    fn blend_weights() {
+        let average = morph_set(3, false);
+        assert_eq!(&*average.blend_weights(&[0.0]), &[1.0, 0.0, 0.0]);
+        assert_eq!(&*average.blend_weights(&[1.25]), &[0.0, 0.75, 0.25]);
+        assert_eq!(&*average.blend_weights(&[2.0]), &[0.0, 0.0, 1.0]);
+
+        // clamped to the first and last targets
+        assert_eq!(&*average.blend_weights(&[3.5]), &[0.0, 0.0, 1.0]);
+        assert_eq!(&*average.blend_weights(&[-1.0]), &[1.0, 0.0, 0.0]);
+        assert_eq!(&*average.blend_weights(&[]), &[1.0, 0.0, 0.0]);
+
+        // the first target takes what the others don't
+        let additive = morph_set(3, true);
+        assert_eq!(&*additive.blend_weights(&[0.0, 0.5, 0.25]), &[0.25, 0.5, 0.25]);
+        assert_eq!(&*additive.blend_weights(&[0.0, 1.0, 1.0]), &[-1.0, 1.0, 1.0]);
+        assert_eq!(&*additive.blend_weights(&[]), &[1.0, 0.0, 0.0]);
+
+        assert!(morph_set(0, false).blend_weights(&[1.0]).is_empty());
+    }
+}
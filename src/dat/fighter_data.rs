@@ -1,10 +1,11 @@
 use crate::dat::{
     HSDStruct, DatFile, Model, JOBJ, extract_model_from_jobj, parse_joint_anim, parse_mat_anim,
-    HSDRawFile, Animation, extract_anim_from_action, extract_character_model,
+    HSDRawFile, Animation, extract_anim_from_aj, extract_character_model,
 };
 use glam::Vec3;
 use crate::parse_string;
 use slp_parser::Character;
+use std::cell::OnceCell;
 
 #[derive(Debug, Clone)]
 pub struct FighterData {
@@ -18,6 +19,19 @@ pub struct FighterData {
     pub hurtboxes: Box<[Hurtbox]>,
 
     pub ecb_bones: [u16; 6],
+
+    /// Parts of the first costume, which `model` is extracted with.
+    pub model_visibility: Option<ModelVisibility>,
+
+    /// PlXxAJ.dat. Action animations are decoded from this on request.
+    pub anim_dat: DatFile,
+}
+
+impl FighterData {
+    /// Decodes the animation of the action the first time it is requested.
+    pub fn action_animation(&self, action_idx: usize) -> Option<&Animation> {
+        self.action_table.get(action_idx)?.animation(&self.anim_dat)
+    }
 }
 
 
@@ -39,9 +53,26 @@ pub struct Hurtbox {
 #[derive(Debug, Clone)]
 pub struct FighterAction {
     pub name: Option<Box<str>>,
-    pub animation: Option<Animation>,
     pub subactions: Option<Box<[u32]>>,
     pub flags: u32,
+
+    /// Location of the FigaTree dat in PlXxAJ.dat.
+    pub animation_offset: u32,
+    pub animation_size: u32,
+
+    /// Filled by `animation`.
+    pub animation_cache: OnceCell<Option<Animation>>,
+}
+
+impl FighterAction {
+    /// `anim_dat` must be the PlXxAJ.dat this action was parsed with.
+    pub fn animation(&self, anim_dat: &DatFile) -> Option<&Animation> {
+        self.animation_cache.get_or_init(|| extract_anim_from_aj(
+            anim_dat,
+            self.animation_offset as usize,
+            self.animation_size as usize,
+        )).as_ref()
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -363,11 +394,12 @@ pub fn parse_fighter_data(
     let attributes = fighter_data_root.attributes();
     let specific_attributes = fighter_data_root.specific_attributes(character);
     let ecb_bones = fighter_data_root.ecb_bones();
-    let action_table = parse_actions(anim_dat, &fighter_hsdfile)?;
+    let action_table = parse_actions(&fighter_hsdfile)?;
     let parsed_model_dat = HSDRawFile::new(model_dat);
     let model = extract_character_model(&fighter_hsdfile, &parsed_model_dat).ok()?;
     let articles = fighter_data_root.articles()?;
     let hurtboxes = fighter_data_root.hurtboxes();
+    let model_visibility = ModelVisibility::parse(&fighter_hsdfile, 0);
 
     Some(FighterData {
         character_name: name.strip_prefix("ftData").unwrap().to_string().into_boxed_str(),
@@ -378,6 +410,8 @@ pub fn parse_fighter_data(
         action_table,
         ecb_bones,
         hurtboxes,
+        model_visibility,
+        anim_dat: anim_dat.clone(),
     })
 }
 
@@ -388,23 +422,14 @@ pub struct ModelBoneIndices {
 }
 
 pub fn get_high_poly_bone_indices<'a>(fighter_hsd: &HSDRawFile<'a>) -> ModelBoneIndices {
-    let fighter_root = &fighter_hsd.roots[0];
-
-    // SBM_PlayerModelLookupTables
-    let lookup_tables = fighter_root.hsd_struct.get_reference(0x08);
-
-    let costume_table = lookup_tables.get_array(0x10, 0x04).next().unwrap();
+    let visibility = ModelVisibility::parse(fighter_hsd, 0).unwrap();
 
     let mut indices = Vec::with_capacity(64);
     let mut groups = Vec::with_capacity(8);
-    for high_poly_table in costume_table.get_array(0x08, 0x00) {
-        if let Some(jobj_table_iter) = high_poly_table.try_get_array(0x08, 0x04) {
-            for jobj_table in jobj_table_iter {
-                let count = jobj_table.get_i32(0x00) as usize;
-                let new = &jobj_table.get_buffer(0x04)[..count];
-                groups.push((indices.len() as u16, new.len() as u16));
-                indices.extend_from_slice(new);
-            }
+    for part in visibility.high_poly.iter() {
+        for object in part.objects.iter() {
+            groups.push((indices.len() as u16, object.len() as u16));
+            indices.extend_from_slice(object);
         }
     }
 
@@ -414,7 +439,117 @@ pub fn get_high_poly_bone_indices<'a>(fighter_hsd: &HSDRawFile<'a>) -> ModelBone
     }
 }
 
-pub fn parse_actions(anim_dat: &DatFile, fighter_hsd: &HSDRawFile) -> Option<Box<[FighterAction]>> {
+/// Switchable model parts of a costume, from SBM_PlayerModelLookupTables.
+/// Subactions `ChangeModelState`, `RevertModels` and `RemoveModels` switch between these,
+/// e.g. Link's sword sheathed or drawn.
+///
+/// DOBJs listed by no part are always visible.
+#[derive(Clone, Debug)]
+pub struct ModelVisibility {
+    pub high_poly: Box<[ModelPart]>,
+    pub low_poly: Box<[ModelPart]>,
+}
+
+/// A part shows one of its objects at a time.
+/// Each object is a list of DOBJ indices, see `PrimitiveGroup::dobj_idx`.
+#[derive(Clone, Debug)]
+pub struct ModelPart {
+    pub objects: Box<[Box<[u8]>]>,
+}
+
+/// The object shown by each part, None if the part is hidden.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ModelState {
+    pub parts: Box<[Option<u8>]>,
+}
+
+impl ModelVisibility {
+    /// None if the costume has no lookup table.
+    pub fn parse<'a>(fighter_hsd: &HSDRawFile<'a>, costume_idx: usize) -> Option<Self> {
+        let fighter_root = fighter_hsd.roots.first()?;
+
+        // SBM_PlayerModelLookupTables
+        let lookup_tables = fighter_root.hsd_struct.try_get_reference(0x08)?;
+        let costume_table = lookup_tables.try_get_array(0x10, 0x04)?.nth(costume_idx)?;
+
+        Some(ModelVisibility {
+            high_poly: parse_model_parts(&costume_table, 0x00),
+            low_poly: parse_model_parts(&costume_table, 0x04),
+        })
+    }
+
+    pub fn costume_count<'a>(fighter_hsd: &HSDRawFile<'a>) -> usize {
+        fighter_hsd.roots.first()
+            .and_then(|root| root.hsd_struct.try_get_reference(0x08))
+            .and_then(|lookup_tables| lookup_tables.try_get_array(0x10, 0x04))
+            .map_or(0, |costumes| costumes.count())
+    }
+
+    /// Every part shows its first object.
+    pub fn default_state(&self) -> ModelState {
+        ModelState { parts: vec![Some(0); self.high_poly.len()].into_boxed_slice() }
+    }
+
+    /// Uses the high poly parts.
+    pub fn is_dobj_visible(&self, state: &ModelState, dobj_idx: u16) -> bool {
+        let mut listed = false;
+
+        for (part_idx, part) in self.high_poly.iter().enumerate() {
+            for (object_idx, object) in part.objects.iter().enumerate() {
+                if !object.iter().any(|&i| i as u16 == dobj_idx) { continue }
+                listed = true;
+
+                if state.parts.get(part_idx).copied().flatten() == Some(object_idx as u8) {
+                    return true;
+                }
+            }
+        }
+
+        !listed
+    }
+
+    /// One for each primitive group of the model.
+    pub fn visible_primitive_groups(&self, model: &Model, state: &ModelState) -> Box<[bool]> {
+        model.primitive_groups.iter()
+            .map(|pgroup| self.is_dobj_visible(state, pgroup.dobj_idx))
+            .collect()
+    }
+}
+
+impl ModelState {
+    /// Applies `ChangeModelState`, `RevertModels` and `RemoveModels`. Other subactions are ignored.
+    pub fn apply_subaction(&mut self, subaction: &Subaction) {
+        match *subaction {
+            Subaction::ChangeModelState { struct_id, object_id } => {
+                if let Some(part) = self.parts.get_mut(struct_id as usize) {
+                    *part = Some(object_id);
+                }
+            }
+            Subaction::RevertModels => self.parts.fill(Some(0)),
+            Subaction::RemoveModels => self.parts.fill(None),
+            _ => (),
+        }
+    }
+}
+
+fn parse_model_parts(costume_table: &HSDStruct, loc: usize) -> Box<[ModelPart]> {
+    let Some(part_tables) = costume_table.try_get_array(0x08, loc) else { return Box::new([]) };
+
+    part_tables.map(|part_table| {
+        let objects = match part_table.try_get_array(0x08, 0x04) {
+            Some(object_tables) => object_tables.map(|object_table| {
+                let count = object_table.get_i32(0x00).max(0) as usize;
+                let dobj_indices = object_table.try_get_buffer(0x04).unwrap_or(&[]);
+                dobj_indices[..count.min(dobj_indices.len())].into()
+            }).collect(),
+            None => Box::new([]) as Box<[Box<[u8]>]>,
+        };
+
+        ModelPart { objects }
+    }).collect()
+}
+
+pub fn parse_actions(fighter_hsd: &HSDRawFile) -> Option<Box<[FighterAction]>> {
     let mut actions = Vec::new();
 
     let fighter_root = &fighter_hsd.roots[0];
@@ -424,32 +559,17 @@ pub fn parse_actions(anim_dat: &DatFile, fighter_hsd: &HSDRawFile) -> Option<Box
 
     for i in 0..(action_table_struct.len() / 0x18) {
         let s = action_table_struct.get_embedded_struct(i * 0x18, 0x18);
-        let action = parse_fighter_action(anim_dat, s);
+        let action = parse_fighter_action(s);
         actions.push(action);
     }
 
     Some(actions.into_boxed_slice())
 }
 
-fn parse_fighter_action(anim_dat: &DatFile, hsd_struct: HSDStruct) -> FighterAction {
+fn parse_fighter_action(hsd_struct: HSDStruct) -> FighterAction {
     let name = hsd_struct.try_get_buffer(0x00)
         .and_then(|s| Some(parse_string(s)?.to_string().into_boxed_str()));
 
-    let animation = extract_anim_from_action(anim_dat, hsd_struct.clone());
-    //let mut animation = None;
-    //if name.as_deref().map(|n| n.contains("Wait1")) == Some(true) {
-    //if name.as_deref().map(|n| n.contains("AttackHi3")) == Some(true) {
-    //    animation = extract_anim_from_action(anim_dat, hsd_struct.clone());
-    //    //for b in animation.as_mut().unwrap().bone_transforms.iter_mut() {
-    //    //    if b.bone_index == 20 {
-    //    //        b.tracks[0].keys[0].value = 0.0;
-    //    //        for track in b.tracks.iter_mut() {
-    //    //            println!("{:?}", track);
-    //    //        }
-    //    //    }
-    //    //}
-    //}
-
     let subactions = hsd_struct
         .try_get_reference(0x0C)
         .map(|sub| {
@@ -463,9 +583,11 @@ fn parse_fighter_action(anim_dat: &DatFile, hsd_struct: HSDStruct) -> FighterAct
 
     FighterAction {
         name,
-        animation,
         subactions,
-        flags
+        flags,
+        animation_offset: hsd_struct.get_u32(0x04),
+        animation_size: hsd_struct.get_u32(0x08),
+        animation_cache: OnceCell::new(),
     }
 }
 
@@ -0,0 +1,582 @@
+use crate::dat::{Animation, AnimationFrame, IndexBuffer, Model, Texture, WrapMode, tex_filter, skinning_transform};
+use ahash::{HashMap, HashMapExt};
+use glam::f32::{Mat3, Quat, Vec3};
+use std::fmt::Write;
+use std::io;
+use std::path::Path;
+
+/// Frame rate animations are baked at.
+pub const GLTF_FRAMES_PER_SECOND: f32 = 60.0;
+
+// glTF 2.0 spec, 5.1.3 (accessor.componentType) and 5.11 (sampler)
+const UNSIGNED_SHORT: u32 = 5123;
+const UNSIGNED_INT: u32 = 5125;
+const FLOAT: u32 = 5126;
+const ARRAY_BUFFER: u32 = 34962;
+const ELEMENT_ARRAY_BUFFER: u32 = 34963;
+
+impl Model {
+    /// Exports the model as a binary glTF (.glb).
+    ///
+    /// Bones become nodes, with a skin using `inv_world_transforms` as inverse bind matrices.
+    /// Each primitive group becomes a mesh primitive with its own material.
+    /// Each animation is sampled every frame and stored as linear translation, rotation and scale channels.
+    /// Animations without bone tracks for this model are skipped, as glTF animations need a channel.
+    pub fn to_glb(&self, animations: &[(&str, &Animation)]) -> io::Result<Vec<u8>> {
+        let mut glb = GlbWriter::default();
+
+        let mut nodes = self.write_nodes();
+        let skin = self.write_skin(&mut glb);
+        let (images, samplers, textures) = self.write_textures(&mut glb)?;
+        let materials = self.write_materials();
+        let primitives = self.write_primitives(&mut glb);
+        let animations = animations.iter()
+            .filter_map(|(name, anim)| self.write_animation(&mut glb, name, anim))
+            .collect::<Vec<_>>();
+
+        let mut scene_nodes = (0..self.bones.len())
+            .filter(|&i| self.bones[i].parent.is_none())
+            .collect::<Vec<_>>();
+
+        let mut json = String::with_capacity(4096);
+        json.push_str(r#"{"asset":{"version":"2.0","generator":"dat_tools"}"#);
+
+        if self.textures.iter().any(|t| has_texture_transform(t)) {
+            json.push_str(r#","extensionsUsed":["KHR_texture_transform"]"#);
+        }
+
+        if !primitives.is_empty() {
+            scene_nodes.push(nodes.len());
+            let mut mesh_node = String::from(r#"{"name":"mesh","mesh":0"#);
+            if !self.bones.is_empty() { mesh_node.push_str(r#","skin":0"#); }
+            mesh_node.push('}');
+            nodes.push(mesh_node);
+
+            write!(json, r#","meshes":[{{"primitives":[{}]}}]"#, primitives.join(",")).unwrap();
+        }
+
+        write!(json, r#","scene":0,"scenes":[{{"nodes":{}}}]"#, json_list(&scene_nodes)).unwrap();
+        write_array(&mut json, "nodes", &nodes);
+        if let Some(skin) = skin { write_array(&mut json, "skins", &[skin]); }
+        write_array(&mut json, "materials", &materials);
+        write_array(&mut json, "images", &images);
+        write_array(&mut json, "samplers", &samplers);
+        write_array(&mut json, "textures", &textures);
+        write_array(&mut json, "animations", &animations);
+        write_array(&mut json, "accessors", &glb.accessors);
+        write_array(&mut json, "bufferViews", &glb.buffer_views);
+        if !glb.bin.is_empty() {
+            write!(json, r#","buffers":[{{"byteLength":{}}}]"#, glb.bin.len().next_multiple_of(4)).unwrap();
+        }
+        json.push('}');
+
+        Ok(glb.finish(json))
+    }
+
+    pub fn save_glb<P: AsRef<Path>>(&self, path: P, animations: &[(&str, &Animation)]) -> io::Result<()> {
+        std::fs::write(path, self.to_glb(animations)?)
+    }
+
+    fn write_nodes(&self) -> Vec<String> {
+        let mut children = vec![Vec::new(); self.bones.len()];
+        for (i, bone) in self.bones.iter().enumerate() {
+            if let Some(p_i) = bone.parent { children[p_i as usize].push(i); }
+        }
+
+        self.bones.iter().enumerate().map(|(i, _)| {
+            let (scale, rotation, translation) = self.base_transforms[i].to_scale_rotation_translation();
+            let mut node = format!(
+                r#"{{"name":"bone_{}","translation":{},"rotation":{},"scale":{}"#,
+                i, json_floats(&translation.to_array()), json_floats(&rotation.to_array()), json_floats(&scale.to_array()),
+            );
+            if !children[i].is_empty() {
+                write!(node, r#","children":{}"#, json_list(&children[i])).unwrap();
+            }
+            node.push('}');
+            node
+        }).collect()
+    }
+
+    fn write_skin(&self, glb: &mut GlbWriter) -> Option<String> {
+        if self.bones.is_empty() { return None }
+
+        let matrices = self.inv_world_transforms.iter()
+            .flat_map(|m| m.to_cols_array())
+            .collect::<Vec<f32>>();
+        let accessor = glb.push_accessor(bytemuck::cast_slice(&matrices), None, FLOAT, self.bones.len(), "MAT4", None);
+        let joints = (0..self.bones.len()).collect::<Vec<_>>();
+        let skeleton = self.bones.iter().position(|b| b.parent.is_none()).unwrap_or(0);
+
+        Some(format!(
+            r#"{{"inverseBindMatrices":{},"skeleton":{},"joints":{}}}"#,
+            accessor, skeleton, json_list(&joints),
+        ))
+    }
+
+    fn write_textures(&self, glb: &mut GlbWriter) -> io::Result<(Vec<String>, Vec<String>, Vec<String>)> {
+        let mut images = Vec::with_capacity(self.textures.len());
+        let mut samplers = Vec::with_capacity(self.textures.len());
+        let mut textures = Vec::with_capacity(self.textures.len());
+
+        for (i, texture) in self.textures.iter().enumerate() {
+            let png = texture.encode_png()?;
+            let view = glb.push_view(&png, None);
+            images.push(format!(r#"{{"bufferView":{},"mimeType":"image/png"}}"#, view));
+
+            samplers.push(format!(
+                r#"{{"magFilter":{},"minFilter":{},"wrapS":{},"wrapT":{}}}"#,
+                gl_filter(texture.lod.mag_filter.min(tex_filter::LINEAR)),
+                // only the base level is exported, so mip filters would sample missing levels
+                gl_filter(without_mips(texture.lod.min_filter)),
+                gl_wrap(texture.wrap_u),
+                gl_wrap(texture.wrap_v),
+            ));
+
+            textures.push(format!(r#"{{"sampler":{},"source":{}}}"#, i, i));
+        }
+
+        Ok((images, samplers, textures))
+    }
+
+    // one material per primitive group
+    fn write_materials(&self) -> Vec<String> {
+        self.primitive_groups.iter().enumerate().map(|(i, pgroup)| {
+            let material = self.materials.get(i).copied().unwrap_or_default();
+            let [r, g, b, _] = material.phong.diffuse.map(|c| c as f32 / 255.0);
+            let base_colour = [r, g, b, material.alpha.clamp(0.0, 1.0)];
+            let texture = pgroup.texture_idx.and_then(|t| self.textures.get(t as usize).map(|tex| (t, tex)));
+
+            let mut json = format!(
+                r#"{{"name":"material_{}","pbrMetallicRoughness":{{"baseColorFactor":{},"metallicFactor":0,"roughnessFactor":1"#,
+                i, json_floats(&base_colour),
+            );
+
+            if let Some((t, texture)) = texture {
+                write!(json, r#","baseColorTexture":{{"index":{}"#, t).unwrap();
+                if has_texture_transform(texture) {
+                    write!(
+                        json, r#","extensions":{{"KHR_texture_transform":{{"scale":{}}}}}"#,
+                        json_floats(&[texture.scale_x, texture.scale_y]),
+                    ).unwrap();
+                }
+                json.push('}');
+            }
+            json.push('}');
+
+            let cutout = texture.is_some_and(|(_, tex)| tex.rgba_data.iter().any(|p| p >> 24 != 0xFF));
+            if material.is_translucent() {
+                json.push_str(r#","alphaMode":"BLEND""#);
+            } else if cutout {
+                json.push_str(r#","alphaMode":"MASK""#);
+            }
+
+            json.push_str(r#","doubleSided":true}"#);
+            json
+        }).collect()
+    }
+
+    fn write_primitives(&self, glb: &mut GlbWriter) -> Vec<String> {
+        let world_transforms = self.world_transforms(&AnimationFrame::new_default_pose(self));
+
+        let mut owners = vec![0usize; self.primitive_groups.len()];
+        for (i, bone) in self.bones.iter().enumerate() {
+            let start = bone.pgroup_start as usize;
+            let end = start + bone.pgroup_len as usize;
+            for owner in owners[start..end].iter_mut() { *owner = i; }
+        }
+
+        let mut primitives = Vec::with_capacity(self.primitive_groups.len());
+        let mut remap = HashMap::with_capacity(1024);
+        for (pg_i, pgroup) in self.primitive_groups.iter().enumerate() {
+            // glTF 2.0 spec, 5.24 (primitive.mode): triangles, lines, points
+            let index_lists: [(&IndexBuffer, u32, u32, u32); 3] = [
+                (&self.indices, pgroup.indices_start, pgroup.indices_len, 4),
+                (&self.line_indices, pgroup.line_indices_start, pgroup.line_indices_len, 1),
+                (&self.point_indices, pgroup.point_indices_start, pgroup.point_indices_len, 0),
+            ];
+
+            for (all_indices, start, len, mode) in index_lists {
+                if len == 0 { continue }
+
+                let start = start as usize;
+                let indices = all_indices.range(start..start + len as usize);
+
+                // Vertices are written per group, as rigid vertices depend on the owning bone.
+                remap.clear();
+                let mut local_indices = Vec::with_capacity(len as usize);
+                let mut vertex_indices = Vec::with_capacity(len as usize);
+                for i in indices {
+                    let local = *remap.entry(i).or_insert_with(|| {
+                        vertex_indices.push(i);
+                        vertex_indices.len() as u32 - 1
+                    });
+                    local_indices.push(local);
+                }
+
+                let owner = owners[pg_i];
+                let count = vertex_indices.len();
+                let mut positions = Vec::with_capacity(count);
+                let mut normals = Vec::with_capacity(count);
+                let mut uvs = Vec::with_capacity(count);
+                let mut colours = Vec::with_capacity(count);
+                let mut joints = [Vec::with_capacity(count), Vec::with_capacity(count)];
+                let mut weights = [Vec::with_capacity(count), Vec::with_capacity(count)];
+
+                for &i in vertex_indices.iter() {
+                    let v = &self.vertex_attributes[i as usize];
+                    let mut v_weights = v.weights;
+                    let mut v_bones = v.bones.map(|b| b as u16);
+
+                    // Enveloped vertices are already in bind space.
+                    // Single bound and rigid vertices are moved there and weighted fully to their bone.
+                    let transform = skinning_transform(v, owner, &world_transforms, &self.inv_world_transforms);
+                    let weight_sum: f32 = v_weights.iter().sum();
+                    if weight_sum == 0.0 || has_invalid_bones(v_bones, &v_weights, self.bones.len()) {
+                        v_bones = [owner as u16, 0, 0, 0, 0, 0];
+                        v_weights = [1.0, 0.0, 0.0, 0.0, 0.0, 0.0];
+                    } else {
+                        for w in v_weights.iter_mut() { *w /= weight_sum; }
+                    }
+
+                    let normal_transform = Mat3::from_mat4(transform).inverse().transpose();
+                    positions.push(transform.transform_point3(v.pos).to_array());
+                    normals.push((normal_transform * v.normal).normalize_or(Vec3::Y).to_array());
+                    uvs.push(v.uvs[0].to_array());
+                    colours.push(v.colours[0].to_array());
+
+                    for j in 0..2 {
+                        let bones: [u16; 4] = std::array::from_fn(|k| if j*4 + k < 6 { v_bones[j*4 + k] } else { 0 });
+                        let bone_weights: [f32; 4] = std::array::from_fn(|k| if j*4 + k < 6 { v_weights[j*4 + k] } else { 0.0 });
+                        joints[j].push(bones.map(|b| if b as usize >= self.bones.len() { 0 } else { b }));
+                        weights[j].push(bone_weights);
+                    }
+                }
+
+                let (min, max) = positions.iter().fold(
+                    ([f32::MAX; 3], [f32::MIN; 3]),
+                    |(min, max), p| (std::array::from_fn(|k| min[k].min(p[k])), std::array::from_fn(|k| max[k].max(p[k]))),
+                );
+
+                let indices = if count <= u16::MAX as usize {
+                    let local_indices = local_indices.iter().map(|&i| i as u16).collect::<Vec<u16>>();
+                    glb.push_accessor(bytemuck::cast_slice(&local_indices), Some(ELEMENT_ARRAY_BUFFER), UNSIGNED_SHORT, local_indices.len(), "SCALAR", None)
+                } else {
+                    glb.push_accessor(bytemuck::cast_slice(&local_indices), Some(ELEMENT_ARRAY_BUFFER), UNSIGNED_INT, local_indices.len(), "SCALAR", None)
+                };
+                let position = glb.push_accessor(bytemuck::cast_slice(&positions), Some(ARRAY_BUFFER), FLOAT, count, "VEC3", Some((&min, &max)));
+                let normal = glb.push_accessor(bytemuck::cast_slice(&normals), Some(ARRAY_BUFFER), FLOAT, count, "VEC3", None);
+                let uv = glb.push_accessor(bytemuck::cast_slice(&uvs), Some(ARRAY_BUFFER), FLOAT, count, "VEC2", None);
+                let colour = glb.push_accessor(bytemuck::cast_slice(&colours), Some(ARRAY_BUFFER), FLOAT, count, "VEC4", None);
+
+                let mut attributes = format!(
+                    r#""POSITION":{},"NORMAL":{},"TEXCOORD_0":{},"COLOR_0":{}"#,
+                    position, normal, uv, colour,
+                );
+                if !self.bones.is_empty() {
+                    for j in 0..2 {
+                        let joint = glb.push_accessor(bytemuck::cast_slice(&joints[j]), Some(ARRAY_BUFFER), UNSIGNED_SHORT, count, "VEC4", None);
+                        let weight = glb.push_accessor(bytemuck::cast_slice(&weights[j]), Some(ARRAY_BUFFER), FLOAT, count, "VEC4", None);
+                        write!(attributes, r#","JOINTS_{j}":{},"WEIGHTS_{j}":{}"#, joint, weight).unwrap();
+                    }
+                }
+
+                primitives.push(format!(
+                    r#"{{"attributes":{{{}}},"indices":{},"material":{},"mode":{}}}"#,
+                    attributes, indices, pg_i, mode,
+                ));
+            }
+        }
+
+        primitives
+    }
+
+    fn write_animation(&self, glb: &mut GlbWriter, name: &str, animation: &Animation) -> Option<String> {
+        let bone_transforms = animation.bone_transforms.iter()
+            .filter(|t| !t.tracks.is_empty() && t.bone_index < self.base_transforms.len())
+            .collect::<Vec<_>>();
+        if bone_transforms.is_empty() { return None }
+
+        let frame_count = animation.end_frame().max(0.0).ceil() as usize + 1;
+        let times = (0..frame_count)
+            .map(|f| f as f32 / GLTF_FRAMES_PER_SECOND)
+            .collect::<Vec<f32>>();
+        let input = glb.push_accessor(
+            bytemuck::cast_slice(&times), None, FLOAT, frame_count, "SCALAR",
+            Some((&[times[0]], &[times[frame_count - 1]])),
+        );
+
+        let mut samplers = Vec::new();
+        let mut channels = Vec::new();
+        for bone_transform in bone_transforms {
+            let bone_index = bone_transform.bone_index;
+            let base_transform = &self.base_transforms[bone_index];
+
+            let mut translations = Vec::with_capacity(frame_count);
+            let mut rotations = Vec::with_capacity(frame_count);
+            let mut scales = Vec::with_capacity(frame_count);
+            let mut prev_rotation = Quat::IDENTITY;
+            for f in 0..frame_count {
+                let transform = bone_transform.compute_transform_at(f as f32, base_transform).transform;
+                let (scale, mut rotation, translation) = transform.to_scale_rotation_translation();
+
+                // keep the shortest path between samples
+                if prev_rotation.dot(rotation) < 0.0 { rotation = -rotation; }
+                prev_rotation = rotation;
+
+                translations.push(translation.to_array());
+                rotations.push(rotation.to_array());
+                scales.push(scale.to_array());
+            }
+
+            let outputs = [
+                ("translation", glb.push_accessor(bytemuck::cast_slice(&translations), None, FLOAT, frame_count, "VEC3", None)),
+                ("rotation", glb.push_accessor(bytemuck::cast_slice(&rotations), None, FLOAT, frame_count, "VEC4", None)),
+                ("scale", glb.push_accessor(bytemuck::cast_slice(&scales), None, FLOAT, frame_count, "VEC3", None)),
+            ];
+            for (path, output) in outputs {
+                channels.push(format!(
+                    r#"{{"sampler":{},"target":{{"node":{},"path":"{}"}}}}"#,
+                    samplers.len(), bone_index, path,
+                ));
+                samplers.push(format!(r#"{{"input":{},"output":{},"interpolation":"LINEAR"}}"#, input, output));
+            }
+        }
+
+        Some(format!(
+            r#"{{"name":{},"samplers":[{}],"channels":[{}]}}"#,
+            json_string(name), samplers.join(","), channels.join(","),
+        ))
+    }
+}
+
+// envelope weights pointing outside the skeleton are treated as rigid
+fn has_invalid_bones(bones: [u16; 6], weights: &[f32; 6], bone_count: usize) -> bool {
+    bones.iter().zip(weights).any(|(&b, &w)| w != 0.0 && b as usize >= bone_count)
+}
+
+fn has_texture_transform(texture: &Texture) -> bool {
+    texture.scale_x != 1.0 || texture.scale_y != 1.0
+}
+
+// glTF 2.0 spec, 5.26 (sampler.wrapS)
+fn gl_wrap(wrap: WrapMode) -> u32 {
+    match wrap {
+        WrapMode::Clamp => 33071,
+        WrapMode::Repeat => 10497,
+        WrapMode::Mirror => 33648,
+    }
+}
+
+// glTF 2.0 spec, 5.26 (sampler.minFilter)
+fn gl_filter(filter: u32) -> u32 {
+    match filter {
+        tex_filter::NEAR => 9728,
+        tex_filter::NEAR_MIP_NEAR => 9984,
+        tex_filter::LIN_MIP_NEAR => 9985,
+        tex_filter::NEAR_MIP_LIN => 9986,
+        tex_filter::LIN_MIP_LIN => 9987,
+        _ => 9729,
+    }
+}
+
+fn without_mips(filter: u32) -> u32 {
+    match filter {
+        tex_filter::NEAR | tex_filter::NEAR_MIP_NEAR | tex_filter::NEAR_MIP_LIN => tex_filter::NEAR,
+        _ => tex_filter::LINEAR,
+    }
+}
+
+#[derive(Default)]
+struct GlbWriter {
+    bin: Vec<u8>,
+    buffer_views: Vec<String>,
+    accessors: Vec<String>,
+}
+
+impl GlbWriter {
+    fn push_view(&mut self, bytes: &[u8], target: Option<u32>) -> usize {
+        self.bin.resize(self.bin.len().next_multiple_of(4), 0);
+        let offset = self.bin.len();
+        self.bin.extend_from_slice(bytes);
+
+        let mut view = format!(r#"{{"buffer":0,"byteOffset":{},"byteLength":{}"#, offset, bytes.len());
+        if let Some(target) = target { write!(view, r#","target":{}"#, target).unwrap(); }
+        view.push('}');
+
+        self.buffer_views.push(view);
+        self.buffer_views.len() - 1
+    }
+
+    fn push_accessor(
+        &mut self,
+        bytes: &[u8],
+        target: Option<u32>,
+        component_type: u32,
+        count: usize,
+        ty: &str,
+        bounds: Option<(&[f32], &[f32])>,
+    ) -> usize {
+        let view = self.push_view(bytes, target);
+
+        let mut accessor = format!(
+            r#"{{"bufferView":{},"componentType":{},"count":{},"type":"{}""#,
+            view, component_type, count, ty,
+        );
+        if let Some((min, max)) = bounds {
+            write!(accessor, r#","min":{},"max":{}"#, json_floats(min), json_floats(max)).unwrap();
+        }
+        accessor.push('}');
+
+        self.accessors.push(accessor);
+        self.accessors.len() - 1
+    }
+
+    // glTF 2.0 spec, 4.4 (GLB File Format Specification)
+    fn finish(mut self, json: String) -> Vec<u8> {
+        let mut json = json.into_bytes();
+        json.resize(json.len().next_multiple_of(4), b' ');
+        self.bin.resize(self.bin.len().next_multiple_of(4), 0);
+
+        let bin_chunk_len = if self.bin.is_empty() { 0 } else { 8 + self.bin.len() };
+        let total_len = 12 + 8 + json.len() + bin_chunk_len;
+
+        let mut glb = Vec::with_capacity(total_len);
+        glb.extend_from_slice(b"glTF");
+        glb.extend_from_slice(&2u32.to_le_bytes());
+        glb.extend_from_slice(&(total_len as u32).to_le_bytes());
+
+        glb.extend_from_slice(&(json.len() as u32).to_le_bytes());
+        glb.extend_from_slice(b"JSON");
+        glb.extend_from_slice(&json);
+
+        if !self.bin.is_empty() {
+            glb.extend_from_slice(&(self.bin.len() as u32).to_le_bytes());
+            glb.extend_from_slice(b"BIN\0");
+            glb.extend_from_slice(&self.bin);
+        }
+
+        glb
+    }
+}
+
+fn write_array(json: &mut String, name: &str, items: &[String]) {
+    if items.is_empty() { return }
+    write!(json, r#","{}":[{}]"#, name, items.join(",")).unwrap();
+}
+
+fn json_list(items: &[usize]) -> String {
+    let items = items.iter().map(|i| i.to_string()).collect::<Vec<_>>();
+    format!("[{}]", items.join(","))
+}
+
+// JSON has no NaN or infinity
+fn json_floats(floats: &[f32]) -> String {
+    let floats = floats.iter()
+        .map(|f| if f.is_finite() { f.to_string() } else { "0".to_string() })
+        .collect::<Vec<_>>();
+    format!("[{}]", floats.join(","))
+}
+
+fn json_string(s: &str) -> String {
+    let mut json = String::with_capacity(s.len() + 2);
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            c if (c as u32) < 0x20 => write!(json, "\\u{:04x}", c as u32).unwrap(),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dat::{AnimTrack, AnimTransformBone, Bone, InterpolationType, Key, PrimitiveGroup, TrackTypeBone, VertexAttributes};
+    use glam::f32::Mat4;
+
+    fn u32_at(glb: &[u8], offset: usize) -> usize {
+        u32::from_le_bytes(glb[offset..offset + 4].try_into().unwrap()) as usize
+    }
+
+    // One bone holding one triangle.
+    fn triangle() -> Model {
+        let vertex_attributes: Box<[VertexAttributes]> = [Vec3::ZERO, Vec3::X, Vec3::Y].into_iter()
+            .map(|pos| VertexAttributes { pos, normal: Vec3::Z, ..VertexAttributes::default() })
+            .collect();
+
+        Model {
+            bones: Box::new([Bone { parent: None, pgroup_start: 0, pgroup_len: 1 }]),
+            base_transforms: Box::new([Mat4::IDENTITY]),
+            inv_world_transforms: Box::new([Mat4::IDENTITY]),
+            phongs: Box::default(),
+            materials: Box::default(),
+            primitive_groups: Box::new([PrimitiveGroup { indices_len: 3, ..PrimitiveGroup::default() }]),
+            textures: Box::default(),
+            texture_stages: Box::default(),
+            indices: IndexBuffer::new(vec![0, 1, 2]),
+            line_indices: IndexBuffer::new(Vec::new()),
+            point_indices: IndexBuffer::new(Vec::new()),
+            vertices: vertex_attributes.iter().map(VertexAttributes::vertex).collect(),
+            vertex_attributes,
+            morph_sets: Box::default(),
+        }
+    }
+
+    fn animation(bone_index: usize) -> Animation {
+        let keys = [0.0, 1.0].map(|frame| Key {
+            frame: frame * 4.0,
+            interpolation: InterpolationType::Linear,
+            value: frame,
+            in_tan: 0.0,
+            out_tan: 0.0,
+        });
+
+        Animation {
+            bone_transforms: vec![AnimTransformBone {
+                tracks: Box::new([AnimTrack { start_frame: 0.0, track_type: TrackTypeBone::TranslateX, keys: Box::new(keys) }]),
+                flags: 0,
+                end_frame: 4.0,
+                bone_index,
+            }],
+            ..Animation::default()
+        }
+    }
+
+    #[test]
+    fn glb_layout() {
+        let model = triangle();
+        let glb = model.to_glb(&[("move", &animation(0)), ("out_of_range", &animation(5)), ("empty", &Animation::default())]).unwrap();
+
+        // header
+        assert_eq!(&glb[0..4], b"glTF");
+        assert_eq!(u32_at(&glb, 4), 2);
+        assert_eq!(u32_at(&glb, 8), glb.len());
+
+        // chunks are 4 byte aligned and fill the file
+        let json_len = u32_at(&glb, 12);
+        assert_eq!(&glb[16..20], b"JSON");
+        assert_eq!(json_len % 4, 0);
+        let bin_start = 20 + json_len;
+        let bin_len = u32_at(&glb, bin_start);
+        assert_eq!(&glb[bin_start + 4..bin_start + 8], b"BIN\0");
+        assert_eq!(bin_len % 4, 0);
+        assert_eq!(bin_start + 8 + bin_len, glb.len());
+
+        let json = std::str::from_utf8(&glb[20..bin_start]).unwrap();
+        assert!(json.contains(&format!(r#""buffers":[{{"byteLength":{}}}]"#, bin_len)));
+
+        // skin, 9 for the triangle and 4 for the animation
+        assert_eq!(json.matches(r#""componentType""#).count(), 14);
+        assert_eq!(json.matches(r#""count":3,"type":"VEC3""#).count(), 2);
+
+        // animations without channels are skipped
+        assert!(json.contains(r#""name":"move""#));
+        assert!(!json.contains("out_of_range") && !json.contains(r#""name":"empty""#));
+        assert!(!json.contains(r#""channels":[]"#) && !json.contains(r#""samplers":[]"#));
+        assert_eq!(json.matches(r#""interpolation":"LINEAR""#).count(), 3);
+    }
+}
@@ -0,0 +1,656 @@
+use crate::dat::{
+    DatFile, DatExtractError, DatPatcher, HSDRawFile, JOBJ, VertexAttributes, PrimitiveType,
+    jobj::{AttributeName, AttributeType, CompTypeFormat, CompTypeColour, POBJFlag},
+    vertex_layout::influences,
+};
+use ahash::{HashMap, HashMapExt};
+
+/// Controls how `append_pobjs` stores vertex attributes.
+#[derive(Copy, Clone, Debug)]
+pub struct POBJImportOptions {
+    /// Largest error allowed when storing positions as fixed point. Larger errors are stored as floats.
+    pub position_tolerance: f32,
+    pub normal_tolerance: f32,
+    pub uv_tolerance: f32,
+
+    /// Write CLR0 from `VertexAttributes::colours[0]`.
+    pub colours: bool,
+
+    /// Number of TEX attributes to write, up to 8.
+    pub uv_count: usize,
+
+    /// Set on every POBJ, e.g. cull flags. The envelope flag is set when needed.
+    pub flags: u16,
+}
+
+impl Default for POBJImportOptions {
+    fn default() -> Self {
+        POBJImportOptions {
+            position_tolerance: 0.001,
+            normal_tolerance: 0.01,
+            uv_tolerance: 0.0005,
+            colours: false,
+            uv_count: 1,
+            flags: 0,
+        }
+    }
+}
+
+// GX has ten position matrix slots, PNMTXIDX is the slot times 3.
+const MAX_ENVELOPES: usize = 10;
+
+// Envelope::jobjs reads at most four influences.
+const MAX_INFLUENCES: usize = 4;
+
+// GX_NRM_XYZ, GX_POS_XYZ, GX_CLR_RGBA, GX_TEX_ST
+const COMP_COUNT_NRM_XYZ: u32 = 0;
+const COMP_COUNT_XYZ: u32 = 1;
+const COMP_COUNT_RGBA: u32 = 1;
+const COMP_COUNT_ST: u32 = 1;
+
+// GX ignores the scale of normals, s8 normals always have 6 fractional bits and s16 normals 14.
+const NORMAL_SCALES: [u8; 2] = [6, 14];
+
+/// Replaces the POBJs of a DOBJ with the triangles.
+/// `dobj_idx` is the same as `TextureTarget::DOBJIndex`.
+///
+/// Bone indices in `VertexAttributes::bones` are in the order of `extract_model_from_jobj`.
+/// Vertices are in the spaces described by `append_pobjs`.
+/// The DOBJ's JOBJ must already be set up for the kind of mesh,
+/// so enveloped geometry replaces enveloped geometry and rigid geometry replaces rigid geometry.
+/// Cull flags are kept from the old first POBJ.
+pub fn replace_dobj_geometry(
+    dat: &DatFile,
+    dobj_idx: usize,
+    vertices: &[VertexAttributes],
+    indices: &[u32],
+    options: &POBJImportOptions,
+) -> Result<DatFile, DatExtractError> {
+    let hsd_file = HSDRawFile::new(dat);
+    let root_jobj = hsd_file.roots.iter().find_map(JOBJ::try_from_root_node).ok_or(DatExtractError::InvalidDatFile)?;
+    let dobj = root_jobj.get_all_dobjs().into_iter().nth(dobj_idx).ok_or(DatExtractError::InvalidDatFile)?;
+    let dobj_offset = DatPatcher::struct_offset(dat, &dobj.hsd_struct).ok_or(DatExtractError::InvalidDatFile)?;
+
+    let bone_jobjs = root_jobj.get_all_bones().iter()
+        .map(|jobj| DatPatcher::struct_offset(dat, &jobj.hsd_struct))
+        .collect::<Option<Vec<usize>>>()
+        .ok_or(DatExtractError::InvalidDatFile)?;
+
+    let cull_mask = POBJFlag::CullBack as u16 | POBJFlag::CullFront as u16;
+    let old_flags = dobj.get_pobj().map(|pobj| pobj.hsd_struct.get_u16(0x0C)).unwrap_or(0);
+    let options = POBJImportOptions { flags: options.flags | (old_flags & cull_mask), ..*options };
+
+    let mut patcher = DatPatcher::new(dat)?;
+    let pobj_offset = append_pobjs(&mut patcher, vertices, indices, &bone_jobjs, &options)?;
+    patcher.set_pointer(dobj_offset + 0x0C, pobj_offset);
+
+    Ok(patcher.finish())
+}
+
+/// Appends POBJs holding the triangles and returns the offset of the first.
+/// The rest are linked as its siblings.
+///
+/// Vertices are in the same spaces that `POBJ::decode_primitives` produces, which are not all bind space:
+/// - Vertices without weights are rigid, in the space of the DOBJ's JOBJ.
+/// - Vertices weighted fully to one bone are in that bone's local space, not bind space.
+///   Move bind space vertices there with the inverse of `skinning_transform` in the bind pose.
+/// - Vertices with several weights are in bind space.
+///
+/// Either every vertex has weights, or none do. Weights are split into envelopes of up to four influences.
+/// `bone_jobjs` is the offset of the JOBJ for each bone index.
+///
+/// A new POBJ is started whenever one would need more than ten envelopes or 65536 vertices.
+pub fn append_pobjs(
+    patcher: &mut DatPatcher,
+    vertices: &[VertexAttributes],
+    indices: &[u32],
+    bone_jobjs: &[usize],
+    options: &POBJImportOptions,
+) -> Result<usize, DatExtractError> {
+    if indices.is_empty() || !indices.len().is_multiple_of(3) { return Err(DatExtractError::InvalidDatFile) }
+    if indices.iter().any(|&i| i as usize >= vertices.len()) { return Err(DatExtractError::InvalidDatFile) }
+
+    let is_weighted = |v: &VertexAttributes| v.weights.iter().any(|&w| w != 0.0);
+    let enveloped = indices.iter().any(|&i| is_weighted(&vertices[i as usize]));
+
+    // envelope of each vertex
+    let mut envelopes: Vec<Envelope> = Vec::new();
+    let mut envelope_lookup: HashMap<Envelope, u32> = HashMap::new();
+    let mut vertex_envelopes = vec![0u32; vertices.len()];
+    if enveloped {
+        for &i in indices.iter() {
+            let v = &vertices[i as usize];
+            if !is_weighted(v) { return Err(DatExtractError::InvalidDatFile) }
+
+            let (weights, bones) = influences(v, MAX_INFLUENCES);
+            let mut envelope = Envelope::default();
+            for (&w, &b) in weights.iter().zip(bones.iter()).take(MAX_INFLUENCES) {
+                if w == 0.0 { continue }
+                if b as usize >= bone_jobjs.len() { return Err(DatExtractError::InvalidDatFile) }
+                envelope.influences[envelope.len] = (b, w.to_bits());
+                envelope.len += 1;
+            }
+
+            let envelope_idx = *envelope_lookup.entry(envelope).or_insert_with(|| {
+                envelopes.push(envelope);
+                envelopes.len() as u32 - 1
+            });
+            vertex_envelopes[i as usize] = envelope_idx;
+        }
+    }
+
+    // split triangles into POBJs
+    let mut chunks: Vec<Chunk> = Vec::new();
+    let mut chunk = Chunk::default();
+    for tri in indices.chunks_exact(3) {
+        let tri = [tri[0], tri[1], tri[2]];
+
+        // degenerate triangles draw nothing, and would confuse strip building
+        if tri[0] == tri[1] || tri[1] == tri[2] || tri[2] == tri[0] { continue }
+
+        let mut new_envelopes = 0;
+        let mut new_vertices = 0;
+        for (k, &i) in tri.iter().enumerate() {
+            let envelope_idx = vertex_envelopes[i as usize];
+            let earlier = &tri[..k];
+            if enveloped && !chunk.envelopes.contains(&envelope_idx)
+                && !earlier.iter().any(|&j| vertex_envelopes[j as usize] == envelope_idx) {
+                new_envelopes += 1;
+            }
+            if !chunk.vertex_lookup.contains_key(&i) && !earlier.contains(&i) {
+                new_vertices += 1;
+            }
+        }
+
+        if chunk.envelopes.len() + new_envelopes > MAX_ENVELOPES
+            || chunk.vertices.len() + new_vertices > u16::MAX as usize + 1 {
+            chunks.push(std::mem::take(&mut chunk));
+        }
+
+        let mut local_tri = [0u32; 3];
+        for (local, &i) in local_tri.iter_mut().zip(tri.iter()) {
+            *local = chunk.add_vertex(i, enveloped.then(|| vertex_envelopes[i as usize]));
+        }
+        chunk.triangles.push(local_tri);
+    }
+    if !chunk.triangles.is_empty() { chunks.push(chunk); }
+    if chunks.is_empty() { return Err(DatExtractError::InvalidDatFile) }
+
+    // append POBJs, linking each to the previous
+    let mut first_pobj = None;
+    let mut prev_pobj: Option<usize> = None;
+    for chunk in chunks.iter() {
+        let pobj_offset = append_pobj(patcher, vertices, &envelopes, bone_jobjs, chunk, options);
+        match prev_pobj {
+            Some(prev) => patcher.set_pointer(prev + 0x04, pobj_offset),
+            None => first_pobj = Some(pobj_offset),
+        }
+        prev_pobj = Some(pobj_offset);
+    }
+
+    Ok(first_pobj.unwrap())
+}
+
+#[derive(Copy, Clone, Default, PartialEq, Eq, Hash)]
+struct Envelope {
+    // bone index and weight bits
+    influences: [(u32, u32); MAX_INFLUENCES],
+    len: usize,
+}
+
+#[derive(Default)]
+struct Chunk {
+    /// Indices into the input vertices.
+    vertices: Vec<u32>,
+    vertex_lookup: HashMap<u32, u32>,
+    triangles: Vec<[u32; 3]>,
+
+    /// Indices into the envelope list.
+    envelopes: Vec<u32>,
+
+    /// Position of each vertex's envelope in `envelopes`. PNMTXIDX is this times 3.
+    vertex_slots: Vec<u8>,
+}
+
+impl Chunk {
+    fn add_vertex(&mut self, i: u32, envelope: Option<u32>) -> u32 {
+        if let Some(&local) = self.vertex_lookup.get(&i) { return local }
+
+        let slot = match envelope {
+            Some(envelope) => match self.envelopes.iter().position(|&e| e == envelope) {
+                Some(slot) => slot,
+                None => {
+                    self.envelopes.push(envelope);
+                    self.envelopes.len() - 1
+                }
+            },
+            None => 0,
+        };
+
+        let local = self.vertices.len() as u32;
+        self.vertices.push(i);
+        self.vertex_slots.push(slot as u8);
+        self.vertex_lookup.insert(i, local);
+        local
+    }
+}
+
+// Writes the POBJ in the layout read by `POBJ::decode_primitives`.
+// HSD_POBJ.cs, GX/GX_Attribute.cs, GX/GX_PrimitiveGroup.cs
+fn append_pobj(
+    patcher: &mut DatPatcher,
+    vertices: &[VertexAttributes],
+    envelopes: &[Envelope],
+    bone_jobjs: &[usize],
+    chunk: &Chunk,
+    options: &POBJImportOptions,
+) -> usize {
+    let enveloped = !chunk.envelopes.is_empty();
+    let chunk_vertices = chunk.vertices.iter().map(|&i| &vertices[i as usize]);
+
+    // attribute arrays -----------------------------------------------------------
+    let mut arrays: Vec<AttributeArray> = Vec::with_capacity(11);
+    arrays.push(AttributeArray::quantized(
+        AttributeName::GX_VA_POS, COMP_COUNT_XYZ, options.position_tolerance, None,
+        chunk_vertices.clone().map(|v| v.pos.to_array()),
+    ));
+    arrays.push(AttributeArray::quantized(
+        AttributeName::GX_VA_NRM, COMP_COUNT_NRM_XYZ, options.normal_tolerance, Some(NORMAL_SCALES),
+        chunk_vertices.clone().map(|v| v.normal.to_array()),
+    ));
+    if options.colours {
+        arrays.push(AttributeArray::colours(chunk_vertices.clone().map(|v| v.colours[0].to_array())));
+    }
+    for uv_idx in 0..options.uv_count.min(8) {
+        let name = AttributeName::from_u8(AttributeName::GX_VA_TEX0 as u8 + uv_idx as u8);
+        arrays.push(AttributeArray::quantized(
+            name, COMP_COUNT_ST, options.uv_tolerance, None,
+            chunk_vertices.clone().map(|v| v.uvs[uv_idx].to_array()),
+        ));
+    }
+
+    // attribute list, ends with GX_VA_NULL
+    let attribute_count = arrays.len() + enveloped as usize + 1;
+    let attributes_offset = patcher.append(&vec![0u8; attribute_count * 0x18], 4);
+    let mut attr_offset = attributes_offset;
+    if enveloped {
+        patcher.write_u32(attr_offset, AttributeName::GX_VA_PNMTXIDX as u32);
+        patcher.write_u32(attr_offset + 0x04, AttributeType::GX_DIRECT as u32);
+        attr_offset += 0x18;
+    }
+    for array in arrays.iter() {
+        let buffer_offset = patcher.append(&array.data, 0x20);
+        patcher.write_u32(attr_offset, array.name as u32);
+        patcher.write_u32(attr_offset + 0x04, AttributeType::GX_INDEX16 as u32);
+        patcher.write_u32(attr_offset + 0x08, array.comp_count);
+        patcher.write_u32(attr_offset + 0x0C, array.comp_type as u32);
+        patcher.write_u8(attr_offset + 0x10, array.scale);
+        patcher.write_u16(attr_offset + 0x12, array.stride as u16);
+        patcher.set_pointer(attr_offset + 0x14, buffer_offset);
+        attr_offset += 0x18;
+    }
+    patcher.write_u32(attr_offset, AttributeName::GX_VA_NULL as u32);
+    patcher.write_u32(attr_offset + 0x04, AttributeType::GX_NONE as u32);
+
+    // display list ---------------------------------------------------------------
+    let write_vertex = |display_list: &mut Vec<u8>, local: u32| {
+        if enveloped {
+            display_list.push(chunk.vertex_slots[local as usize] * 3);
+        }
+        for array in arrays.iter() {
+            display_list.extend_from_slice(&(array.indices[local as usize] as u16).to_be_bytes());
+        }
+    };
+
+    let (strips, loose) = stripify(&chunk.triangles);
+    let mut display_list = Vec::new();
+    for strip in strips.iter() {
+        display_list.push(PrimitiveType::TriangleStrip as u8);
+        display_list.extend_from_slice(&(strip.len() as u16).to_be_bytes());
+        for &local in strip.iter() { write_vertex(&mut display_list, local); }
+    }
+    for triangles in loose.chunks(u16::MAX as usize) {
+        display_list.push(PrimitiveType::Triangles as u8);
+        display_list.extend_from_slice(&(triangles.len() as u16).to_be_bytes());
+        for &local in triangles.iter() { write_vertex(&mut display_list, local); }
+    }
+    display_list.push(0);
+    display_list.resize(display_list.len().next_multiple_of(0x20), 0);
+    let display_list_offset = patcher.append(&display_list, 0x20);
+
+    // envelopes ------------------------------------------------------------------
+    let envelope_list_offset = enveloped.then(|| {
+        let list_offset = patcher.append(&vec![0u8; (chunk.envelopes.len() + 1) * 4], 4);
+        for (i, &envelope_idx) in chunk.envelopes.iter().enumerate() {
+            let envelope = &envelopes[envelope_idx as usize];
+
+            // HSD_Envelope: JOBJ pointer and weight, ends with a null entry
+            let envelope_offset = patcher.append(&vec![0u8; (envelope.len + 1) * 8], 4);
+            for (j, &(bone, weight)) in envelope.influences[..envelope.len].iter().enumerate() {
+                patcher.set_pointer(envelope_offset + j * 8, bone_jobjs[bone as usize]);
+                patcher.write_f32(envelope_offset + j * 8 + 4, f32::from_bits(weight));
+            }
+            patcher.set_pointer(list_offset + i * 4, envelope_offset);
+        }
+        list_offset
+    });
+
+    // HSD_POBJ -------------------------------------------------------------------
+    let pobj_offset = patcher.append(&[0u8; 0x18], 4);
+    let mut flags = options.flags & !(POBJFlag::Envelope as u16);
+    if enveloped { flags |= POBJFlag::Envelope as u16; }
+    patcher.set_pointer(pobj_offset + 0x08, attributes_offset);
+    patcher.write_u16(pobj_offset + 0x0C, flags);
+    patcher.write_u16(pobj_offset + 0x0E, (display_list.len() / 0x20) as u16);
+    patcher.set_pointer(pobj_offset + 0x10, display_list_offset);
+    if let Some(envelope_list_offset) = envelope_list_offset {
+        patcher.set_pointer(pobj_offset + 0x14, envelope_list_offset);
+    }
+
+    pobj_offset
+}
+
+struct AttributeArray {
+    name: AttributeName,
+    comp_count: u32,
+    comp_type: u8,
+    scale: u8,
+    stride: usize,
+    data: Vec<u8>,
+
+    /// Index into `data` for each vertex in the chunk.
+    indices: Vec<u32>,
+}
+
+impl AttributeArray {
+    // `scales` fixes the fractional bits for s8 and s16, otherwise they are chosen from the values.
+    fn quantized<const N: usize>(
+        name: AttributeName,
+        comp_count: u32,
+        tolerance: f32,
+        scales: Option<[u8; 2]>,
+        values: impl Iterator<Item=[f32; N]>,
+    ) -> Self {
+        let (unique, indices) = dedupe(values, |v| v.map(f32::to_bits));
+        let flat: Vec<f32> = unique.iter().flatten().copied().collect();
+        let (comp_type, scale) = choose_format(&flat, tolerance, scales);
+
+        let mut data = Vec::with_capacity(flat.len() * 4);
+        let fixed = |v: f32| (v * (1u32 << scale) as f32).round();
+        for &v in flat.iter() {
+            match comp_type {
+                CompTypeFormat::Int8 => data.push(fixed(v) as i8 as u8),
+                CompTypeFormat::Int16 => data.extend_from_slice(&(fixed(v) as i16).to_be_bytes()),
+                _ => data.extend_from_slice(&v.to_be_bytes()),
+            }
+        }
+
+        let comp_size = match comp_type {
+            CompTypeFormat::Int8 => 1,
+            CompTypeFormat::Int16 => 2,
+            _ => 4,
+        };
+
+        AttributeArray {
+            name,
+            comp_count,
+            comp_type: comp_type as u8,
+            scale,
+            stride: comp_size * N,
+            data,
+            indices,
+        }
+    }
+
+    fn colours(values: impl Iterator<Item=[f32; 4]>) -> Self {
+        let (unique, indices) = dedupe(values, |c| c.map(|c| (c.clamp(0.0, 1.0) * 255.0).round() as u8));
+        let data = unique.iter()
+            .flat_map(|c| c.map(|c| (c.clamp(0.0, 1.0) * 255.0).round() as u8))
+            .collect();
+
+        AttributeArray {
+            name: AttributeName::GX_VA_CLR0,
+            comp_count: COMP_COUNT_RGBA,
+            comp_type: CompTypeColour::RGBA8 as u8,
+            scale: 0,
+            stride: 4,
+            data,
+            indices,
+        }
+    }
+}
+
+// Returns the unique values, and the index of each value in them.
+fn dedupe<T: Copy, K: Eq + std::hash::Hash>(
+    values: impl Iterator<Item=T>,
+    key: impl Fn(&T) -> K,
+) -> (Vec<T>, Vec<u32>) {
+    let mut unique = Vec::new();
+    let mut lookup: HashMap<K, u32> = HashMap::new();
+    let indices = values.map(|v| {
+        *lookup.entry(key(&v)).or_insert_with(|| {
+            unique.push(v);
+            unique.len() as u32 - 1
+        })
+    }).collect();
+
+    (unique, indices)
+}
+
+// Smallest fixed point format within the tolerance, with the most fractional bits that fit the range,
+// or the fixed fractional bits of `scales` for s8 and s16. Falls back to floats.
+fn choose_format(values: &[f32], tolerance: f32, scales: Option<[u8; 2]>) -> (CompTypeFormat, u8) {
+    let max = values.iter().fold(0f32, |max, v| max.max(v.abs()));
+    if !max.is_finite() { return (CompTypeFormat::Float, 0) }
+
+    let formats = [(CompTypeFormat::Int8, i8::MAX as f32), (CompTypeFormat::Int16, i16::MAX as f32)];
+    for (k, (format, limit)) in formats.into_iter().enumerate() {
+        let scale = match scales {
+            Some(scales) => {
+                if (max * (1u32 << scales[k]) as f32).round() > limit { continue }
+                scales[k]
+            }
+            None => {
+                if max.round() > limit { continue }
+
+                let mut scale = 0u8;
+                while scale < 15 && (max * (1u32 << (scale + 1)) as f32).round() <= limit {
+                    scale += 1;
+                }
+                scale
+            }
+        };
+
+        let factor = (1u32 << scale) as f32;
+        let within_tolerance = values.iter().all(|&v| ((v * factor).round() / factor - v).abs() <= tolerance);
+        if within_tolerance { return (format, scale) }
+    }
+
+    (CompTypeFormat::Float, 0)
+}
+
+// Greedy triangle strips. A strip grows while an unused triangle continues it with the same winding.
+// Triangles that don't join a strip are returned as a triangle list.
+// Decoded with the alternating winding in `POBJ::decode_primitives`.
+fn stripify(triangles: &[[u32; 3]]) -> (Vec<Vec<u32>>, Vec<u32>) {
+    // directed edge to triangles containing it, in winding order
+    let mut edges: HashMap<(u32, u32), Vec<usize>> = HashMap::new();
+    for (t, tri) in triangles.iter().enumerate() {
+        for k in 0..3 {
+            edges.entry((tri[k], tri[(k + 1) % 3])).or_default().push(t);
+        }
+    }
+
+    let mut used = vec![false; triangles.len()];
+    let mut strips = Vec::new();
+    let mut loose = Vec::new();
+
+    for start in 0..triangles.len() {
+        if used[start] { continue }
+        used[start] = true;
+
+        let mut strip = triangles[start].to_vec();
+        while strip.len() < u16::MAX as usize {
+            // even triangles are (k, k+1, k+2), odd triangles are (k, k+2, k+1)
+            let k = strip.len() - 2;
+            let edge = if k.is_multiple_of(2) { (strip[k], strip[k + 1]) } else { (strip[k + 1], strip[k]) };
+
+            let next = edges.get(&edge).and_then(|ts| ts.iter().copied().find(|&t| !used[t]));
+            let t = match next {
+                Some(t) => t,
+                None => break,
+            };
+
+            used[t] = true;
+            let tri = triangles[t];
+            let p = (0..3).find(|&p| tri[p] == edge.0 && tri[(p + 1) % 3] == edge.1).unwrap();
+            strip.push(tri[(p + 2) % 3]);
+        }
+
+        if strip.len() == 3 {
+            loose.extend_from_slice(&strip);
+        } else {
+            strips.push(strip);
+        }
+    }
+
+    (strips, loose)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dat::{DatFile, MeshBuilder};
+    use glam::f32::Vec3;
+
+    // Root "test_joint" JOBJ @0x00 with a child JOBJ @0x40, and a DOBJ @0x80 on the root.
+    fn test_dat() -> DatFile {
+        let mut data = vec![0u8; 0x90];
+        for (offset, target) in [(0x08, 0x40u32), (0x10, 0x80)] {
+            data[offset..offset + 4].copy_from_slice(&target.to_be_bytes());
+        }
+        for jobj in [0x00, 0x40] {
+            for k in 0..3 {
+                data[jobj + 0x2C + k * 4..][..4].copy_from_slice(&1.0f32.to_be_bytes());
+            }
+        }
+
+        let relocs = [0x08u32, 0x10];
+        let strings = b"test_joint\0";
+        let mut file = Vec::new();
+        let fsize = 0x20 + data.len() + relocs.len() * 4 + 8 + strings.len();
+        for n in [fsize as u32, data.len() as u32, relocs.len() as u32, 1, 0] {
+            file.extend_from_slice(&n.to_be_bytes());
+        }
+        file.extend_from_slice(&[0u8; 12]);
+        file.extend_from_slice(&data);
+        for r in relocs { file.extend_from_slice(&r.to_be_bytes()); }
+        file.extend_from_slice(&[0u8; 8]);
+        file.extend_from_slice(strings);
+
+        DatFile { filename: "test.dat".into(), data: file.into_boxed_slice().into() }
+    }
+
+    // Grid weighted across two bones. The first and last columns are weighted fully to one bone.
+    fn test_mesh() -> (Vec<VertexAttributes>, Vec<u32>) {
+        const SIZE: u32 = 12;
+        let mut vertices = Vec::new();
+        for y in 0..SIZE {
+            for x in 0..SIZE {
+                let t = x as f32 / (SIZE - 1) as f32;
+                let mut v = VertexAttributes {
+                    pos: Vec3::new(x as f32 * 0.5, y as f32 * 0.5, 0.0),
+                    normal: Vec3::new(x as f32 - 5.5, y as f32 - 5.5, 10.0).normalize(),
+                    ..VertexAttributes::default()
+                };
+                v.uvs[0] = glam::f32::Vec2::new(t, y as f32 / (SIZE - 1) as f32);
+
+                // descending, the order envelopes are written in
+                let influences = if t >= 0.5 { [(1, t), (0, 1.0 - t)] } else { [(0, 1.0 - t), (1, t)] };
+                for (k, &(bone, weight)) in influences.iter().filter(|(_, w)| *w != 0.0).enumerate() {
+                    v.bones[k] = bone;
+                    v.weights[k] = weight;
+                }
+                vertices.push(v);
+            }
+        }
+
+        let mut indices = Vec::new();
+        for y in 0..SIZE - 1 {
+            for x in 0..SIZE - 1 {
+                let i = y * SIZE + x;
+                indices.extend_from_slice(&[i, i + 1, i + SIZE, i + 1, i + SIZE + 1, i + SIZE]);
+            }
+        }
+
+        (vertices, indices)
+    }
+
+    // Triangle rotated to start at its smallest key, so strips and lists compare equal.
+    fn triangle_key(tri: [&VertexAttributes; 3]) -> Vec<[i32; 6]> {
+        let key = |v: &VertexAttributes| [
+            (v.pos.x * 1000.0).round() as i32,
+            (v.pos.y * 1000.0).round() as i32,
+            (v.pos.z * 1000.0).round() as i32,
+            (v.weights[0] * 1000.0).round() as i32,
+            v.bones[0] as i32,
+            (v.uvs[0].x * 1000.0).round() as i32,
+        ];
+        let keys = tri.map(key);
+        let first = (0..3).min_by_key(|&k| keys[k]).unwrap();
+        (0..3).map(|k| keys[(first + k) % 3]).collect()
+    }
+
+    #[test]
+    fn append_pobjs_round_trip() {
+        let (vertices, indices) = test_mesh();
+        let dat = test_dat();
+        let new_dat = replace_dobj_geometry(&dat, 0, &vertices, &indices, &POBJImportOptions::default()).unwrap();
+
+        let hsd_file = HSDRawFile::new(&new_dat);
+        let root_jobj = hsd_file.roots.iter().find_map(JOBJ::try_from_root_node).unwrap();
+        let bone_jobjs = root_jobj.get_all_bones();
+        let pobj = root_jobj.get_all_dobjs()[0].get_pobj().unwrap();
+
+        let mut builder = MeshBuilder {
+            indices: Vec::new(),
+            line_indices: Vec::new(),
+            point_indices: Vec::new(),
+            vertex_attributes: Vec::new(),
+            reuse_start: 0,
+        };
+        let pobjs: Vec<_> = pobj.siblings().collect();
+        for pobj in pobjs.iter() {
+            pobj.decode_primitives(&mut builder, &bone_jobjs);
+        }
+
+        // twelve envelopes don't fit in one POBJ
+        assert!(pobjs.len() > 1);
+
+        let mut expected: Vec<_> = indices.chunks_exact(3)
+            .map(|t| triangle_key([&vertices[t[0] as usize], &vertices[t[1] as usize], &vertices[t[2] as usize]]))
+            .collect();
+        let mut decoded: Vec<_> = builder.indices.chunks_exact(3)
+            .map(|t| {
+                let v = &builder.vertex_attributes;
+                triangle_key([&v[t[0] as usize], &v[t[1] as usize], &v[t[2] as usize]])
+            })
+            .collect();
+        expected.sort();
+        decoded.sort();
+        assert_eq!(expected, decoded);
+
+        // vertices are at unique positions
+        for v in builder.vertex_attributes.iter() {
+            let input = vertices.iter().find(|input| (input.pos - v.pos).length() < 0.001).unwrap();
+            assert!((input.normal - v.normal).length() < POBJImportOptions::default().normal_tolerance * 2.0);
+        }
+    }
+
+    #[test]
+    fn normals_use_fixed_scale() {
+        let normals = [0.99, -0.5, 0.1, 0.0, 0.8, -0.6];
+        assert!(matches!(choose_format(&normals, 0.01, Some(NORMAL_SCALES)), (CompTypeFormat::Int8, 6)));
+        assert!(matches!(choose_format(&normals, 0.001, Some(NORMAL_SCALES)), (CompTypeFormat::Int16, 14)));
+    }
+}
@@ -0,0 +1,137 @@
+use crate::dat::{
+    HSDStruct, Image, InternalTextureFormat, TLUT, TLUTFormat, TOBJ,
+    EncodedImage, EncodedTLUT, encode_palette,
+    encode_textures::tiled_coords,
+};
+
+/// A CI4, CI8 or CI14X2 image with its palette kept separate.
+///
+/// Swap `palette` for colour variants, or upload the indices and palette separately for shader recolours.
+/// `encode` writes the same indices back, so re-encoding is lossless.
+#[derive(Clone, Debug)]
+pub struct IndexedImage {
+    pub width: usize,
+    pub height: usize,
+
+    /// One palette index per pixel, row major.
+    pub indices: Box<[u16]>,
+
+    /// Same packing as `Image::rgba_data`.
+    pub palette: Box<[u32]>,
+
+    pub format: InternalTextureFormat,
+    pub tlut_format: TLUTFormat,
+}
+
+impl IndexedImage {
+    /// Indices outside the palette are transparent black.
+    pub fn to_image(&self) -> Image {
+        self.to_image_with_palette(&self.palette)
+    }
+
+    /// Expands the indices with a different palette.
+    pub fn to_image_with_palette(&self, palette: &[u32]) -> Image {
+        let rgba_data = self.indices.iter()
+            .map(|&i| palette.get(i as usize).copied().unwrap_or(0))
+            .collect();
+
+        Image { width: self.width, height: self.height, rgba_data }
+    }
+
+    pub fn encode(&self) -> EncodedImage {
+        let mut data = vec![0u8; self.format.encoded_size(self.width, self.height)].into_boxed_slice();
+        encode_indices(self.format, self.width, self.height, &self.indices, &mut data);
+
+        EncodedImage {
+            format: self.format,
+            width: self.width,
+            height: self.height,
+            data,
+            tlut: Some(EncodedTLUT {
+                format: self.tlut_format,
+                colour_count: self.palette.len() as u16,
+                data: encode_palette(&self.palette, self.tlut_format),
+            }),
+        }
+    }
+}
+
+impl<'a> TOBJ<'a> {
+    /// None if the TOBJ is not paletted.
+    pub fn indexed_image(&self) -> Option<IndexedImage> {
+        let hsd_image = self.hsd_struct.try_get_reference(0x4C)?;
+        let tlut = self.hsd_struct.try_get_reference(0x50).map(TLUT::new)?;
+        decode_indexed_image(hsd_image, tlut)
+    }
+}
+
+/// Decodes the base level of a paletted HSD_Image without expanding the palette.
+/// None if the image is not CI4, CI8 or CI14X2.
+pub fn decode_indexed_image(hsd_image: HSDStruct<'_>, tlut: TLUT<'_>) -> Option<IndexedImage> {
+    let data_buffer = hsd_image.get_buffer(0x00);
+    let width = hsd_image.get_u16(0x04) as usize;
+    let height = hsd_image.get_u16(0x06) as usize;
+    let format = InternalTextureFormat::new(hsd_image.get_u32(0x08))?;
+    let tlut_format = TLUTFormat::new(tlut.hsd_struct.get_u32(0x04))?;
+
+    let mut indices = vec![0u16; width * height].into_boxed_slice();
+    match format {
+        InternalTextureFormat::CI4 | InternalTextureFormat::CI8 | InternalTextureFormat::CI14X2 => {
+            if data_buffer.len() < format.encoded_size(width, height) { return None }
+            decode_indices(format, width, height, data_buffer, &mut indices);
+        }
+        _ => return None,
+    }
+
+    Some(IndexedImage {
+        width,
+        height,
+        indices,
+        palette: tlut.palette(),
+        format,
+        tlut_format,
+    })
+}
+
+/// Same tiling as decode_ci4_image, decode_ci8_image and decode_ci14x2_image.
+/// Does nothing for formats without a palette.
+pub fn decode_indices(format: InternalTextureFormat, width: usize, height: usize, data: &[u8], indices: &mut [u16]) {
+    for (i, (x, y)) in tiled_coords(format, width, height).enumerate() {
+        let index = match format {
+            InternalTextureFormat::CI4 => {
+                let byte = data[i / 2] as u16;
+                if i % 2 == 0 { byte >> 4 } else { byte & 0x0F }
+            }
+            InternalTextureFormat::CI8 => data[i] as u16,
+            InternalTextureFormat::CI14X2 => u16::from_be_bytes([data[i*2], data[i*2+1]]) & 0x3FFF,
+            _ => return,
+        };
+
+        if x < width && y < height {
+            indices[y * width + x] = index;
+        }
+    }
+}
+
+/// Inverse of `decode_indices`. Padding outside the image repeats the nearest edge index.
+pub fn encode_indices(format: InternalTextureFormat, width: usize, height: usize, indices: &[u16], data: &mut [u8]) {
+    if width == 0 || height == 0 { return }
+
+    for (i, (x, y)) in tiled_coords(format, width, height).enumerate() {
+        let index = indices[y.min(height - 1) * width + x.min(width - 1)];
+
+        match format {
+            InternalTextureFormat::CI4 => {
+                let nibble = (index & 0x0F) as u8;
+                if i % 2 == 0 {
+                    data[i / 2] = nibble << 4;
+                } else {
+                    data[i / 2] |= nibble;
+                }
+            }
+            InternalTextureFormat::CI8 => data[i] = index as u8,
+            InternalTextureFormat::CI14X2 => data[i*2..i*2+2].copy_from_slice(&(index & 0x3FFF).to_be_bytes()),
+            _ => return,
+        }
+    }
+}
@@ -1,7 +1,10 @@
 #![allow(clippy::upper_case_acronyms)]
 
-use crate::dat::{HSDStruct, HSDRootNode, Vertex, PrimitiveType, MeshBuilder, textures::MOBJ};
-use glam::f32::{Vec3, Quat, Mat4};
+use crate::dat::{
+    HSDStruct, HSDRootNode, VertexAttributes, PrimitiveType, MeshBuilder, MorphSet, MorphTarget,
+    textures::MOBJ,
+};
+use glam::f32::{Vec2, Vec3, Vec4, Quat, Mat4};
 
 #[derive(Clone, PartialEq, Eq, Hash)]
 pub struct JOBJ<'a> {
@@ -18,6 +21,13 @@ pub struct POBJ<'a> {
     pub hsd_struct: HSDStruct<'a>,
 }
 
+/// Blend targets of a POBJ with shape animation.
+/// Display list positions and normals index the shape set instead of the POBJ's attributes.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct ShapeSet<'a> {
+    pub hsd_struct: HSDStruct<'a>,
+}
+
 #[derive(Clone, Debug)]
 pub struct Attribute<'a> {
     pub name: AttributeName,
@@ -176,9 +186,6 @@ impl<'a> POBJ<'a> {
 
     pub fn get_attributes(&self) -> Vec<Attribute<'a>> {
         let attr_buf = self.hsd_struct.get_reference(0x08);
-        let shape_set = self.check_flag(POBJFlag::ShapeAnim);
-        assert!(!shape_set); // just a hopeful guess. check ToGXAttributes in HSD_POBJ
-    
         let count = attr_buf.len() / 0x18;
         let mut attributes = Vec::with_capacity(count);
         for i in 0..count {
@@ -198,6 +205,11 @@ impl<'a> POBJ<'a> {
         (flags & flag as u32) != 0
     }
 
+    pub fn get_shape_set(&self) -> Option<ShapeSet<'a>> {
+        if !self.check_flag(POBJFlag::ShapeAnim) { return None }
+        self.hsd_struct.try_get_reference(0x14).map(ShapeSet::new)
+    }
+
     pub fn envelope_weights<'b>(&'b self) -> Option<Box<[Envelope<'a>]>> {
         if !self.check_flag(POBJFlag::Envelope) { return None }
 
@@ -217,37 +229,46 @@ impl<'a> POBJ<'a> {
     }
 
     /// does not decode siblings.
+    ///
+    /// Returns the blend targets if the POBJ has a shape set.
+    /// Its `dobj_idx` and `pobj_idx` are left for the caller to set.
     pub fn decode_primitives<'b>(
         &'b self, 
         builder: &mut MeshBuilder,
         bone_jobjs: &[JOBJ<'a>],
-    ) {
+    ) -> Option<MorphSet> {
         let attributes = self.get_attributes();
 
+        // vertices of shape sets are kept separate, as they morph differently
+        let shape_set = self.get_shape_set();
+        let vertex_start = builder.vertex_attributes.len();
+        if shape_set.is_some() { builder.reuse_start = vertex_start; }
+        let mut shape_indices: Vec<(u32, u32)> = Vec::new();
+
         let buffer = self.hsd_struct.get_buffer(0x10);
         let envelope_weights = self.envelope_weights();
 
         let reader = crate::dat::Stream::new(buffer);
 
-        let mut primitive_indices: Vec<u16> = Vec::with_capacity(256);
+        let mut primitive_indices: Vec<u32> = Vec::with_capacity(256);
         let mut data: Vec<f32> = Vec::with_capacity(9);
 
         while !reader.finished() {
             let b = reader.read_byte();
             if b == 0 { break }
 
-            let primitive_type = PrimitiveType::from_u8(b).unwrap();
+            // unknown primitive types can't be skipped, as their length is unknown
+            let primitive_type = match PrimitiveType::from_u8(b) {
+                Some(primitive_type) => primitive_type,
+                None => break,
+            };
             let vert_len = reader.read_u16();
             primitive_indices.clear();
 
             // add vertices ------------------------------------------------
             for _ in 0..vert_len {
-                let mut pos = [0f32; 3];
-                let mut bones = [0u32; 6];
-                let mut weights = [0f32; 6];
-                let mut tex0 = [0f32; 2];
-                let mut normal = [0f32; 3];
-                let mut colour = [0f32; 4];
+                let mut v = VertexAttributes::default();
+                let mut shape_index = (0, 0);
 
                 for attr in attributes.iter() {
                     if attr.name == AttributeName::GX_VA_NULL {
@@ -258,11 +279,10 @@ impl<'a> POBJ<'a> {
                         // check GX_PrimitiveGroup.Read
                         AttributeType::GX_DIRECT => {
                             if attr.name == AttributeName::GX_VA_CLR0 {
-                                colour = read_direct_colour(&reader, attr.comp_type);
+                                v.colours[0] = read_direct_colour(&reader, attr.comp_type).into();
                                 continue;
                             } else if attr.name == AttributeName::GX_VA_CLR1 {
-                                eprintln!("unused GX_VA_CLR1 attribute");
-                                read_direct_colour(&reader, attr.comp_type);
+                                v.colours[1] = read_direct_colour(&reader, attr.comp_type).into();
                                 continue;
                             } else { 
                                 reader.read_byte() as usize
@@ -270,86 +290,112 @@ impl<'a> POBJ<'a> {
                         }
 
                         AttributeType::GX_INDEX8 => reader.read_byte() as usize,
-                        AttributeType::GX_INDEX16 => reader.read_i16() as usize,
-                        AttributeType::GX_NONE => todo!(), // unmatched - see GX_PrimitiveGroup:45
+                        AttributeType::GX_INDEX16 => reader.read_u16() as usize,
+
+                        // not present in the display list
+                        AttributeType::GX_NONE => continue,
                     };
 
+                    if let Some(ref shape_set) = shape_set {
+                        // HSD_ShapeSet
+                        if attr.name == AttributeName::GX_VA_POS {
+                            shape_index.0 = index as u32;
+                            v.pos = shape_set.position(0, index, &mut data).unwrap_or_default();
+                            continue;
+                        }
+
+                        if attr.name == AttributeName::GX_VA_NRM && shape_set.has_normals() {
+                            shape_index.1 = index as u32;
+                            v.normal = shape_set.normal(0, index, &mut data).unwrap_or_default();
+                            continue;
+                        }
+                    }
+
                     if attr.typ != AttributeType::GX_DIRECT {
                         attr.get_decoded_data_at(&mut data, index);
 
                         match attr.name {
                             AttributeName::GX_VA_POS => {
-                                // shapeset?? check GX_VertexAccessor:111
-
-                                pos[0] = data[0];
-                                pos[1] = data[1];
-                                pos[2] = data[2];
+                                v.pos = Vec3::new(data[0], data[1], data[2]);
                             },
-                            AttributeName::GX_VA_TEX0 => {
-                                tex0[0] = data[0];
-                                tex0[1] = data[1];
+                            AttributeName::GX_VA_TEX0 | AttributeName::GX_VA_TEX1
+                                | AttributeName::GX_VA_TEX2 | AttributeName::GX_VA_TEX3
+                                | AttributeName::GX_VA_TEX4 | AttributeName::GX_VA_TEX5
+                                | AttributeName::GX_VA_TEX6 | AttributeName::GX_VA_TEX7 => {
+                                let uv_idx = attr.name as usize - AttributeName::GX_VA_TEX0 as usize;
+                                v.uvs[uv_idx] = Vec2::new(data[0], data[1]);
                             },
                             AttributeName::GX_VA_NRM => {
-                                normal[0] = data[0];
-                                normal[1] = data[1];
-                                normal[2] = data[2];
+                                v.normal = Vec3::new(data[0], data[1], data[2]);
                             },
                             AttributeName::GX_VA_NBT => {
-                                normal[0] = data[0];
-                                normal[1] = data[1];
-                                normal[2] = data[2];
-                                // bitan + tan as well
-                            }
-                            AttributeName::GX_VA_CLR0 => {
-                                colour[0] = data[0];
-                                colour[1] = data[1];
-                                colour[2] = data[2];
-                                colour[3] = data[3];
+                                // normal, binormal, tangent
+                                v.normal = Vec3::new(data[0], data[1], data[2]);
+                                if data.len() >= 9 {
+                                    v.binormal = Vec3::new(data[3], data[4], data[5]);
+                                    v.tangent = Vec3::new(data[6], data[7], data[8]);
+                                }
                             }
+                            AttributeName::GX_VA_CLR0 => v.colours[0] = Vec4::new(data[0], data[1], data[2], data[3]),
+                            AttributeName::GX_VA_CLR1 => v.colours[1] = Vec4::new(data[0], data[1], data[2], data[3]),
                             _ => (), // TODO
                         }
                     } else {
-                        #[allow(clippy::single_match)]
                         match attr.name {
                             // SBHsdMesh.cs:277 (GXVertexToHsdVertex)
-                            AttributeName::GX_VA_PNMTXIDX => if let Some(ref env) = envelope_weights {
-                                let jobjweight = &env[index / 3];
-                                weights = jobjweight.weights();
-
-                                for (i, jobj) in jobjweight.jobjs().enumerate() {
-                                    let jobj_data_ptr = jobj.hsd_struct.data.as_ptr();
-                                    for (j, bone_jobj) in bone_jobjs.iter().enumerate() {
-                                        if bone_jobj.hsd_struct.data.as_ptr() == jobj_data_ptr {
-                                            bones[i] = j as u32;
-                                            break;
+                            AttributeName::GX_VA_PNMTXIDX => {
+                                v.pnmtx_idx = index as u8;
+
+                                if let Some(ref env) = envelope_weights {
+                                    let jobjweight = &env[index / 3];
+                                    v.weights = jobjweight.weights();
+
+                                    for (i, jobj) in jobjweight.jobjs().enumerate() {
+                                        let jobj_data_ptr = jobj.hsd_struct.data.as_ptr();
+                                        for (j, bone_jobj) in bone_jobjs.iter().enumerate() {
+                                            if bone_jobj.hsd_struct.data.as_ptr() == jobj_data_ptr {
+                                                v.bones[i] = j as u32;
+                                                break;
+                                            }
                                         }
                                     }
                                 }
                             }
+                            AttributeName::GX_VA_TEX0MTXIDX | AttributeName::GX_VA_TEX1MTXIDX
+                                | AttributeName::GX_VA_TEX2MTXIDX | AttributeName::GX_VA_TEX3MTXIDX
+                                | AttributeName::GX_VA_TEX4MTXIDX | AttributeName::GX_VA_TEX5MTXIDX
+                                | AttributeName::GX_VA_TEX6MTXIDX | AttributeName::GX_VA_TEX7MTXIDX => {
+                                let mtx_idx = attr.name as usize - AttributeName::GX_VA_TEX0MTXIDX as usize;
+                                v.tex_mtx_idx[mtx_idx] = index as u8;
+                            }
                             _ => (), // TODO
                         }
-                        // TODO
                     }
                 }
 
-                let vertex = Vertex::from_parts(pos, tex0, normal, weights, bones, colour);
-                let cache_start = builder.vertices.len().saturating_sub(32);
-                if let Some(v_i) = builder.vertices[cache_start..].iter().copied().position(|v| v == vertex) {
-                    primitive_indices.push((cache_start + v_i) as u16);
+                let cache_start = builder.vertex_attributes.len().saturating_sub(32).max(builder.reuse_start);
+
+                let cached = builder.vertex_attributes[cache_start..].iter().enumerate().position(|(i, attr)| {
+                    *attr == v && (shape_set.is_none() || shape_indices[cache_start + i - vertex_start] == shape_index)
+                });
+
+                if let Some(v_i) = cached {
+                    primitive_indices.push((cache_start + v_i) as u32);
                 } else {
-                    primitive_indices.push(builder.vertices.len() as u16);
-                    builder.vertices.push(vertex);
+                    primitive_indices.push(builder.vertex_attributes.len() as u32);
+                    builder.vertex_attributes.push(v);
+                    if shape_set.is_some() { shape_indices.push(shape_index); }
                 }
             }
 
             // add primitives ---------------------------------------------------
-            // we convert everything into indexed triangles
+            // we convert everything into indexed triangles, lines and points
             match primitive_type {
                 PrimitiveType::Triangles => {
                     builder.indices.extend_from_slice(&primitive_indices);
                 }
                 PrimitiveType::TriangleStrip => {
-                    if vert_len != 0 {
+                    if vert_len >= 3 {
                         let mut idx_iter = 0..(vert_len as usize-2);
 
                         // alternate triangle direction
@@ -380,6 +426,13 @@ impl<'a> POBJ<'a> {
                         }
                     }
                 }
+                PrimitiveType::TriangleFan => {
+                    for i in 1..(vert_len as usize).saturating_sub(1) {
+                        builder.indices.push(primitive_indices[0]);
+                        builder.indices.push(primitive_indices[i]);
+                        builder.indices.push(primitive_indices[i+1]);
+                    }
+                }
                 PrimitiveType::Quads => {
                     for i in (0..vert_len as usize).step_by(4) {
                         let idx_0 = primitive_indices[i+0];
@@ -395,8 +448,109 @@ impl<'a> POBJ<'a> {
                         builder.indices.push(idx_0);
                     }
                 }
+                PrimitiveType::Lines => {
+                    let len = vert_len as usize & !1;
+                    builder.line_indices.extend_from_slice(&primitive_indices[..len]);
+                }
+                PrimitiveType::LineStrip => {
+                    for pair in primitive_indices.windows(2) {
+                        builder.line_indices.extend_from_slice(pair);
+                    }
+                }
+                PrimitiveType::Points => {
+                    builder.point_indices.extend_from_slice(&primitive_indices);
+                }
             }
         }
+
+        let shape_set = shape_set?;
+        builder.reuse_start = builder.vertex_attributes.len();
+        let vertices = &builder.vertex_attributes[vertex_start..];
+        let targets = (0..shape_set.shape_count()).map(|shape| MorphTarget {
+            positions: shape_indices.iter().zip(vertices.iter())
+                .map(|(&(i, _), v)| shape_set.position(shape, i as usize, &mut data).unwrap_or(v.pos))
+                .collect(),
+            normals: shape_indices.iter().zip(vertices.iter())
+                .map(|(&(_, i), v)| shape_set.normal(shape, i as usize, &mut data).unwrap_or(v.normal))
+                .collect(),
+        }).collect();
+
+        let additive = self.check_flag(POBJFlag::ShapeSetAdditive);
+        Some(MorphSet {
+            dobj_idx: 0,
+            pobj_idx: 0,
+            vertex_start: vertex_start as u32,
+            vertex_len: vertices.len() as u32,
+            targets,
+            default_blend: shape_set.blend(additive),
+            additive,
+        })
+    }
+}
+
+// HSD_ShapeSet:
+// 0x00 flags, 0x02 shape count,
+// 0x04 vertex index count, 0x08 vertex attribute, 0x0C vertex index list for each shape,
+// 0x10 normal index count, 0x14 normal attribute, 0x18 normal index list for each shape,
+// 0x1C blend value, or a pointer to a value for each shape if additive
+impl<'a> ShapeSet<'a> {
+    pub fn new(hsd_struct: HSDStruct<'a>) -> Self {
+        Self { hsd_struct }
+    }
+
+    pub fn shape_count(&self) -> usize {
+        self.hsd_struct.get_u16(0x02) as usize
+    }
+
+    pub fn has_normals(&self) -> bool {
+        self.hsd_struct.try_get_reference(0x14).is_some() && self.hsd_struct.try_get_reference(0x18).is_some()
+    }
+
+    /// Position of a display list vertex in the shape.
+    pub fn position(&self, shape: usize, index: usize, data: &mut Vec<f32>) -> Option<Vec3> {
+        self.decode(0x04, shape, index, data)
+    }
+
+    pub fn normal(&self, shape: usize, index: usize, data: &mut Vec<f32>) -> Option<Vec3> {
+        self.decode(0x10, shape, index, data)
+    }
+
+    /// Blend values when not animated. See `MorphSet::blend_weights`.
+    pub fn blend(&self, additive: bool) -> Box<[f32]> {
+        if additive {
+            let values = self.hsd_struct.try_get_buffer(0x1C).unwrap_or(&[]);
+            values.chunks_exact(4)
+                .take(self.shape_count())
+                .map(|b| f32::from_be_bytes(b.try_into().unwrap()))
+                .collect()
+        } else {
+            Box::new([self.hsd_struct.get_f32(0x1C)])
+        }
+    }
+
+    // count_offset is followed by the attribute and index lists
+    fn decode(&self, count_offset: usize, shape: usize, index: usize, data: &mut Vec<f32>) -> Option<Vec3> {
+        let count = self.hsd_struct.get_i32(count_offset).max(0) as usize;
+        if index >= count || shape >= self.shape_count() { return None }
+
+        let attr = Attribute::new(self.hsd_struct.try_get_reference(count_offset + 0x04)?);
+        let index_lists = self.hsd_struct.try_get_reference(count_offset + 0x08)?;
+        let indices = index_lists.try_get_reference(shape * 4)?;
+
+        let data_index = match attr.typ {
+            AttributeType::GX_INDEX8 => *indices.data.get(index)? as usize,
+            _ => {
+                let bytes = indices.data.get(index * 2..index * 2 + 2)?;
+                u16::from_be_bytes([bytes[0], bytes[1]]) as usize
+            }
+        };
+
+        let stride = attr.hsd_struct.get_i16(0x12) as usize;
+        let buffer = attr.hsd_struct.try_get_reference(0x14)?;
+        if stride == 0 || (data_index + 1) * stride > buffer.len() { return None }
+
+        attr.get_decoded_data_at(data, data_index);
+        (data.len() >= 3).then(|| Vec3::new(data[0], data[1], data[2]))
     }
 }
 
@@ -541,6 +695,43 @@ impl<'a> JOBJ<'a> {
         self.hsd_struct.try_get_reference(0x08).map(JOBJ::new)
     }
 
+    /// DOBJs of self, its siblings and their descendants.
+    /// Same order as the DOBJ indices in `extract_model_from_jobj`.
+    pub fn get_all_dobjs<'b>(&'b self) -> Vec<DOBJ<'a>> {
+        fn add_dobjs<'a>(jobj: JOBJ<'a>, dobjs: &mut Vec<DOBJ<'a>>) {
+            if let Some(dobj) = jobj.get_dobj() {
+                dobjs.extend(dobj.siblings());
+            }
+
+            for child in jobj.children() {
+                add_dobjs(child, dobjs);
+            }
+        }
+
+        let mut dobjs = Vec::new();
+        for jobj in self.siblings() {
+            add_dobjs(jobj, &mut dobjs);
+        }
+        dobjs
+    }
+
+    /// Self, its siblings and their descendants.
+    /// Same order as the bones in `extract_model_from_jobj`.
+    pub fn get_all_bones<'b>(&'b self) -> Vec<JOBJ<'a>> {
+        fn add_bones<'a>(jobj: JOBJ<'a>, bones: &mut Vec<JOBJ<'a>>) {
+            bones.push(jobj.clone());
+            for child in jobj.children() {
+                add_bones(child, bones);
+            }
+        }
+
+        let mut bones = Vec::new();
+        for jobj in self.siblings() {
+            add_bones(jobj, &mut bones);
+        }
+        bones
+    }
+
     pub fn get_all_jobjs<'b>(&'b self) -> Vec<JOBJ<'a>> {
         let mut jobjs = Vec::new();
         self.add_jobjs(&mut jobjs);
@@ -0,0 +1,136 @@
+use crate::dat::{HSDRawFile, HSDStruct};
+use ahash::{HashMap, HashMapExt};
+
+/// Resolves the external references of dat files to the roots of other dat files.
+///
+/// A dat file can import symbols it does not define itself (`HSDRawFile::references`).
+/// Add the files that define them, then link the file that uses them.
+/// After linking, `HSDStruct::get_reference` follows the imported pointers like any other.
+#[derive(Debug, Clone, Default)]
+pub struct DatLinker<'a> {
+    pub symbols: HashMap<&'a str, HSDStruct<'a>>,
+}
+
+impl<'a> DatLinker<'a> {
+    pub fn new() -> Self {
+        Self { symbols: HashMap::new() }
+    }
+
+    /// Makes every root of the file available to `link`.
+    /// If a symbol is defined twice, the first definition is kept.
+    pub fn add_file(&mut self, file: &HSDRawFile<'a>) {
+        for root in file.roots.iter() {
+            self.symbols.entry(root.root_string).or_insert_with(|| root.hsd_struct.clone());
+        }
+    }
+
+    pub fn symbol(&self, name: &str) -> Option<HSDStruct<'a>> {
+        self.symbols.get(name).cloned()
+    }
+
+    /// Binds the references of the file to the added roots.
+    /// Returns the reference symbols that could not be resolved.
+    ///
+    /// The link is set on every struct that holds the pointer field.
+    ///
+    /// `HSDRawFile::open` starts a new struct at every reference location (same as HSDRaw),
+    /// so the struct that owns the field ends right before it. That struct gets the link at `offset == len()`, the field's own offset,
+    /// otherwise it could not follow the pointer at all. The struct starting at the location
+    /// gets the link at 0. The owner's fields past the split are only in the following struct.
+    pub fn link(&self, file: &HSDRawFile<'a>) -> Vec<&'a str> {
+        let mut unresolved = Vec::new();
+
+        let mut structs: Vec<(usize, HSDStruct<'a>)> = file.struct_cache_to_offset.iter()
+            .map(|(s, &offset)| (offset, s.clone()))
+            .collect();
+        structs.sort_unstable_by_key(|(offset, _)| *offset);
+        let max_len = structs.iter().map(|(_, s)| s.len()).max().unwrap_or(0);
+
+        for (reference, locations) in file.references.iter().zip(file.reference_locations.iter()) {
+            let target = match self.symbols.get(reference.root_string) {
+                Some(target) => target.clone(),
+                None => {
+                    unresolved.push(reference.root_string);
+                    continue;
+                }
+            };
+
+            for &location in locations.iter() {
+                // HSDRawFile::open splits a struct at every reference location (same as HSDRaw),
+                // and extends a struct over an orphaned subaction that follows it,
+                // so the pointer field can be in more than one struct.
+                let mut i = structs.partition_point(|(offset, _)| *offset <= location);
+                while i > 0 {
+                    let (start, s) = &structs[i - 1];
+                    if start + max_len < location { break }
+
+                    // end == location: the owner, cut off at the field, see above
+                    let end = start + s.len();
+                    if end >= location + 4 || (end == location && *start < location) {
+                        s.set_reference_struct(location - start, target.clone());
+                    }
+                    i -= 1;
+                }
+            }
+        }
+
+        unresolved
+    }
+}
+
+/// Links every file against the roots of every other file.
+/// Returns the reference symbols that could not be resolved.
+pub fn link_files<'a>(files: &[HSDRawFile<'a>]) -> Vec<&'a str> {
+    let mut linker = DatLinker::new();
+    for file in files.iter() {
+        linker.add_file(file);
+    }
+
+    files.iter()
+        .flat_map(|file| linker.link(file))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dat::test_dat::{build_dat, write_u32};
+
+    #[test]
+    fn links_external_symbols() {
+        let mut data = vec![0u8; 0x08];
+        write_u32(&mut data, 0x00, 0x12345678);
+        let defining_dat = build_dat(&data, &[], &[(0x00, "symbol")], &[]);
+
+        // root @0x00 imports "symbol" at 0x08 and 0x18, chained through the first field.
+        // The chain splits the root at 0x18. "missing" is imported at 0x24.
+        let mut data = vec![0u8; 0x30];
+        write_u32(&mut data, 0x08, 0x18);
+        let importing_dat = build_dat(&data, &[], &[(0x00, "root")], &[(0x08, "symbol"), (0x24, "missing")]);
+
+        let defining = HSDRawFile::new(&defining_dat);
+        let importing = HSDRawFile::new(&importing_dat);
+
+        let mut linker = DatLinker::new();
+        linker.add_file(&defining);
+        assert_eq!(linker.link(&importing), vec!["missing"]);
+
+        let struct_at = |offset: usize| importing.struct_cache_to_offset.iter()
+            .find(|&(_, &o)| o == offset + 0x20)
+            .map(|(s, _)| s.clone())
+            .unwrap();
+
+        // cut off at the first field, which it can still follow
+        let root = &importing.roots[0].hsd_struct;
+        assert_eq!(root.len(), 0x08);
+        assert_eq!(root.get_reference(0x08).get_u32(0x00), 0x12345678);
+
+        for (offset, len) in [(0x08, 0x10), (0x18, 0x0C)] {
+            let s = struct_at(offset);
+            assert_eq!(s.len(), len);
+            assert_eq!(s.get_reference(0x00).get_u32(0x00), 0x12345678);
+        }
+        assert_eq!(struct_at(0x08).get_reference(0x10).get_u32(0x00), 0x12345678);
+        assert!(struct_at(0x24).try_get_reference(0x00).is_none());
+    }
+}
@@ -0,0 +1,345 @@
+use crate::dat::{HSDStruct, MOBJ, TOBJ, Phong, RenderModeFlags, render_mode_flags};
+
+/// Everything needed to draw a primitive group apart from its textures.
+/// Texture stages, including their TEV config, are in `Model::texture_stages`.
+#[derive(Copy, Clone, Debug)]
+pub struct Material {
+    pub render_mode: RenderModeFlags,
+    pub phong: Phong,
+    pub alpha: f32,
+    pub shininess: f32,
+
+    /// None if the MOBJ has no PEDesc. The default pixel engine state is then chosen by the render mode.
+    pub pe: Option<PEDesc>,
+}
+
+impl Default for Material {
+    fn default() -> Self {
+        Material {
+            render_mode: 0,
+            phong: Phong::default(),
+            alpha: 1.0,
+            shininess: 50.0,
+            pe: None,
+        }
+    }
+}
+
+impl Material {
+    pub fn is_translucent(&self) -> bool {
+        match self.pe {
+            Some(pe) => pe.blend_mode != blend_mode::NONE,
+            None => self.render_mode & render_mode_flags::XLU != 0,
+        }
+    }
+}
+
+// HSD_PEDesc.cs
+#[derive(Copy, Clone, Debug)]
+pub struct PEDesc {
+    pub flags: PixelProcessFlags,
+    pub alpha_ref_0: u8,
+    pub alpha_ref_1: u8,
+    pub destination_alpha: u8,
+    pub blend_mode: BlendMode,
+    pub src_factor: BlendFactor,
+    pub dst_factor: BlendFactor,
+    pub logic_op: LogicOp,
+    pub depth_function: CompareType,
+    pub alpha_compare_0: CompareType,
+    pub alpha_op: AlphaOp,
+    pub alpha_compare_1: CompareType,
+}
+
+impl PEDesc {
+    pub fn from_hsd_struct(hsd_struct: &HSDStruct) -> Self {
+        PEDesc {
+            flags: hsd_struct.get_u8(0x00),
+            alpha_ref_0: hsd_struct.get_u8(0x01),
+            alpha_ref_1: hsd_struct.get_u8(0x02),
+            destination_alpha: hsd_struct.get_u8(0x03),
+            blend_mode: hsd_struct.get_u8(0x04),
+            src_factor: hsd_struct.get_u8(0x05),
+            dst_factor: hsd_struct.get_u8(0x06),
+            logic_op: hsd_struct.get_u8(0x07),
+            depth_function: hsd_struct.get_u8(0x08),
+            alpha_compare_0: hsd_struct.get_u8(0x09),
+            alpha_op: hsd_struct.get_u8(0x0A),
+            alpha_compare_1: hsd_struct.get_u8(0x0B),
+        }
+    }
+
+    pub fn alpha_test_enabled(&self) -> bool {
+        self.flags & pixel_process_flags::COMPARE != 0
+    }
+
+    pub fn depth_update_enabled(&self) -> bool {
+        self.flags & pixel_process_flags::ZUPDATE != 0
+    }
+
+    /// Same as GX_SetAlphaCompare.
+    pub fn alpha_test(&self, alpha: u8) -> bool {
+        let a = compare(self.alpha_compare_0, alpha, self.alpha_ref_0);
+        let b = compare(self.alpha_compare_1, alpha, self.alpha_ref_1);
+        match self.alpha_op {
+            alpha_op::AND => a && b,
+            alpha_op::OR => a || b,
+            alpha_op::XOR => a != b,
+            _ => a == b,
+        }
+    }
+}
+
+fn compare(compare: CompareType, a: u8, b: u8) -> bool {
+    match compare {
+        compare_type::NEVER => false,
+        compare_type::LESS => a < b,
+        compare_type::EQUAL => a == b,
+        compare_type::LESS_OR_EQUAL => a <= b,
+        compare_type::GREATER => a > b,
+        compare_type::NOT_EQUAL => a != b,
+        compare_type::GREATER_OR_EQUAL => a >= b,
+        _ => true,
+    }
+}
+
+// HSD_TOBJ.cs (HSD_TOBJ_TEV)
+#[derive(Copy, Clone, Debug)]
+pub struct TextureTEV {
+    pub colour_op: TevColourOp,
+    pub alpha_op: TevAlphaOp,
+    pub colour_bias: TevBias,
+    pub alpha_bias: TevBias,
+    pub colour_scale: TevScale,
+    pub alpha_scale: TevScale,
+    pub colour_clamp: bool,
+    pub alpha_clamp: bool,
+
+    /// TEV inputs a, b, c and d
+    pub colour_in: [u8; 4],
+    pub alpha_in: [u8; 4],
+
+    pub konst: [u8; 4],
+    pub tev0: [u8; 4],
+    pub tev1: [u8; 4],
+    pub active: TEVActive,
+}
+
+impl TextureTEV {
+    pub fn from_hsd_struct(hsd_struct: &HSDStruct) -> Self {
+        let rgba = |offset: usize| [
+            hsd_struct.get_u8(offset),
+            hsd_struct.get_u8(offset + 1),
+            hsd_struct.get_u8(offset + 2),
+            hsd_struct.get_u8(offset + 3),
+        ];
+
+        TextureTEV {
+            colour_op: hsd_struct.get_u8(0x00),
+            alpha_op: hsd_struct.get_u8(0x01),
+            colour_bias: hsd_struct.get_u8(0x02),
+            alpha_bias: hsd_struct.get_u8(0x03),
+            colour_scale: hsd_struct.get_u8(0x04),
+            alpha_scale: hsd_struct.get_u8(0x05),
+            colour_clamp: hsd_struct.get_u8(0x06) != 0,
+            alpha_clamp: hsd_struct.get_u8(0x07) != 0,
+            colour_in: rgba(0x08),
+            alpha_in: rgba(0x0C),
+            konst: rgba(0x10),
+            tev0: rgba(0x14),
+            tev1: rgba(0x18),
+            active: hsd_struct.get_u32(0x1C),
+        }
+    }
+}
+
+impl<'a> MOBJ<'a> {
+    // HSD_MOBJ.cs
+    pub fn get_pe_desc(&self) -> Option<PEDesc> {
+        self.hsd_struct.try_get_reference(0x14)
+            .map(|pe| PEDesc::from_hsd_struct(&pe))
+    }
+
+    pub fn material(&self) -> Material {
+        let mut material = Material {
+            render_mode: self.flags(),
+            phong: self.get_phong(),
+            pe: self.get_pe_desc(),
+            ..Material::default()
+        };
+
+        if let Some(mat) = self.get_material() {
+            material.alpha = mat.get_f32(0x0C);
+            material.shininess = mat.get_f32(0x10);
+        }
+
+        material
+    }
+}
+
+impl<'a> TOBJ<'a> {
+    pub fn tev(&self) -> Option<TextureTEV> {
+        self.hsd_struct.try_get_reference(0x58)
+            .map(|tev| TextureTEV::from_hsd_struct(&tev))
+    }
+}
+
+// GX/Enums.cs (GXBlendMode)
+pub type BlendMode = u8;
+pub mod blend_mode {
+    use super::BlendMode;
+    pub const NONE     : BlendMode = 0;
+    pub const BLEND    : BlendMode = 1;
+    pub const LOGIC    : BlendMode = 2;
+    pub const SUBTRACT : BlendMode = 3;
+}
+
+// GX/Enums.cs (GXLogicOp)
+pub type LogicOp = u8;
+pub mod logic_op {
+    use super::LogicOp;
+    pub const CLEAR   : LogicOp = 0;
+    pub const AND     : LogicOp = 1;
+    pub const REVAND  : LogicOp = 2;
+    pub const COPY    : LogicOp = 3;
+    pub const INVAND  : LogicOp = 4;
+    pub const NOOP    : LogicOp = 5;
+    pub const XOR     : LogicOp = 6;
+    pub const OR      : LogicOp = 7;
+    pub const NOR     : LogicOp = 8;
+    pub const EQUIV   : LogicOp = 9;
+    pub const INV     : LogicOp = 10;
+    pub const REVOR   : LogicOp = 11;
+    pub const INVCOPY : LogicOp = 12;
+    pub const INVOR   : LogicOp = 13;
+    pub const NAND    : LogicOp = 14;
+    pub const SET     : LogicOp = 15;
+}
+
+// GX/Enums.cs (GXCompareType)
+pub type CompareType = u8;
+pub mod compare_type {
+    use super::CompareType;
+    pub const NEVER            : CompareType = 0;
+    pub const LESS             : CompareType = 1;
+    pub const EQUAL            : CompareType = 2;
+    pub const LESS_OR_EQUAL    : CompareType = 3;
+    pub const GREATER          : CompareType = 4;
+    pub const NOT_EQUAL        : CompareType = 5;
+    pub const GREATER_OR_EQUAL : CompareType = 6;
+    pub const ALWAYS           : CompareType = 7;
+}
+
+// GX/Enums.cs (GXAlphaOp)
+pub type AlphaOp = u8;
+pub mod alpha_op {
+    use super::AlphaOp;
+    pub const AND  : AlphaOp = 0;
+    pub const OR   : AlphaOp = 1;
+    pub const XOR  : AlphaOp = 2;
+    pub const XNOR : AlphaOp = 3;
+}
+
+// GX/Enums.cs (GXBlendFactor)
+pub type BlendFactor = u8;
+pub mod blend_factor {
+    use super::BlendFactor;
+    pub const ZERO        : BlendFactor = 0;
+    pub const ONE         : BlendFactor = 1;
+    pub const SRCCLR      : BlendFactor = 2;
+    pub const INVSRCCLR   : BlendFactor = 3;
+    pub const SRCALPHA    : BlendFactor = 4;
+    pub const INVSRCALPHA : BlendFactor = 5;
+    pub const DSTALPHA    : BlendFactor = 6;
+    pub const INVDSTALPHA : BlendFactor = 7;
+
+    pub const DSTCLR      : BlendFactor = SRCCLR;
+    pub const INVDSTCLR   : BlendFactor = INVSRCCLR;
+}
+
+// HSD_PEDesc.cs (PIXEL_PROCESS_ENABLE)
+pub type PixelProcessFlags = u8;
+pub mod pixel_process_flags {
+    use super::PixelProcessFlags;
+    pub const COLOR_UPDATE : PixelProcessFlags = 1 << 0;
+    pub const ALPHA_UPDATE : PixelProcessFlags = 1 << 1;
+    pub const DST_ALPHA    : PixelProcessFlags = 1 << 2;
+    pub const BEFORE_TEX   : PixelProcessFlags = 1 << 3;
+    pub const COMPARE      : PixelProcessFlags = 1 << 4;
+    pub const ZUPDATE      : PixelProcessFlags = 1 << 5;
+    pub const DITHER       : PixelProcessFlags = 1 << 6;
+}
+
+// GX/Enums.cs (TevColorOp)
+pub type TevColourOp = u8;
+pub mod tev_colour_op {
+    use super::TevColourOp;
+    pub const ADD           : TevColourOp = 0;
+    pub const SUB           : TevColourOp = 1;
+    pub const COMP_R8_GT    : TevColourOp = 8;
+    pub const COMP_R8_EQ    : TevColourOp = 9;
+    pub const COMP_GR16_GT  : TevColourOp = 10;
+    pub const COMP_GR16_EQ  : TevColourOp = 11;
+    pub const COMP_BGR24_GT : TevColourOp = 12;
+    pub const COMP_BGR24_EQ : TevColourOp = 13;
+    pub const COMP_RGB8_GT  : TevColourOp = 14;
+    pub const COMP_RGB8_EQ  : TevColourOp = 15;
+}
+
+// GX/Enums.cs (TevAlphaOp)
+pub type TevAlphaOp = u8;
+pub mod tev_alpha_op {
+    use super::TevAlphaOp;
+    pub const ADD           : TevAlphaOp = 0;
+    pub const SUB           : TevAlphaOp = 1;
+    pub const COMP_R8_GT    : TevAlphaOp = 8;
+    pub const COMP_R8_EQ    : TevAlphaOp = 9;
+    pub const COMP_GR16_GT  : TevAlphaOp = 10;
+    pub const COMP_GR16_EQ  : TevAlphaOp = 11;
+    pub const COMP_BGR24_GT : TevAlphaOp = 12;
+    pub const COMP_BGR24_EQ : TevAlphaOp = 13;
+    pub const COMP_A8_GT    : TevAlphaOp = 14;
+    pub const COMP_A8_EQ    : TevAlphaOp = 15;
+}
+
+// GX/Enums.cs (TevBias)
+pub type TevBias = u8;
+pub mod tev_bias {
+    use super::TevBias;
+    pub const ZERO    : TevBias = 0;
+    pub const ADDHALF : TevBias = 1;
+    pub const SUBHALF : TevBias = 2;
+}
+
+// GX/Enums.cs (TevScale)
+pub type TevScale = u8;
+pub mod tev_scale {
+    use super::TevScale;
+    pub const SCALE_1  : TevScale = 0;
+    pub const SCALE_2  : TevScale = 1;
+    pub const SCALE_4  : TevScale = 2;
+    pub const DIVIDE_2 : TevScale = 3;
+}
+
+// HSD_TOBJ.cs (TOBJ_TEVREG_ACTIVE)
+pub type TEVActive = u32;
+pub mod tev_active {
+    use super::TEVActive;
+    pub const KONST_R   : TEVActive = 1 << 0;
+    pub const KONST_G   : TEVActive = 1 << 1;
+    pub const KONST_B   : TEVActive = 1 << 2;
+    pub const KONST_A   : TEVActive = 1 << 3;
+    pub const KONST     : TEVActive = KONST_R | KONST_G | KONST_B | KONST_A;
+    pub const TEV0_R    : TEVActive = 1 << 4;
+    pub const TEV0_G    : TEVActive = 1 << 5;
+    pub const TEV0_B    : TEVActive = 1 << 6;
+    pub const TEV0_A    : TEVActive = 1 << 7;
+    pub const TEV0      : TEVActive = TEV0_R | TEV0_G | TEV0_B | TEV0_A;
+    pub const TEV1_R    : TEVActive = 1 << 8;
+    pub const TEV1_G    : TEVActive = 1 << 9;
+    pub const TEV1_B    : TEVActive = 1 << 10;
+    pub const TEV1_A    : TEVActive = 1 << 11;
+    pub const TEV1      : TEVActive = TEV1_R | TEV1_G | TEV1_B | TEV1_A;
+    pub const COLOR_TEV : TEVActive = 1 << 30;
+    pub const ALPHA_TEV : TEVActive = 1 << 31;
+}
@@ -7,9 +7,21 @@ pub use jobj::{DOBJ, JOBJ};
 mod extract_mesh;
 pub use extract_mesh::*;
 
+mod vertex_layout;
+pub use vertex_layout::*;
+
 mod extract_anims;
 pub use extract_anims::*;
 
+mod skinning;
+pub use skinning::*;
+
+mod bounds;
+pub use bounds::*;
+
+mod stage_collision;
+pub use stage_collision::*;
+
 mod extract_effects;
 pub use extract_effects::*;
 
@@ -19,6 +31,47 @@ pub use fighter_data::*;
 mod textures;
 pub use textures::*;
 
+mod material;
+pub use material::*;
+
+mod encode_textures;
+pub use encode_textures::*;
+
+mod indexed_image;
+pub use indexed_image::*;
+
+mod replace_textures;
+pub use replace_textures::*;
+
+mod dolphin_textures;
+pub use dolphin_textures::*;
+
+mod texture_store;
+pub use texture_store::*;
+
+mod linker;
+pub use linker::*;
+
+mod archive;
+pub use archive::*;
+
+mod patcher;
+pub use patcher::*;
+
+mod import_mesh;
+pub use import_mesh::*;
+
+#[cfg(test)]
+pub(crate) mod test_dat;
+
+#[cfg(feature = "png")]
+mod png;
+
+#[cfg(feature = "gltf")]
+mod gltf;
+#[cfg(feature = "gltf")]
+pub use gltf::*;
+
 use ahash::{HashMap, HashSet, HashMapExt, HashSetExt};
 use std::rc::Rc;
 
@@ -38,6 +91,75 @@ impl DatFile {
     pub fn stream(&self) -> Stream {
         Stream::new(&self.data)
     }
+
+    /// Checks that the header's tables fit in the file and that relocations,
+    /// roots and reference chains all point into the data section.
+    /// `HSDRawFile::new` can panic on files that fail this, such as files that are not dat files.
+    pub fn check_header(&self) -> Result<(), DatExtractError> {
+        let data: &[u8] = &self.data;
+        if data.len() < 0x20 { return Err(DatExtractError::InvalidDatFile) }
+
+        let word = |i: usize| -> Result<u32, DatExtractError> {
+            let bytes = data.get(i..i.wrapping_add(4)).ok_or(DatExtractError::InvalidDatFile)?;
+            Ok(u32::from_be_bytes(bytes.try_into().unwrap()))
+        };
+
+        let data_size = word(0x04)? as usize;
+        let reloc_count = word(0x08)? as usize;
+        let root_count = word(0x0C)? as usize;
+        let ref_count = word(0x10)? as usize;
+        if std::str::from_utf8(&data[0x14..0x18]).is_err() { return Err(DatExtractError::InvalidDatFile) }
+
+        // offsets in the file, not the data section
+        let reloc_offset = 0x20usize.checked_add(data_size)
+            .filter(|&o| o <= data.len() && data_size.is_multiple_of(4))
+            .ok_or(DatExtractError::InvalidDatFile)?;
+        let table_start = reloc_count.checked_mul(4)
+            .and_then(|size| reloc_offset.checked_add(size))
+            .ok_or(DatExtractError::InvalidDatFile)?;
+        let string_start = root_count.checked_add(ref_count)
+            .and_then(|count| count.checked_mul(8))
+            .and_then(|size| table_start.checked_add(size))
+            .filter(|&o| o <= data.len())
+            .ok_or(DatExtractError::InvalidDatFile)?;
+
+        let in_data = |offset: usize| offset.is_multiple_of(4) && offset + 4 <= data_size;
+
+        for i in 0..reloc_count {
+            let location = word(reloc_offset + i * 4)? as usize;
+            if !in_data(location) { return Err(DatExtractError::InvalidDatFile) }
+
+            // negative pointers are skipped
+            let target = word(0x20 + location)? as i32;
+            if target > data_size as i32 { return Err(DatExtractError::InvalidDatFile) }
+        }
+
+        for i in 0..root_count + ref_count {
+            let offset = word(table_start + i * 8)? as usize;
+            let string_offset = word(table_start + i * 8 + 4)? as usize;
+            if offset >= data_size { return Err(DatExtractError::InvalidDatFile) }
+            data.get(string_start.saturating_add(string_offset)..)
+                .and_then(crate::parse_string)
+                .ok_or(DatExtractError::InvalidDatFile)?;
+
+            // reference chains end with 0 or -1, and can't have more links than words
+            if i >= root_count {
+                let mut location = offset;
+                let mut links = 0;
+                loop {
+                    if !in_data(location) || links > data_size / 4 { return Err(DatExtractError::InvalidDatFile) }
+                    match word(0x20 + location)? as i32 {
+                        0 | -1 => break,
+                        next if next < 0 => return Err(DatExtractError::InvalidDatFile),
+                        next => location = next as usize,
+                    }
+                    links += 1;
+                }
+            }
+        }
+
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -54,6 +176,10 @@ pub struct HSDRawFile<'a> {
     pub roots: Vec<HSDRootNode<'a>>,
     pub references: Vec<HSDRootNode<'a>>,
 
+    /// File offsets of every location that uses a reference symbol.
+    /// Indexed the same as `references`. Used by `DatLinker`.
+    pub reference_locations: Vec<Box<[usize]>>,
+
     pub struct_cache: Vec<HSDStruct<'a>>,
     pub struct_cache_to_offset: HashMap<HSDStruct<'a>, usize>,
 }
@@ -63,6 +189,12 @@ impl<'a> HSDRawFile<'a> {
         Self::open(Stream::new(&r.data))
     }
 
+    /// Same as `new`, but returns an error instead of panicking if the header fails `DatFile::check_header`.
+    pub fn try_new(r: &'a DatFile) -> Result<Self, DatExtractError> {
+        r.check_header()?;
+        Ok(Self::new(r))
+    }
+
     /// I have no idea what is happening here.
     /// This is straight up copied from HSDRaw.
     /// It works and I do not want to ever look at this again.
@@ -121,6 +253,7 @@ impl<'a> HSDRawFile<'a> {
         let mut root_strings: Vec<&'a str> = Vec::with_capacity(2);
         let mut ref_offsets: Vec<usize> = Vec::new();
         let mut ref_strings: Vec<&'a str> = Vec::new();
+        let mut reference_locations: Vec<Box<[usize]>> = Vec::new();
         let string_start = r.cursor() + (ref_count + root_count) * 8;
 
         for _ in 0..root_count {
@@ -141,6 +274,7 @@ impl<'a> HSDRawFile<'a> {
 
             let temp = r.cursor();
             let mut special = refp;
+            let mut locations = vec![refp];
 
             loop {
                 r.seek(special);
@@ -157,6 +291,7 @@ impl<'a> HSDRawFile<'a> {
                 reloc_offsets.insert(refp, special);
 
                 refp = special;
+                locations.push(special);
 
                 if !offset_contain.contains(&special) {
                     offset_contain.insert(special);
@@ -164,6 +299,7 @@ impl<'a> HSDRawFile<'a> {
                 }
             }
 
+            reference_locations.push(locations.into_boxed_slice());
             r.seek(temp);
         }
 
@@ -274,28 +410,55 @@ impl<'a> HSDRawFile<'a> {
         }
 
         // process special orphans
+        // HSDRaw appends a goto to the orphan onto the previous struct.
+        // Struct data is borrowed from the file, so instead the previous struct is
+        // extended over the orphan, which directly follows it in the file.
+        let mut orphan_subactions: Vec<usize> = Vec::new();
         for s in &orphans {
             // hack: if this is a subaction append it to previous struct
             if s.reference_count() > 0 {
                 let maxkey = s.max_key().unwrap();
                 if s.get_reference(maxkey) == *s && maxkey >= 8 && s.get_i32(maxkey - 4) == 0x1C000000 {
-                    // get previous struct
-                    let prev = Self::get_previous_struct(&struct_cache, s.clone());
-
-                    // add goto pointer to subaction
-                    if let Some(_prev) = prev {
-                        // Removed this section because I don't want arbitrary SetReferenceStructs.
-                        eprintln!("todo add goto pointer to subaction");
-                        //let len = prev.len();
-                        //prev.Resize(prev.len() + 8);
-                        //prev.SetInt32(len, 0x1C000000);
-                        //prev.SetReferenceStruct(len + 4, Some(s));
-                        //continue;
-                    }
+                    orphan_subactions.push(struct_cache_to_offset[s]);
                 }
             }
         }
 
+        if !orphan_subactions.is_empty() {
+            let mut sorted_structs: Vec<(usize, HSDStruct<'a>)> = struct_cache_to_offset.iter()
+                .map(|(s, &offset)| (offset, s.clone()))
+                .collect();
+            sorted_structs.sort_unstable_by_key(|(offset, _)| *offset);
+
+            // merge back to front so orphans following orphans are carried along
+            orphan_subactions.sort_unstable();
+            for &orphan_offset in orphan_subactions.iter().rev() {
+                let i = sorted_structs.partition_point(|(offset, _)| *offset < orphan_offset);
+                if i == 0 { continue }
+
+                let (prev_offset, prev) = sorted_structs[i - 1].clone();
+                let orphan = sorted_structs[i].1.clone();
+                if prev_offset + prev.len() != orphan_offset { continue }
+
+                let data = &r.data[prev_offset..orphan_offset + orphan.len()];
+                let mut merged_refs = prev.get_references().borrow().clone();
+                for (&loc, target) in orphan.get_references().borrow().iter() {
+                    merged_refs.insert(loc + prev.len(), target.clone());
+                }
+                let merged = HSDStruct::new(data, merged_refs);
+
+                Self::replace_struct(
+                    &mut struct_cache,
+                    &mut struct_cache_to_offset,
+                    &mut roots,
+                    &mut references,
+                    &prev,
+                    &merged
+                );
+                sorted_structs[i - 1].1 = merged;
+            }
+        }
+
         // debug information
         // it all matches HSDRaw!
         //println!("struct_cache              {}", struct_cache.len());
@@ -320,17 +483,34 @@ impl<'a> HSDRawFile<'a> {
             struct_cache_to_offset,
             roots,
             references,
+            reference_locations,
         }
     }
 
-    fn get_previous_struct(struct_cache: &[HSDStruct<'a>], s: HSDStruct<'a>) -> Option<HSDStruct<'a>> {
-        for i in 0..struct_cache.len()-1 {
-            if struct_cache[i + 1] == s {
-                return Some(struct_cache[i].clone());
+    #[allow(clippy::mutable_key_type)]
+    fn replace_struct(
+        struct_cache: &mut [HSDStruct<'a>],
+        struct_cache_to_offset: &mut HashMap<HSDStruct<'a>, usize>,
+        roots: &mut [HSDRootNode<'a>],
+        references: &mut [HSDRootNode<'a>],
+        old: &HSDStruct<'a>,
+        new: &HSDStruct<'a>,
+    ) {
+        for s in struct_cache.iter_mut() {
+            if s == old { *s = new.clone(); }
+
+            for r in s.get_references().borrow_mut().values_mut() {
+                if r == old { *r = new.clone(); }
             }
         }
 
-        None
+        if let Some(offset) = struct_cache_to_offset.remove(old) {
+            struct_cache_to_offset.insert(new.clone(), offset);
+        }
+
+        for root in roots.iter_mut().chain(references.iter_mut()) {
+            if root.hsd_struct == *old { root.hsd_struct = new.clone(); }
+        }
     }
 }
 
@@ -464,3 +644,37 @@ fn binary_search<T: PartialEq + PartialOrd>(item: T, a: &[T]) -> Option<usize> {
 
     Some(mid as usize)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dat::test_dat::{build_dat, write_u32};
+
+    #[test]
+    fn check_header_rejects_malformed_files() {
+        // root @0x00 pointing to @0x10, referenced symbol used @0x14
+        let mut data = vec![0u8; 0x20];
+        write_u32(&mut data, 0x00, 0x10);
+        let valid = build_dat(&data, &[0x00], &[(0x00, "root")], &[(0x14, "symbol")]);
+        assert!(valid.check_header().is_ok());
+        assert!(HSDRawFile::try_new(&valid).is_ok());
+
+        let with_data = |f: &dyn Fn(&mut Vec<u8>)| {
+            let mut file = valid.data.to_vec();
+            f(&mut file);
+            DatFile { filename: "test.dat".into(), data: file.into_boxed_slice().into() }
+        };
+
+        let not_a_dat = with_data(&|file| file.iter_mut().for_each(|b| *b = 0xFF));
+        let truncated = with_data(&|file| file.truncate(0x30));
+        let bad_relocation = with_data(&|file| write_u32(file, 0x20 + 0x20, 0x40));
+        let bad_pointer = with_data(&|file| write_u32(file, 0x20, 0x100));
+        let bad_root = with_data(&|file| write_u32(file, 0x20 + 0x24, 0x20));
+        let reference_loop = with_data(&|file| write_u32(file, 0x20 + 0x14, 0x14));
+
+        for file in [not_a_dat, truncated, bad_relocation, bad_pointer, bad_root, reference_loop] {
+            assert!(file.check_header().is_err());
+            assert!(HSDRawFile::try_new(&file).is_err());
+        }
+    }
+}
@@ -0,0 +1,164 @@
+use crate::dat::{DatExtractError, DatFile, HSDStruct};
+use std::collections::BTreeSet;
+
+pub const DAT_HEADER_SIZE: usize = 0x20;
+
+/// Edits the raw bytes of a dat file.
+///
+/// New data is appended to the end of the data section, so existing offsets stay valid.
+/// The relocation, root and reference tables are rebuilt by `finish`.
+///
+/// All offsets are relative to the start of the data section, the same as pointers in the file.
+#[derive(Debug, Clone)]
+pub struct DatPatcher {
+    pub filename: std::rc::Rc<str>,
+    pub data: Vec<u8>,
+
+    /// Offsets of every pointer in the data section.
+    pub relocations: BTreeSet<usize>,
+
+    // copied unchanged, offsets in these tables are not affected by appending data
+    root_table: Box<[u8]>,
+    reference_table: Box<[u8]>,
+    string_table: Box<[u8]>,
+    version_chars: [u8; 12],
+}
+
+impl DatPatcher {
+    pub fn new(dat: &DatFile) -> Result<Self, DatExtractError> {
+        let file = &dat.data;
+        if file.len() < DAT_HEADER_SIZE { return Err(DatExtractError::InvalidDatFile) }
+
+        let word = |i: usize| -> Result<usize, DatExtractError> {
+            let bytes = file.get(i..i+4).ok_or(DatExtractError::InvalidDatFile)?;
+            Ok(u32::from_be_bytes(bytes.try_into().unwrap()) as usize)
+        };
+
+        let fsize = word(0x00)?.min(file.len());
+        let data_size = word(0x04)?;
+        let reloc_count = word(0x08)?;
+        let root_count = word(0x0C)?;
+        let ref_count = word(0x10)?;
+
+        let reloc_start = DAT_HEADER_SIZE + data_size;
+        let root_start = reloc_start + reloc_count * 4;
+        let ref_start = root_start + root_count * 8;
+        let string_start = ref_start + ref_count * 8;
+        if string_start > fsize { return Err(DatExtractError::InvalidDatFile) }
+
+        let mut relocations = BTreeSet::new();
+        for i in 0..reloc_count {
+            relocations.insert(word(reloc_start + i * 4)?);
+        }
+
+        Ok(Self {
+            filename: dat.filename.clone(),
+            data: file[DAT_HEADER_SIZE..reloc_start].to_vec(),
+            relocations,
+            root_table: file[root_start..ref_start].into(),
+            reference_table: file[ref_start..string_start].into(),
+            string_table: file[string_start..fsize].into(),
+            version_chars: file[0x14..0x20].try_into().unwrap(),
+        })
+    }
+
+    /// Returns the offset of the appended bytes.
+    /// Alignment must be a multiple of 4. GX image data needs 0x20.
+    pub fn append(&mut self, bytes: &[u8], alignment: usize) -> usize {
+        let offset = self.data.len().next_multiple_of(alignment);
+        self.data.resize(offset, 0);
+        self.data.extend_from_slice(bytes);
+        self.data.resize(self.data.len().next_multiple_of(4), 0);
+        offset
+    }
+
+    pub fn write_bytes(&mut self, offset: usize, bytes: &[u8]) {
+        self.data[offset..offset + bytes.len()].copy_from_slice(bytes);
+    }
+
+    pub fn write_u8(&mut self, offset: usize, n: u8) { self.data[offset] = n; }
+    pub fn write_u16(&mut self, offset: usize, n: u16) { self.write_bytes(offset, &n.to_be_bytes()); }
+    pub fn write_u32(&mut self, offset: usize, n: u32) { self.write_bytes(offset, &n.to_be_bytes()); }
+    pub fn write_f32(&mut self, offset: usize, n: f32) { self.write_bytes(offset, &n.to_be_bytes()); }
+
+    pub fn read_u32(&self, offset: usize) -> u32 {
+        u32::from_be_bytes(self.data[offset..offset+4].try_into().unwrap())
+    }
+
+    /// Returns the target of the pointer at the offset, if there is one.
+    pub fn pointer(&self, offset: usize) -> Option<usize> {
+        if self.relocations.contains(&offset) {
+            Some(self.read_u32(offset) as usize)
+        } else {
+            None
+        }
+    }
+
+    pub fn set_pointer(&mut self, offset: usize, target: usize) {
+        self.write_u32(offset, target as u32);
+        self.relocations.insert(offset);
+    }
+
+    pub fn clear_pointer(&mut self, offset: usize) {
+        self.write_u32(offset, 0);
+        self.relocations.remove(&offset);
+    }
+
+    /// Number of pointers and roots that point to the offset.
+    pub fn reference_count(&self, target: usize) -> usize {
+        let pointers = self.relocations.iter()
+            .filter(|&&offset| self.read_u32(offset) as usize == target)
+            .count();
+        let roots = self.root_table.chunks_exact(8)
+            .filter(|root| u32::from_be_bytes(root[0..4].try_into().unwrap()) as usize == target)
+            .count();
+
+        pointers + roots
+    }
+
+    /// Offset of a struct parsed from the same dat file.
+    pub fn struct_offset(dat: &DatFile, hsd_struct: &HSDStruct) -> Option<usize> {
+        let file_start = dat.data.as_ptr() as usize;
+        let struct_start = hsd_struct.data.as_ptr() as usize;
+
+        if struct_start < file_start + DAT_HEADER_SIZE || struct_start >= file_start + dat.data.len() {
+            return None;
+        }
+
+        Some(struct_start - file_start - DAT_HEADER_SIZE)
+    }
+
+    pub fn finish(mut self) -> DatFile {
+        self.data.resize(self.data.len().next_multiple_of(4), 0);
+
+        let root_count = self.root_table.len() / 8;
+        let ref_count = self.reference_table.len() / 8;
+        let fsize = DAT_HEADER_SIZE
+            + self.data.len()
+            + self.relocations.len() * 4
+            + self.root_table.len()
+            + self.reference_table.len()
+            + self.string_table.len();
+
+        let mut file = Vec::with_capacity(fsize);
+        file.extend_from_slice(&(fsize as u32).to_be_bytes());
+        file.extend_from_slice(&(self.data.len() as u32).to_be_bytes());
+        file.extend_from_slice(&(self.relocations.len() as u32).to_be_bytes());
+        file.extend_from_slice(&(root_count as u32).to_be_bytes());
+        file.extend_from_slice(&(ref_count as u32).to_be_bytes());
+        file.extend_from_slice(&self.version_chars);
+
+        file.extend_from_slice(&self.data);
+        for &offset in self.relocations.iter() {
+            file.extend_from_slice(&(offset as u32).to_be_bytes());
+        }
+        file.extend_from_slice(&self.root_table);
+        file.extend_from_slice(&self.reference_table);
+        file.extend_from_slice(&self.string_table);
+
+        DatFile {
+            filename: self.filename,
+            data: file.into_boxed_slice().into(),
+        }
+    }
+}
@@ -0,0 +1,36 @@
+use crate::dat::{Image, Texture};
+use std::io;
+use std::path::Path;
+
+// rgba_data is r | g << 8 | b << 16 | a << 24, which is RGBA in memory
+fn save_rgba_png(path: &Path, rgba_data: &[u32], width: usize, height: usize) -> io::Result<()> {
+    lodepng::encode_file(path, rgba_data, width, height, lodepng::ColorType::RGBA, 8)
+        .map_err(io::Error::other)
+}
+
+fn encode_rgba_png(rgba_data: &[u32], width: usize, height: usize) -> io::Result<Vec<u8>> {
+    lodepng::encode_memory(rgba_data, width, height, lodepng::ColorType::RGBA, 8)
+        .map_err(io::Error::other)
+}
+
+impl Image {
+    pub fn save_png<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        save_rgba_png(path.as_ref(), &self.rgba_data, self.width, self.height)
+    }
+
+    pub fn encode_png(&self) -> io::Result<Vec<u8>> {
+        encode_rgba_png(&self.rgba_data, self.width, self.height)
+    }
+}
+
+impl Texture {
+    /// Saves the base level. Mipmaps are not saved.
+    pub fn save_png<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        save_rgba_png(path.as_ref(), &self.rgba_data, self.width, self.height)
+    }
+
+    /// Encodes the base level. Mipmaps are not encoded.
+    pub fn encode_png(&self) -> io::Result<Vec<u8>> {
+        encode_rgba_png(&self.rgba_data, self.width, self.height)
+    }
+}
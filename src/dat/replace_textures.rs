@@ -0,0 +1,225 @@
+use crate::dat::{
+    DatFile, DatExtractError, DatPatcher, HSDRawFile, HSDStruct, JOBJ, TOBJ, TLUT,
+    Image, InternalTextureFormat, TLUTFormat, encode_data, tex_map_id,
+};
+
+#[derive(Copy, Clone, Debug)]
+pub enum TextureTarget {
+    /// Offset of the TOBJ struct in the data section. See `DatPatcher::struct_offset`.
+    TOBJOffset(usize),
+
+    /// Index of the DOBJ in the first joint root, same order as `extract_model_from_jobj`.
+    /// Replaces the first TOBJ of its MOBJ.
+    DOBJIndex(usize),
+}
+
+pub fn find_tobj<'a>(dat: &DatFile, hsd_file: &HSDRawFile<'a>, target: TextureTarget) -> Option<TOBJ<'a>> {
+    match target {
+        TextureTarget::TOBJOffset(offset) => hsd_file.struct_cache.iter()
+            .find(|s| DatPatcher::struct_offset(dat, s) == Some(offset))
+            .map(|s| TOBJ::new(s.clone())),
+        TextureTarget::DOBJIndex(idx) => {
+            let root_jobj = hsd_file.roots.iter().find_map(JOBJ::try_from_root_node)?;
+            root_jobj.get_all_dobjs().get(idx)?.get_mobj()?.get_tobj()
+        }
+    }
+}
+
+/// Every TOBJ in the file with a decodable image, sorted by offset.
+/// Offsets are the same as `TextureTarget::TOBJOffset`.
+///
+/// TOBJs are found by their layout rather than by walking the roots,
+/// so textures in stages and effect files are found too.
+pub fn find_all_tobjs<'a>(dat: &DatFile, hsd_file: &HSDRawFile<'a>) -> Vec<(usize, TOBJ<'a>)> {
+    let mut tobjs: Vec<(usize, TOBJ<'a>)> = hsd_file.struct_cache.iter()
+        .filter(|s| is_tobj(s))
+        .filter_map(|s| Some((DatPatcher::struct_offset(dat, s)?, TOBJ::new(s.clone()))))
+        .collect();
+
+    tobjs.sort_unstable_by_key(|(offset, _)| *offset);
+    tobjs
+}
+
+fn is_tobj(s: &HSDStruct) -> bool {
+    if s.len() < 0x5C { return false }
+    if s.get_u32(0x08) > tex_map_id::TEXMAP_DISABLE { return false }
+
+    let hsd_image = match s.try_get_reference(0x4C) {
+        Some(hsd_image) if hsd_image.len() >= 0x18 => hsd_image,
+        _ => return false,
+    };
+
+    let format = match InternalTextureFormat::new(hsd_image.get_u32(0x08)) {
+        Some(format) => format,
+        None => return false,
+    };
+
+    let width = hsd_image.get_u16(0x04) as usize;
+    let height = hsd_image.get_u16(0x06) as usize;
+    if width == 0 || height == 0 { return false }
+
+    match hsd_image.try_get_buffer(0x00) {
+        Some(buffer) if buffer.len() >= format.encoded_size(width, height) => (),
+        _ => return false,
+    }
+
+    let paletted = matches!(
+        format,
+        InternalTextureFormat::CI4 | InternalTextureFormat::CI8 | InternalTextureFormat::CI14X2
+    );
+    !paletted || s.try_get_reference(0x50).is_some_and(|tlut| tlut.try_get_buffer(0x00).is_some())
+}
+
+/// Re-encodes the image and writes it into the TOBJ's image and TLUT.
+/// Uses the texture's current format if `format` is None.
+///
+/// Image and palette data are overwritten if the new data fits and nothing else uses them,
+/// otherwise they are appended to the file. An HSD_Image or HSD_Tlut shared with other TOBJs
+/// is copied first, so only this TOBJ's texture changes.
+/// Mipmaps are removed, along with the TOBJ's LOD settings.
+// HSD_TOBJ.cs (EncodeImageData)
+pub fn replace_texture(
+    dat: &DatFile,
+    target: TextureTarget,
+    image: &Image,
+    format: Option<InternalTextureFormat>,
+) -> Result<DatFile, DatExtractError> {
+    let hsd_file = HSDRawFile::new(dat);
+    let tobj = find_tobj(dat, &hsd_file, target).ok_or(DatExtractError::InvalidDatFile)?;
+    let hsd_image = tobj.hsd_struct.try_get_reference(0x4C).ok_or(DatExtractError::InvalidDatFile)?;
+    let tlut = tobj.hsd_struct.try_get_reference(0x50).map(TLUT::new);
+
+    if image.width > u16::MAX as usize || image.height > u16::MAX as usize {
+        return Err(DatExtractError::InvalidDatFile);
+    }
+
+    let format = format.or_else(|| tobj.format()).ok_or(DatExtractError::InvalidDatFile)?;
+    let tlut_format = tlut.as_ref().and_then(|t| TLUTFormat::new(t.hsd_struct.get_u32(0x04)));
+    let encoded = encode_data(format, image.width, image.height, &image.rgba_data, tlut_format);
+
+    let mut patcher = DatPatcher::new(dat)?;
+    let tobj_offset = DatPatcher::struct_offset(dat, &tobj.hsd_struct).ok_or(DatExtractError::InvalidDatFile)?;
+    let image_offset = DatPatcher::struct_offset(dat, &hsd_image).ok_or(DatExtractError::InvalidDatFile)?;
+
+    // HSD_Image
+    let image_offset = unshare_struct(&mut patcher, tobj_offset + 0x4C, image_offset, hsd_image.len());
+    write_buffer(&mut patcher, dat, image_offset, hsd_image.try_get_reference(0x00), &encoded.data);
+    patcher.write_u16(image_offset + 0x04, image.width as u16);
+    patcher.write_u16(image_offset + 0x06, image.height as u16);
+    patcher.write_u32(image_offset + 0x08, format as u32);
+    patcher.write_u32(image_offset + 0x0C, 0); // mipmap
+    patcher.write_f32(image_offset + 0x10, 0.0); // min lod
+    patcher.write_f32(image_offset + 0x14, 0.0); // max lod
+
+    // HSD_TOBJ_LOD, the default min filter doesn't sample mip levels
+    if patcher.pointer(tobj_offset + 0x54).is_some() {
+        patcher.clear_pointer(tobj_offset + 0x54);
+    }
+
+    match (encoded.tlut, tlut) {
+        (Some(new_tlut), old_tlut) => {
+            // HSD_Tlut
+            let tlut_offset = match old_tlut.as_ref() {
+                Some(old_tlut) => {
+                    let offset = DatPatcher::struct_offset(dat, &old_tlut.hsd_struct)
+                        .ok_or(DatExtractError::InvalidDatFile)?;
+                    unshare_struct(&mut patcher, tobj_offset + 0x50, offset, old_tlut.hsd_struct.len())
+                }
+                None => {
+                    let tlut_offset = patcher.append(&[0u8; 0x10], 4);
+                    patcher.set_pointer(tobj_offset + 0x50, tlut_offset);
+                    tlut_offset
+                }
+            };
+
+            let old_data = old_tlut.and_then(|t| t.hsd_struct.try_get_reference(0x00));
+            write_buffer(&mut patcher, dat, tlut_offset, old_data, &new_tlut.data);
+            patcher.write_u32(tlut_offset + 0x04, new_tlut.format as u32);
+            patcher.write_u16(tlut_offset + 0x0C, new_tlut.colour_count);
+        }
+        (None, Some(_)) => patcher.clear_pointer(tobj_offset + 0x50),
+        (None, None) => (),
+    }
+
+    Ok(patcher.finish())
+}
+
+// If anything else points to the struct, appends a copy of it and points `pointer_offset` to the copy.
+// Returns the offset of the struct to write to.
+fn unshare_struct(patcher: &mut DatPatcher, pointer_offset: usize, offset: usize, size: usize) -> usize {
+    if patcher.reference_count(offset) <= 1 { return offset }
+
+    let bytes = patcher.data[offset..offset + size].to_vec();
+    let copy_offset = patcher.append(&bytes, 4);
+
+    let pointers: Vec<usize> = patcher.relocations.range(offset..offset + size).copied().collect();
+    for pointer in pointers {
+        let target = patcher.read_u32(pointer) as usize;
+        patcher.set_pointer(copy_offset + pointer - offset, target);
+    }
+
+    patcher.set_pointer(pointer_offset, copy_offset);
+    copy_offset
+}
+
+fn write_buffer(
+    patcher: &mut DatPatcher,
+    dat: &DatFile,
+    pointer_offset: usize,
+    old_buffer: Option<HSDStruct>,
+    bytes: &[u8],
+) {
+    let in_place = old_buffer
+        .filter(|old| bytes.len() <= old.len())
+        .and_then(|old| DatPatcher::struct_offset(dat, &old))
+        .filter(|&offset| patcher.reference_count(offset) <= 1);
+
+    match in_place {
+        Some(offset) => patcher.write_bytes(offset, bytes),
+        None => {
+            let offset = patcher.append(bytes, 0x20);
+            patcher.set_pointer(pointer_offset, offset);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dat::test_dat::{build_dat, write_u32};
+    use crate::dat::decode_image;
+
+    #[test]
+    fn replacing_a_shared_image_keeps_the_other_tobj() {
+        // TOBJ a @0x00 and TOBJ b @0x60 share the HSD_Image @0xC0 and its 8x4 I8 buffer @0xE0.
+        // a also has an HSD_TOBJ_LOD @0x100.
+        let mut data = vec![0u8; 0x110];
+        write_u32(&mut data, 0x4C, 0xC0);
+        write_u32(&mut data, 0x54, 0x100);
+        write_u32(&mut data, 0x60 + 0x4C, 0xC0);
+        write_u32(&mut data, 0xC0, 0xE0);
+        data[0xC4..0xC8].copy_from_slice(&[0, 8, 0, 4]);
+        write_u32(&mut data, 0xC8, InternalTextureFormat::I8 as u32);
+        for (i, b) in data[0xE0..0x100].iter_mut().enumerate() { *b = i as u8 * 8; }
+
+        let dat = build_dat(&data, &[0x4C, 0x54, 0xAC, 0xC0], &[(0x00, "a"), (0x60, "b")], &[]);
+        let hsd = HSDRawFile::new(&dat);
+        let decode = |hsd: &HSDRawFile, i: usize| decode_image(hsd.roots[i].hsd_struct.get_reference(0x4C), None);
+        let original = decode(&hsd, 1);
+
+        let white = Image { width: 8, height: 4, rgba_data: vec![0xFFFFFFFF; 32].into() };
+        let new_dat = replace_texture(&dat, TextureTarget::TOBJOffset(0x00), &white, None).unwrap();
+
+        let new_hsd = HSDRawFile::new(&new_dat);
+        assert_eq!(find_all_tobjs(&new_dat, &new_hsd).len(), 2);
+
+        let a = decode(&new_hsd, 0);
+        assert!(a.rgba_data.iter().all(|&p| p == 0xFFFFFFFF));
+        assert!(new_hsd.roots[0].hsd_struct.try_get_reference(0x54).is_none());
+
+        let b = decode(&new_hsd, 1);
+        assert_eq!((b.width, b.height), (original.width, original.height));
+        assert_eq!(b.rgba_data, original.rgba_data);
+        assert_eq!(new_hsd.roots[1].hsd_struct.get_u32(0x4C), 0xC0);
+    }
+}
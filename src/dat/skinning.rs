@@ -0,0 +1,139 @@
+use crate::dat::{AnimationFrame, Model, VertexAttributes};
+use glam::f32::{Mat3, Mat4, Vec3};
+
+impl Model {
+    /// Posed position and normal of each vertex, in the same order as `vertex_attributes`.
+    ///
+    /// Vertices with several weights are blended from `inv_world_transforms` to the posed world transforms.
+    /// Vertices weighted fully to one bone and rigid vertices without weights are in the space of their bone.
+    // SBHsdMesh.cs (GXVertexToHsdVertex)
+    pub fn skin(&self, frame: &AnimationFrame) -> Vec<(Vec3, Vec3)> {
+        let world_transforms = self.world_transforms(frame);
+        let owners = self.vertex_owners();
+        let mut morphed = self.morph(frame);
+
+        for ((v, &owner), (pos, normal)) in self.vertex_attributes.iter().zip(owners.iter()).zip(morphed.iter_mut()) {
+            let transform = skinning_transform(v, owner as usize, &world_transforms, &self.inv_world_transforms);
+            let normal_transform = Mat3::from_mat4(transform).inverse().transpose();
+            *pos = transform.transform_point3(*pos);
+            *normal = (normal_transform * *normal).normalize_or_zero();
+        }
+
+        morphed
+    }
+
+    /// Position and normal of each vertex after blending shape sets with `AnimationFrame::morph_weights`.
+    /// Vertices without a shape set are unchanged.
+    pub fn morph(&self, frame: &AnimationFrame) -> Vec<(Vec3, Vec3)> {
+        let mut morphed: Vec<(Vec3, Vec3)> = self.vertex_attributes.iter().map(|v| (v.pos, v.normal)).collect();
+
+        for (morph_set, weights) in self.morph_sets.iter().zip(frame.morph_weights.iter()) {
+            let start = morph_set.vertex_start as usize;
+            let end = start + morph_set.vertex_len as usize;
+            let vertices = &mut morphed[start..end];
+            vertices.fill((Vec3::ZERO, Vec3::ZERO));
+
+            for (target, &w) in morph_set.targets.iter().zip(weights.iter()) {
+                if w == 0.0 { continue }
+                for ((pos, normal), (&target_pos, &target_normal)) in vertices.iter_mut()
+                    .zip(target.positions.iter().zip(target.normals.iter()))
+                {
+                    *pos += target_pos * w;
+                    *normal += target_normal * w;
+                }
+            }
+
+            for (_, normal) in vertices.iter_mut() {
+                *normal = normal.normalize_or_zero();
+            }
+        }
+
+        morphed
+    }
+
+    /// Posed world transform of each bone.
+    pub fn world_transforms(&self, frame: &AnimationFrame) -> Vec<Mat4> {
+        let mut world_transforms: Vec<Mat4> = Vec::with_capacity(self.bones.len());
+        for (i, bone) in self.bones.iter().enumerate() {
+            let transform = frame.transforms.get(i).copied().unwrap_or(self.base_transforms[i]);
+            let world_transform = match bone.parent {
+                Some(p_i) => world_transforms[p_i as usize] * transform,
+                None => transform,
+            };
+            world_transforms.push(world_transform);
+        }
+        world_transforms
+    }
+
+    /// The bone whose primitive groups first use each vertex.
+    /// Rigid vertices are in the space of this bone.
+    pub fn vertex_owners(&self) -> Vec<u16> {
+        let mut owners = vec![u16::MAX; self.vertex_attributes.len()];
+
+        for (bone_idx, bone) in self.bones.iter().enumerate() {
+            let start = bone.pgroup_start as usize;
+            let end = start + bone.pgroup_len as usize;
+
+            for pgroup in self.primitive_groups[start..end].iter() {
+                let ranges = [
+                    (&self.indices, pgroup.indices_start, pgroup.indices_len),
+                    (&self.line_indices, pgroup.line_indices_start, pgroup.line_indices_len),
+                    (&self.point_indices, pgroup.point_indices_start, pgroup.point_indices_len),
+                ];
+
+                for (indices, start, len) in ranges {
+                    let start = start as usize;
+                    for i in indices.range(start..start + len as usize) {
+                        let owner = &mut owners[i as usize];
+                        if *owner == u16::MAX { *owner = bone_idx as u16; }
+                    }
+                }
+            }
+        }
+
+        // unused vertices
+        for owner in owners.iter_mut() {
+            if *owner == u16::MAX { *owner = 0; }
+        }
+
+        owners
+    }
+}
+
+/// Transform from a vertex's stored position to its posed position, given the owning bone.
+/// Weights referencing bones outside `world_transforms` are treated as rigid.
+pub fn skinning_transform(
+    v: &VertexAttributes,
+    owner: usize,
+    world_transforms: &[Mat4],
+    inv_world_transforms: &[Mat4],
+) -> Mat4 {
+    let weights = v.weights;
+    let bones = v.bones;
+    let owner_world = world_transforms.get(owner).copied().unwrap_or(Mat4::IDENTITY);
+
+    if is_rigid(v, world_transforms.len().min(inv_world_transforms.len())) {
+        // rigid
+        owner_world
+    } else if weights[0] == 1.0 {
+        // single bound
+        world_transforms[bones[0] as usize] * owner_world
+    } else {
+        // envelope
+        let mut transform = Mat4::ZERO;
+        for (&b, &w) in bones.iter().zip(weights.iter()) {
+            if w == 0.0 { continue }
+            transform += (world_transforms[b as usize] * inv_world_transforms[b as usize]) * w;
+        }
+        transform
+    }
+}
+
+/// True if `skinning_transform` treats the vertex as rigid to its owner:
+/// it has no weights, or a weight on a bone outside the model.
+pub(crate) fn is_rigid(v: &VertexAttributes, bone_count: usize) -> bool {
+    let invalid = v.bones.iter().zip(v.weights.iter())
+        .any(|(&b, &w)| w != 0.0 && b as usize >= bone_count);
+
+    invalid || v.weights.iter().all(|&w| w == 0.0)
+}
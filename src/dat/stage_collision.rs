@@ -0,0 +1,329 @@
+use crate::dat::{HSDRawFile, HSDStruct, StageSection, AnimationFrame};
+use glam::f32::{Mat4, Vec2, Vec3};
+use std::ops::Range;
+
+/// Collision mesh of a stage, from the `coll_data` root.
+// Melee/Gr/SBM_Coll_Data.cs
+#[derive(Clone, Debug)]
+pub struct StageCollision {
+    pub vertices: Box<[Vec2]>,
+    pub links: Box<[CollisionLink]>,
+
+    /// Ranges of `links` by surface. HSDRaw calls these top, bottom, right, left and dynamic links.
+    pub floors: Range<u16>,
+    pub ceilings: Range<u16>,
+    pub right_walls: Range<u16>,
+    pub left_walls: Range<u16>,
+    pub dynamic: Range<u16>,
+
+    pub groups: Box<[CollisionGroup]>,
+}
+
+/// A line between two vertices.
+// Melee/Gr/SBM_Coll_Data.cs (SBM_CollLine)
+#[derive(Copy, Clone, Debug)]
+pub struct CollisionLink {
+    /// Indices into `StageCollision::vertices`
+    pub vertices: [u16; 2],
+
+    /// Connected links, indices into `StageCollision::links`
+    pub next: Option<u16>,
+    pub prev: Option<u16>,
+    pub next_alt: Option<u16>,
+    pub prev_alt: Option<u16>,
+
+    /// Surface material, which changes footstep sounds and effects.
+    pub material: u16,
+
+    pub flags: CollisionLinkFlags,
+}
+
+/// Surface flags in the low byte, property flags in the high byte.
+pub type CollisionLinkFlags = u16;
+pub mod collision_link_flags {
+    pub const FLOOR        : u16 = 1 << 0;
+    pub const CEILING      : u16 = 1 << 1;
+    pub const RIGHT_WALL   : u16 = 1 << 2;
+    pub const LEFT_WALL    : u16 = 1 << 3;
+    pub const DISABLED     : u16 = 1 << 4;
+    pub const DROP_THROUGH : u16 = 1 << 8;
+    pub const LEDGE_GRAB   : u16 = 1 << 9;
+}
+
+/// Area group. Links and vertices of a group move together, e.g. a moving platform.
+// Melee/Gr/SBM_Coll_Data.cs (SBM_CollArea)
+#[derive(Clone, Debug)]
+pub struct CollisionGroup {
+    pub floors: Range<u16>,
+    pub ceilings: Range<u16>,
+    pub right_walls: Range<u16>,
+    pub left_walls: Range<u16>,
+    pub dynamic: Range<u16>,
+
+    /// Bounds of the group at rest
+    pub min: Vec2,
+    pub max: Vec2,
+
+    pub vertices: Range<u16>,
+
+    /// The joint moving the group, if any.
+    ///
+    /// Always None after parsing. The dat file doesn't store this link, Melee makes it
+    /// in each stage's code, and this crate has no table of those links for any stage.
+    /// Callers that need moving collision have to find the joints themselves
+    /// and set them with `StageCollision::link_group`.
+    pub joint: Option<CollisionJoint>,
+}
+
+/// A bone of a stage model.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct CollisionJoint {
+    /// Index into `StageData::sections`
+    pub section: u16,
+
+    /// Index into the section model's bones
+    pub bone: u16,
+}
+
+impl StageCollision {
+    pub fn from_stage_dat(parsed_stage_dat: &HSDRawFile) -> Option<Self> {
+        let coll_data = &parsed_stage_dat.roots.iter()
+            .find(|root| root.root_string == "coll_data")?
+            .hsd_struct;
+
+        Self::new(coll_data)
+    }
+
+    /// Returns None if the structure is too short or its counts don't fit its arrays.
+    pub fn new(coll_data: &HSDStruct) -> Option<Self> {
+        if coll_data.len() < 0x2C { return None }
+
+        let vertex_count = coll_data.get_i32(0x04).max(0) as usize;
+        let link_count = coll_data.get_i32(0x0C).max(0) as usize;
+        let group_count = coll_data.get_i32(0x28).max(0) as usize;
+
+        // SBM_CollVertex
+        let vertices = match coll_data.try_get_reference(0x00) {
+            Some(v) if v.len() >= vertex_count * 0x08 => (0..vertex_count)
+                .map(|i| Vec2::new(v.get_f32(i * 0x08), v.get_f32(i * 0x08 + 0x04)))
+                .collect(),
+            Some(_) => return None,
+            None if vertex_count == 0 => Box::default(),
+            None => return None,
+        };
+
+        let links = match coll_data.try_get_reference(0x08) {
+            Some(l) if l.len() >= link_count * 0x10 => (0..link_count)
+                .map(|i| CollisionLink::new(&l.get_embedded_struct(i * 0x10, 0x10)))
+                .collect(),
+            Some(_) => return None,
+            None if link_count == 0 => Box::default(),
+            None => return None,
+        };
+
+        let groups = match coll_data.try_get_reference(0x24) {
+            Some(g) if g.len() >= group_count * 0x28 => (0..group_count)
+                .map(|i| CollisionGroup::new(&g.get_embedded_struct(i * 0x28, 0x28)))
+                .collect(),
+            Some(_) => return None,
+            None if group_count == 0 => Box::default(),
+            None => return None,
+        };
+
+        Some(StageCollision {
+            vertices,
+            links,
+            floors: index_range(coll_data, 0x10),
+            ceilings: index_range(coll_data, 0x14),
+            right_walls: index_range(coll_data, 0x18),
+            left_walls: index_range(coll_data, 0x1C),
+            dynamic: index_range(coll_data, 0x20),
+            groups,
+        })
+    }
+
+    /// Links a group to the joint that moves it, used by `posed_vertices`.
+    /// Nothing is linked by parsing, see `CollisionGroup::joint`.
+    /// Returns false if there is no group at that index.
+    pub fn link_group(&mut self, group: usize, joint: CollisionJoint) -> bool {
+        match self.groups.get_mut(group) {
+            Some(group) => {
+                group.joint = Some(joint);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Vertices moved by the joints linked to their groups with `link_group`.
+    /// Groups without a joint, which is all of them unless linked by the caller, are left at rest.
+    /// `frames` has a pose for each section, sections without one are left at rest.
+    ///
+    /// Vertices are stored where they are with the joint in its default pose,
+    /// so each is moved by the change from the default to the posed world transform.
+    pub fn posed_vertices(&self, sections: &[StageSection], frames: &[AnimationFrame]) -> Box<[Vec2]> {
+        let mut vertices = self.vertices.clone();
+        let mut world_transforms: Vec<Option<Vec<Mat4>>> = vec![None; sections.len()];
+
+        for group in self.groups.iter() {
+            let joint = match group.joint {
+                Some(joint) => joint,
+                None => continue,
+            };
+
+            let (section, frame) = match (sections.get(joint.section as usize), frames.get(joint.section as usize)) {
+                (Some(section), Some(frame)) => (section, frame),
+                _ => continue,
+            };
+
+            let model = &section.model;
+            let bone = joint.bone as usize;
+            let section_transforms = world_transforms[joint.section as usize]
+                .get_or_insert_with(|| model.world_transforms(frame));
+            let world = match section_transforms.get(bone) {
+                Some(&world) => world,
+                None => continue,
+            };
+            let transform = world * model.inv_world_transforms[bone];
+
+            let start = (group.vertices.start as usize).min(vertices.len());
+            let end = (group.vertices.end as usize).min(vertices.len());
+            for v in vertices[start..end].iter_mut() {
+                *v = transform.transform_point3(Vec3::new(v.x, v.y, 0.0)).truncate();
+            }
+        }
+
+        vertices
+    }
+}
+
+impl CollisionLink {
+    fn new(s: &HSDStruct) -> Self {
+        let link = |offset| {
+            let idx = s.get_i16(offset);
+            (idx >= 0).then_some(idx as u16)
+        };
+
+        CollisionLink {
+            vertices: [s.get_u16(0x00), s.get_u16(0x02)],
+            next: link(0x04),
+            prev: link(0x06),
+            next_alt: link(0x08),
+            prev_alt: link(0x0A),
+            material: s.get_u16(0x0C),
+
+            // property flags at 0x0E, surface flags at 0x0F
+            flags: s.get_u16(0x0E),
+        }
+    }
+
+    pub fn check_flag(&self, flag: CollisionLinkFlags) -> bool {
+        self.flags & flag != 0
+    }
+}
+
+impl CollisionGroup {
+    fn new(s: &HSDStruct) -> Self {
+        CollisionGroup {
+            floors: index_range(s, 0x00),
+            ceilings: index_range(s, 0x04),
+            right_walls: index_range(s, 0x08),
+            left_walls: index_range(s, 0x0C),
+            dynamic: index_range(s, 0x10),
+            min: Vec2::new(s.get_f32(0x14), s.get_f32(0x18)),
+            max: Vec2::new(s.get_f32(0x1C), s.get_f32(0x20)),
+            vertices: index_range(s, 0x24),
+            joint: None,
+        }
+    }
+}
+
+// start and count as i16
+fn index_range(s: &HSDStruct, offset: usize) -> Range<u16> {
+    let start = s.get_i16(offset).max(0) as u16;
+    let count = s.get_i16(offset + 0x02).max(0) as u16;
+    start..start.saturating_add(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dat::test_dat::{build_dat, write_u32};
+    use crate::dat::HSDRawFile;
+
+    fn write_u16(data: &mut [u8], offset: usize, n: u16) {
+        data[offset..offset + 2].copy_from_slice(&n.to_be_bytes());
+    }
+
+    #[test]
+    fn parses_coll_data() {
+        use collision_link_flags::*;
+
+        // coll_data @0x00, vertices @0x30, links @0x50, group @0x80
+        let mut data = vec![0u8; 0xA8];
+        write_u32(&mut data, 0x00, 0x30);
+        write_u32(&mut data, 0x04, 4);
+        write_u32(&mut data, 0x08, 0x50);
+        write_u32(&mut data, 0x0C, 3);
+        for (offset, start, count) in [(0x10, 0, 2), (0x14, 2, 0), (0x18, 2, 1), (0x1C, 3, 0), (0x20, 3, 0)] {
+            write_u16(&mut data, offset, start);
+            write_u16(&mut data, offset + 2, count);
+        }
+        write_u32(&mut data, 0x24, 0x80);
+        write_u32(&mut data, 0x28, 1);
+
+        for (i, (x, y)) in [(-10.0f32, 0.0f32), (0.0, 0.0), (10.0, 0.0), (10.0, -5.0)].into_iter().enumerate() {
+            data[0x30 + i * 8..][..4].copy_from_slice(&x.to_be_bytes());
+            data[0x34 + i * 8..][..4].copy_from_slice(&y.to_be_bytes());
+        }
+
+        // vertices, next, prev, next alt, prev alt, material, properties and surface
+        let links = [
+            ([0u16, 1], [1i16, -1, -1, -1], 3u16, [0x01u8, 0x01]),
+            ([1, 2], [2, 0, -1, -1], 3, [0x02, 0x01]),
+            ([2, 3], [-1, 1, -1, -1], 6, [0x00, 0x04]),
+        ];
+        for (i, (vertices, connected, material, flags)) in links.into_iter().enumerate() {
+            let link = 0x50 + i * 0x10;
+            write_u16(&mut data, link, vertices[0]);
+            write_u16(&mut data, link + 0x02, vertices[1]);
+            for (k, &c) in connected.iter().enumerate() {
+                write_u16(&mut data, link + 0x04 + k * 2, c as u16);
+            }
+            write_u16(&mut data, link + 0x0C, material);
+            data[link + 0x0E..link + 0x10].copy_from_slice(&flags);
+        }
+
+        for (offset, start, count) in [(0x80, 0, 2), (0x84, 2, 0), (0x88, 2, 1), (0x8C, 3, 0), (0x90, 3, 0), (0xA4, 0, 4)] {
+            write_u16(&mut data, offset, start);
+            write_u16(&mut data, offset + 2, count);
+        }
+        for (k, v) in [-10.0f32, -5.0, 10.0, 0.0].into_iter().enumerate() {
+            data[0x94 + k * 4..][..4].copy_from_slice(&v.to_be_bytes());
+        }
+
+        let dat = build_dat(&data, &[0x00, 0x08, 0x24], &[(0x00, "coll_data")], &[]);
+        let hsd = HSDRawFile::new(&dat);
+        let collision = StageCollision::from_stage_dat(&hsd).unwrap();
+
+        assert_eq!(collision.vertices.len(), 4);
+        assert_eq!(collision.vertices[3], Vec2::new(10.0, -5.0));
+        assert_eq!((collision.floors.clone(), collision.right_walls.clone()), (0..2, 2..3));
+        assert!(collision.ceilings.is_empty() && collision.left_walls.is_empty() && collision.dynamic.is_empty());
+
+        let [platform, ledge, wall] = [0, 1, 2].map(|i| collision.links[i]);
+        assert_eq!(platform.vertices, [0, 1]);
+        assert_eq!((platform.next, platform.prev, platform.next_alt), (Some(1), None, None));
+        assert_eq!((ledge.next, ledge.prev), (Some(2), Some(0)));
+        assert_eq!((platform.material, wall.material), (3, 6));
+
+        assert!(platform.check_flag(FLOOR) && platform.check_flag(DROP_THROUGH) && !platform.check_flag(LEDGE_GRAB));
+        assert!(ledge.check_flag(FLOOR) && ledge.check_flag(LEDGE_GRAB) && !ledge.check_flag(DROP_THROUGH));
+        assert!(wall.check_flag(RIGHT_WALL) && !wall.check_flag(FLOOR));
+
+        let group = &collision.groups[0];
+        assert_eq!((group.floors.clone(), group.right_walls.clone(), group.vertices.clone()), (0..2, 2..3, 0..4));
+        assert_eq!((group.min, group.max), (Vec2::new(-10.0, -5.0), Vec2::new(10.0, 0.0)));
+        assert!(group.joint.is_none());
+    }
+}
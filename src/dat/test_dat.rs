@@ -0,0 +1,32 @@
+use crate::dat::DatFile;
+
+/// Builds a dat file from its data section, relocation table, root nodes and reference nodes.
+pub(crate) fn build_dat(data: &[u8], relocs: &[u32], roots: &[(u32, &str)], refs: &[(u32, &str)]) -> DatFile {
+    let mut strings = Vec::new();
+    let mut nodes = Vec::new();
+    for &(offset, name) in roots.iter().chain(refs.iter()) {
+        nodes.push((offset, strings.len() as u32));
+        strings.extend_from_slice(name.as_bytes());
+        strings.push(0);
+    }
+
+    let file_size = 0x20 + data.len() + relocs.len() * 4 + nodes.len() * 8 + strings.len();
+    let mut file = Vec::with_capacity(file_size);
+    for n in [file_size, data.len(), relocs.len(), roots.len(), refs.len()] {
+        file.extend_from_slice(&(n as u32).to_be_bytes());
+    }
+    file.extend_from_slice(&[0u8; 12]);
+    file.extend_from_slice(data);
+    for r in relocs { file.extend_from_slice(&r.to_be_bytes()); }
+    for (offset, string_offset) in nodes {
+        file.extend_from_slice(&offset.to_be_bytes());
+        file.extend_from_slice(&string_offset.to_be_bytes());
+    }
+    file.extend_from_slice(&strings);
+
+    DatFile { filename: "test.dat".into(), data: file.into_boxed_slice().into() }
+}
+
+pub(crate) fn write_u32(data: &mut [u8], offset: usize, n: u32) {
+    data[offset..offset + 4].copy_from_slice(&n.to_be_bytes());
+}
@@ -0,0 +1,215 @@
+use crate::dat::{HSDStruct, InternalTextureFormat, TOBJ, Texture};
+use ahash::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock, Weak};
+use xxhash_rust::xxh64::xxh64;
+
+/// A decoded texture shared between models.
+/// Handles to the same texture compare equal and have the same `id`,
+/// so renderers can upload each texture once.
+#[derive(Clone, Debug)]
+pub struct TextureHandle(Arc<Texture>);
+
+impl TextureHandle {
+    pub fn id(&self) -> usize {
+        Arc::as_ptr(&self.0) as usize
+    }
+
+    pub fn texture(&self) -> &Texture {
+        &self.0
+    }
+}
+
+impl std::ops::Deref for TextureHandle {
+    type Target = Texture;
+
+    fn deref(&self) -> &Texture {
+        &self.0
+    }
+}
+
+impl PartialEq for TextureHandle {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for TextureHandle {}
+
+impl std::hash::Hash for TextureHandle {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.id().hash(state)
+    }
+}
+
+/// Deduplicates decoded textures by content.
+///
+/// Textures are keyed by their format, dimensions, image data, palette and sampler settings,
+/// so textures shared between colours, costumes, articles and effect files are only decoded once.
+/// A texture is freed when its last handle is dropped.
+///
+/// The store can be shared between threads. Textures are decoded without holding its lock.
+#[derive(Debug, Default)]
+pub struct TextureStore {
+    inner: Mutex<TextureStoreInner>,
+}
+
+#[derive(Debug, Default)]
+struct TextureStoreInner {
+    // hash of the content key -> content keys and textures with that hash
+    textures: HashMap<u64, Vec<StoredTexture>>,
+
+    // entry count at which freed textures are next forgotten
+    prune_at: usize,
+}
+
+static GLOBAL_TEXTURE_STORE: OnceLock<TextureStore> = OnceLock::new();
+
+#[derive(Debug)]
+struct StoredTexture {
+    key: Box<[u8]>,
+    texture: Weak<Texture>,
+}
+
+// entry count before freed textures are first forgotten
+const MIN_PRUNE_COUNT: usize = 64;
+
+impl TextureStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The store used when extracting models.
+    pub fn global() -> &'static TextureStore {
+        GLOBAL_TEXTURE_STORE.get_or_init(TextureStore::new)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, TextureStoreInner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Returns the stored texture if an identical one is alive, otherwise decodes the TOBJ.
+    pub fn get_or_decode(&self, tobj: &TOBJ) -> Option<TextureHandle> {
+        let key = texture_content_key(tobj)?;
+        let hash = xxh64(&key, 0);
+
+        if let Some(texture) = self.lock().get(hash, &key) {
+            return Some(TextureHandle(texture));
+        }
+
+        let texture = Arc::new(tobj.texture()?);
+
+        // another thread may have decoded the same texture in the meantime
+        let mut inner = self.lock();
+        if let Some(texture) = inner.get(hash, &key) {
+            return Some(TextureHandle(texture));
+        }
+        inner.insert(hash, key, &texture);
+
+        Some(TextureHandle(texture))
+    }
+
+    /// Number of textures that are still alive.
+    pub fn len(&self) -> usize {
+        self.lock().textures.values()
+            .flatten()
+            .filter(|stored| stored.texture.strong_count() != 0)
+            .count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Forgets textures that have been freed.
+    /// This also happens as textures are added.
+    pub fn remove_unused(&self) {
+        self.lock().remove_unused();
+    }
+}
+
+impl TextureStoreInner {
+    fn get(&self, hash: u64, key: &[u8]) -> Option<Arc<Texture>> {
+        self.textures.get(&hash)?
+            .iter()
+            .find(|stored| *stored.key == *key)
+            .and_then(|stored| stored.texture.upgrade())
+    }
+
+    fn insert(&mut self, hash: u64, key: Box<[u8]>, texture: &Arc<Texture>) {
+        let entries = self.textures.entry(hash).or_default();
+        entries.retain(|stored| stored.texture.strong_count() != 0 && stored.key != key);
+        entries.push(StoredTexture { key, texture: Arc::downgrade(texture) });
+
+        if self.textures.len() >= self.prune_at {
+            self.remove_unused();
+        }
+    }
+
+    fn remove_unused(&mut self) {
+        for entries in self.textures.values_mut() {
+            entries.retain(|stored| stored.texture.strong_count() != 0);
+        }
+        self.textures.retain(|_, entries| !entries.is_empty());
+        self.prune_at = (self.textures.len() * 2).max(MIN_PRUNE_COUNT);
+    }
+}
+
+/// Hash of everything `TOBJ::texture` reads.
+/// None if the TOBJ has no image.
+pub fn texture_content_hash(tobj: &TOBJ) -> Option<u64> {
+    texture_content_key(tobj).map(|key| xxh64(&key, 0))
+}
+
+// Everything `TOBJ::texture` reads, so textures with equal keys decode the same.
+fn texture_content_key(tobj: &TOBJ) -> Option<Box<[u8]>> {
+    let hsd_image = tobj.hsd_struct.try_get_reference(0x4C)?;
+    let image_buffer = hsd_image.try_get_buffer(0x00)?;
+    let mut key = Vec::new();
+
+    // TOBJ sampler settings: scale, wrap, repeat, mag filter
+    push_bytes(&mut key, &tobj.hsd_struct, 0x1C..0x24);
+    push_bytes(&mut key, &tobj.hsd_struct, 0x34..0x3E);
+    push_bytes(&mut key, &tobj.hsd_struct, 0x48..0x4C);
+    if let Some(hsd_lod) = tobj.hsd_struct.try_get_reference(0x54) {
+        push_bytes(&mut key, &hsd_lod, 0x00..0x10);
+    }
+
+    // HSD_Image: dimensions, format, mip range
+    push_bytes(&mut key, &hsd_image, 0x04..0x18);
+    let width = hsd_image.get_u16(0x04) as usize;
+    let height = hsd_image.get_u16(0x06) as usize;
+    let format = InternalTextureFormat::new(hsd_image.get_u32(0x08))?;
+    let mipmap = hsd_image.get_u16(0x0C) != 0;
+    let max_lod = hsd_image.get_f32(0x14);
+
+    // same levels as decode_image_levels
+    let level_count = if mipmap { max_lod.max(0.0) as usize + 1 } else { 1 };
+    let mut size = 0;
+    let (mut level_width, mut level_height) = (width, height);
+    for _ in 0..level_count {
+        size += format.encoded_size(level_width, level_height);
+        if level_width == 1 && level_height == 1 { break }
+        level_width = (level_width / 2).max(1);
+        level_height = (level_height / 2).max(1);
+    }
+    key.extend_from_slice(&image_buffer[..size.min(image_buffer.len())]);
+
+    if let Some(hsd_tlut) = tobj.hsd_struct.try_get_reference(0x50) {
+        // format and colour count
+        push_bytes(&mut key, &hsd_tlut, 0x04..0x08);
+        push_bytes(&mut key, &hsd_tlut, 0x0C..0x0E);
+
+        let colour_count = hsd_tlut.get_u16(0x0C) as usize;
+        if let Some(tlut_buffer) = hsd_tlut.try_get_buffer(0x00) {
+            key.extend_from_slice(&tlut_buffer[..(colour_count * 2).min(tlut_buffer.len())]);
+        }
+    }
+
+    Some(key.into_boxed_slice())
+}
+
+fn push_bytes(key: &mut Vec<u8>, hsd_struct: &HSDStruct, range: std::ops::Range<usize>) {
+    let end = range.end.min(hsd_struct.len());
+    let start = range.start.min(end);
+    key.extend_from_slice(&hsd_struct.data[start..end]);
+}
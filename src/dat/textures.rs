@@ -1,6 +1,6 @@
 #![allow(clippy::upper_case_acronyms)]
 
-use crate::dat::{DOBJ, HSDStruct};
+use crate::dat::{DOBJ, HSDStruct, TextureTEV, TextureHandle, TextureStore};
 
 use std::collections::HashMap;
 
@@ -28,6 +28,161 @@ pub struct Texture {
     pub scale_y: f32,
     pub wrap_u: WrapMode,
     pub wrap_v: WrapMode,
+
+    /// Mip levels after the base level, each half the size of the previous.
+    pub mipmaps: Box<[Image]>,
+    pub lod: TextureLOD,
+}
+
+// HSD_TOBJ.cs (HSD_TOBJ_LOD), with the mip range from HSD_Image
+#[derive(Debug, Clone, Copy)]
+pub struct TextureLOD {
+    pub min_filter: TexFilter,
+    pub mag_filter: TexFilter,
+    pub bias: f32,
+    pub bias_clamp: bool,
+    pub edge_lod: bool,
+    pub anisotropy: Anisotropy,
+    pub min_lod: f32,
+    pub max_lod: f32,
+}
+
+impl Default for TextureLOD {
+    fn default() -> Self {
+        TextureLOD {
+            min_filter: tex_filter::LINEAR,
+            mag_filter: tex_filter::LINEAR,
+            bias: 0.0,
+            bias_clamp: false,
+            edge_lod: false,
+            anisotropy: anisotropy::ANISO_1,
+            min_lod: 0.0,
+            max_lod: 0.0,
+        }
+    }
+}
+
+// GX/Enums.cs (GXTexFilter)
+pub type TexFilter = u32;
+pub mod tex_filter {
+    use super::TexFilter;
+    pub const NEAR          : TexFilter = 0;
+    pub const LINEAR        : TexFilter = 1;
+    pub const NEAR_MIP_NEAR : TexFilter = 2;
+    pub const LIN_MIP_NEAR  : TexFilter = 3;
+    pub const NEAR_MIP_LIN  : TexFilter = 4;
+    pub const LIN_MIP_LIN   : TexFilter = 5;
+}
+
+// GX/Enums.cs (GXAnisotropy)
+pub type Anisotropy = u32;
+pub mod anisotropy {
+    use super::Anisotropy;
+    pub const ANISO_1        : Anisotropy = 0;
+    pub const ANISO_2        : Anisotropy = 1;
+    pub const ANISO_4        : Anisotropy = 2;
+    pub const MAX_ANISOTROPY : Anisotropy = 3;
+}
+
+/// One TOBJ of a material's texture chain.
+/// The first stage is usually the colour texture, later stages are reflections, bump maps, etc.
+#[derive(Copy, Clone, Debug)]
+pub struct TextureStage {
+    /// Index into `Model::textures`
+    pub texture_idx: u16,
+    pub tex_map_id: TexMapID,
+    pub tex_gen_src: TexGenSrc,
+    pub flags: TOBJFlags,
+    pub blending: f32,
+    pub tev: Option<TextureTEV>,
+}
+
+impl TextureStage {
+    pub fn coord(&self) -> TOBJFlags { self.flags & tobj_flags::COORD_MASK }
+    pub fn colormap(&self) -> TOBJFlags { self.flags & tobj_flags::COLORMAP_MASK }
+    pub fn alphamap(&self) -> TOBJFlags { self.flags & tobj_flags::ALPHAMAP_MASK }
+    pub fn is_bump(&self) -> bool { self.flags & tobj_flags::BUMP != 0 }
+}
+
+// GX/Enums.cs (GXTexMapID)
+pub type TexMapID = u32;
+pub mod tex_map_id {
+    use super::TexMapID;
+    pub const TEXMAP0        : TexMapID = 0;
+    pub const TEXMAP1        : TexMapID = 1;
+    pub const TEXMAP2        : TexMapID = 2;
+    pub const TEXMAP3        : TexMapID = 3;
+    pub const TEXMAP4        : TexMapID = 4;
+    pub const TEXMAP5        : TexMapID = 5;
+    pub const TEXMAP6        : TexMapID = 6;
+    pub const TEXMAP7        : TexMapID = 7;
+    pub const MAX_TEXMAP     : TexMapID = 8;
+    pub const TEXMAP_NULL    : TexMapID = 9;
+    pub const TEXMAP_DISABLE : TexMapID = 10;
+}
+
+// GX/Enums.cs (GXTexGenSrc)
+pub type TexGenSrc = u32;
+pub mod tex_gen_src {
+    use super::TexGenSrc;
+    pub const POS       : TexGenSrc = 0;
+    pub const NRM       : TexGenSrc = 1;
+    pub const BINRM     : TexGenSrc = 2;
+    pub const TANGENT   : TexGenSrc = 3;
+    pub const TEX0      : TexGenSrc = 4;
+    pub const TEX1      : TexGenSrc = 5;
+    pub const TEX2      : TexGenSrc = 6;
+    pub const TEX3      : TexGenSrc = 7;
+    pub const TEX4      : TexGenSrc = 8;
+    pub const TEX5      : TexGenSrc = 9;
+    pub const TEX6      : TexGenSrc = 10;
+    pub const TEX7      : TexGenSrc = 11;
+    pub const TEXCOORD0 : TexGenSrc = 12;
+    pub const TEXCOORD1 : TexGenSrc = 13;
+    pub const TEXCOORD2 : TexGenSrc = 14;
+    pub const TEXCOORD3 : TexGenSrc = 15;
+    pub const TEXCOORD4 : TexGenSrc = 16;
+    pub const TEXCOORD5 : TexGenSrc = 17;
+    pub const TEXCOORD6 : TexGenSrc = 18;
+    pub const COLOR0    : TexGenSrc = 19;
+    pub const COLOR1    : TexGenSrc = 20;
+}
+
+// HSD_TOBJ.cs (TOBJ_FLAGS)
+pub type TOBJFlags = u32;
+pub mod tobj_flags {
+    use super::TOBJFlags;
+    pub const COORD_UV            : TOBJFlags = 0;
+    pub const COORD_REFLECTION    : TOBJFlags = 1;
+    pub const COORD_HILIGHT       : TOBJFlags = 2;
+    pub const COORD_SHADOW        : TOBJFlags = 3;
+    pub const COORD_TOON          : TOBJFlags = 4;
+    pub const COORD_GRADATION     : TOBJFlags = 5;
+    pub const COORD_MASK          : TOBJFlags = 0xF;
+    pub const LIGHTMAP_DIFFUSE    : TOBJFlags = 1 << 4;
+    pub const LIGHTMAP_SPECULAR   : TOBJFlags = 1 << 5;
+    pub const LIGHTMAP_AMBIENT    : TOBJFlags = 1 << 6;
+    pub const LIGHTMAP_EXT        : TOBJFlags = 1 << 7;
+    pub const LIGHTMAP_SHADOW     : TOBJFlags = 1 << 8;
+    pub const COLORMAP_ALPHA_MASK : TOBJFlags = 1 << 16;
+    pub const COLORMAP_RGB_MASK   : TOBJFlags = 2 << 16;
+    pub const COLORMAP_BLEND      : TOBJFlags = 3 << 16;
+    pub const COLORMAP_MODULATE   : TOBJFlags = 4 << 16;
+    pub const COLORMAP_REPLACE    : TOBJFlags = 5 << 16;
+    pub const COLORMAP_PASS       : TOBJFlags = 6 << 16;
+    pub const COLORMAP_ADD        : TOBJFlags = 7 << 16;
+    pub const COLORMAP_SUB        : TOBJFlags = 8 << 16;
+    pub const COLORMAP_MASK       : TOBJFlags = 0xF << 16;
+    pub const ALPHAMAP_ALPHA_MASK : TOBJFlags = 1 << 20;
+    pub const ALPHAMAP_BLEND      : TOBJFlags = 2 << 20;
+    pub const ALPHAMAP_MODULATE   : TOBJFlags = 3 << 20;
+    pub const ALPHAMAP_REPLACE    : TOBJFlags = 4 << 20;
+    pub const ALPHAMAP_PASS       : TOBJFlags = 5 << 20;
+    pub const ALPHAMAP_ADD        : TOBJFlags = 6 << 20;
+    pub const ALPHAMAP_SUB        : TOBJFlags = 7 << 20;
+    pub const ALPHAMAP_MASK       : TOBJFlags = 0xF << 20;
+    pub const BUMP                : TOBJFlags = 1 << 24;
+    pub const MTX_DIRTY           : TOBJFlags = 1 << 31;
 }
 
 // GX/Enums.cs:192 (GXWrapMode)
@@ -80,7 +235,7 @@ impl Default for PhongF32 {
 unsafe impl bytemuck::NoUninit for PhongF32 {}
 
 // GX/Enums.cs:122 (GXTexFmt)
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum InternalTextureFormat {
     I4 = 0,
     I8 = 1,
@@ -106,26 +261,67 @@ impl WrapMode {
     }
 }
 
+/// Decodes the first TOBJ of the material. Use `try_decode_texture_stages` for the whole chain.
 pub fn try_decode_texture<'a>(
     cache: &mut HashMap<*const u8, u16>,
-    textures: &mut Vec<Texture>,
+    textures: &mut Vec<TextureHandle>,
     dobj: DOBJ<'a>
 ) -> Option<u16> {
     let mobj = dobj.get_mobj()?;
     let tobj = mobj.get_tobj()?;
 
-    // There are other tobjs present that are currently unused.
-    // They contain bump maps, lighting maps, etc.
-
     let render_mode = mobj.flags();
     if render_mode & (1 << 24) != 0 { eprintln!("unused z offset") }
+
+    cached_texture_idx(cache, textures, &tobj)
+}
+
+/// Decodes every TOBJ in the material's chain, in order.
+/// TOBJs without an image are skipped.
+/// Returns the number of stages pushed.
+pub fn try_decode_texture_stages<'a>(
+    cache: &mut HashMap<*const u8, u16>,
+    textures: &mut Vec<TextureHandle>,
+    stages: &mut Vec<TextureStage>,
+    dobj: DOBJ<'a>
+) -> usize {
+    let tobj = match dobj.get_mobj().and_then(|mobj| mobj.get_tobj()) {
+        Some(tobj) => tobj,
+        None => return 0,
+    };
+
+    let start = stages.len();
+    for tobj in tobj.siblings() {
+        let texture_idx = match cached_texture_idx(cache, textures, &tobj) {
+            Some(idx) => idx,
+            None => continue,
+        };
+
+        stages.push(TextureStage {
+            texture_idx,
+            tex_map_id: tobj.tex_map_id(),
+            tex_gen_src: tobj.tex_gen_src(),
+            flags: tobj.flags(),
+            blending: tobj.blending(),
+            tev: tobj.tev(),
+        });
+    }
+
+    stages.len() - start
+}
+
+fn cached_texture_idx<'a>(
+    cache: &mut HashMap<*const u8, u16>,
+    textures: &mut Vec<TextureHandle>,
+    tobj: &TOBJ<'a>
+) -> Option<u16> {
     let data_ptr = tobj.image_buffer()?.as_ptr();
 
     use std::collections::hash_map::Entry;
     let id = match cache.entry(data_ptr) {
         Entry::Occupied(entry) => *entry.get(),
         Entry::Vacant(entry) => {
-            let texture = tobj.texture().unwrap();
+            let texture = TextureStore::global().get_or_decode(tobj)?;
             let texture_idx = textures.len() as _;
             textures.push(texture);
             entry.insert(texture_idx);
@@ -158,10 +354,22 @@ impl<'a> TOBJ<'a> {
         self.hsd_struct.try_get_reference(0x4C).map(|t| t.get_buffer(0x00))
     }
 
-    pub fn flags(&self) -> u32 {
+    pub fn tex_map_id(&self) -> TexMapID {
+        self.hsd_struct.get_u32(0x08)
+    }
+
+    pub fn tex_gen_src(&self) -> TexGenSrc {
+        self.hsd_struct.get_u32(0x0C)
+    }
+
+    pub fn flags(&self) -> TOBJFlags {
         self.hsd_struct.get_u32(0x40)
     }
 
+    pub fn blending(&self) -> f32 {
+        self.hsd_struct.get_f32(0x44)
+    }
+
     pub fn format(&self) -> Option<InternalTextureFormat> {
         self.hsd_struct.try_get_reference(0x4C)
             .map(|hsd_image| InternalTextureFormat::new(hsd_image.get_i32(0x08) as u32).unwrap())
@@ -182,7 +390,8 @@ impl<'a> TOBJ<'a> {
         let tlut_data = self.hsd_struct.try_get_reference(0x50)
             .map(TLUT::new);
 
-        let Image { width, height, rgba_data } = decode_image(hsd_image, tlut_data);
+        let mut levels = decode_image_levels(hsd_image, tlut_data).into_vec();
+        let Image { width, height, rgba_data } = levels.remove(0);
 
         Some(Texture {
             width,
@@ -192,8 +401,33 @@ impl<'a> TOBJ<'a> {
             scale_y,
             wrap_u,
             wrap_v,
+            mipmaps: levels.into_boxed_slice(),
+            lod: self.lod(),
         })
     }
+
+    pub fn lod(&self) -> TextureLOD {
+        let mut lod = TextureLOD {
+            mag_filter: self.hsd_struct.get_u32(0x48),
+            ..TextureLOD::default()
+        };
+
+        if let Some(hsd_image) = self.hsd_struct.try_get_reference(0x4C) {
+            lod.min_lod = hsd_image.get_f32(0x10);
+            lod.max_lod = hsd_image.get_f32(0x14);
+        }
+
+        // HSD_TOBJ_LOD
+        if let Some(hsd_lod) = self.hsd_struct.try_get_reference(0x54) {
+            lod.min_filter = hsd_lod.get_u32(0x00);
+            lod.bias = hsd_lod.get_f32(0x04);
+            lod.bias_clamp = hsd_lod.get_u8(0x08) != 0;
+            lod.edge_lod = hsd_lod.get_u8(0x09) != 0;
+            lod.anisotropy = hsd_lod.get_u32(0x0C);
+        }
+
+        lod
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -220,6 +454,42 @@ pub fn decode_image(hsd_image: HSDStruct<'_>, tlut_data: Option<TLUT<'_>>) -> Im
     Image { width, height, rgba_data }
 }
 
+/// Decodes every mip level, starting with the base image.
+/// Images without mipmaps only return the base image.
+pub fn decode_image_levels(hsd_image: HSDStruct<'_>, tlut_data: Option<TLUT<'_>>) -> Box<[Image]> {
+    let data_buffer = hsd_image.get_buffer(0x00);
+    let width = hsd_image.get_i16(0x04) as usize;
+    let height = hsd_image.get_i16(0x06) as usize;
+    let format = InternalTextureFormat::new(hsd_image.get_i32(0x08) as u32).unwrap();
+    let mipmap = hsd_image.get_u16(0x0C) != 0;
+    let max_lod = hsd_image.get_f32(0x14);
+
+    let level_count = if mipmap { max_lod.max(0.0) as usize + 1 } else { 1 };
+
+    let palette = tlut_data.map(|tlut| tlut.palette());
+    let pal_ref = palette.as_deref();
+
+    let mut levels = Vec::with_capacity(level_count);
+    let mut offset = 0;
+    let (mut level_width, mut level_height) = (width, height);
+    for level in 0..level_count {
+        // levels are stored one after another, each padded to whole tiles
+        let size = format.encoded_size(level_width, level_height);
+        if level != 0 && offset + size > data_buffer.len() { break }
+
+        let mut rgba_data = vec![0u32; level_width * level_height].into_boxed_slice();
+        decode_data(format, level_width, level_height, &data_buffer[offset.min(data_buffer.len())..], pal_ref, &mut rgba_data);
+        levels.push(Image { width: level_width, height: level_height, rgba_data });
+
+        if level_width == 1 && level_height == 1 { break }
+        offset += size;
+        level_width = (level_width / 2).max(1);
+        level_height = (level_height / 2).max(1);
+    }
+
+    levels.into_boxed_slice()
+}
+
 pub fn decode_image_preallocated(
     hsd_image: HSDStruct<'_>, 
     tlut_data: Option<TLUT<'_>>,
@@ -258,7 +528,10 @@ pub fn decode_data(format: InternalTextureFormat, width: usize, height: usize, d
             let palette = palette.unwrap();
             decode_ci8_image(data_buffer, &palette, width, height, rgba_data)
         }
-        t => panic!("texture format {:?} unimplemented", t),
+        InternalTextureFormat::CI14X2 => {
+            let palette = palette.unwrap();
+            decode_ci14x2_image(data_buffer, palette, width, height, rgba_data)
+        }
     };
 }
 
@@ -378,7 +651,9 @@ impl<'a> TLUT<'a> {
     }
 }
 
+/// Count is not limited to 256, CI14X2 palettes have up to 16384 colours.
 pub fn decode_palette(count: usize, format: TLUTFormat, data: &[u8]) -> Box<[u32]> {
+    let count = count.min(data.len() / 2);
     let mut palette = Vec::with_capacity(count);
 
     for i in 0..count {
@@ -458,12 +733,33 @@ impl InternalTextureFormat {
         use InternalTextureFormat::*;
         match self {
             CI4 | I4 | CMP => size / 2,
-            IA4 | I8 | CI14X2 | CI8 => size,
-            IA8 | RGB565 | RGB5A3 => size * 2,
+            IA4 | I8 | CI8 => size,
+            IA8 | RGB565 | RGB5A3 | CI14X2 => size * 2,
             RGBA8 => size * 4,
         }
     }
 
+    /// Width and height of the tiles the image data is stored in.
+    pub fn block_dimensions(self) -> (usize, usize) {
+        use InternalTextureFormat::*;
+        match self {
+            I4 | CI4 | CMP => (8, 8),
+            I8 | IA4 | CI8 => (8, 4),
+            IA8 | RGB565 | RGB5A3 | RGBA8 | CI14X2 => (4, 4),
+        }
+    }
+
+    /// Size of the image data padded to whole tiles.
+    pub fn encoded_size(self, width: usize, height: usize) -> usize {
+        let (block_width, block_height) = self.block_dimensions();
+        let blocks = width.div_ceil(block_width) * height.div_ceil(block_height);
+
+        match self {
+            InternalTextureFormat::RGBA8 => blocks * 64,
+            _ => blocks * 32,
+        }
+    }
+
     pub fn is_paletted(self) -> bool {
         match self {
             InternalTextureFormat::I4     => false,
@@ -510,11 +806,12 @@ pub fn decode_rgba8_image(data: &[u8], width: usize, height: usize, rgba_buffer:
                         let pixel = u16::from_be_bytes([data[inp], data[inp+1]]) as u32;
                         inp += 2;
 
-                        if x >= width || y >= height { continue }
+                        if y1 >= height || x1 >= width { continue }
 
                         let a = (pixel >> 8) & 0xff;
                         let b = (pixel >> 0) & 0xff;
-                        let (s1, s2) = ([(16, 24), (8, 0)])[k];
+                        // AR pairs then GB pairs
+                        let (s1, s2) = ([(24, 16), (8, 0)])[k];
                         rgba_buffer[x1 + (y1 * width)] |= convert((a << s1) | (b << s2));
                     }
                 }
@@ -534,7 +831,7 @@ pub fn decode_rgb565_image(data: &[u8], width: usize, height: usize, rgba_buffer
                     let pixel = u16::from_be_bytes([data[inp], data[inp+1]]) as u32;
                     inp += 2;
 
-                    if x >= width || y >= height { continue }
+                    if y1 >= height || x1 >= width { continue }
 
                     let b = (((pixel >> 11) & 0x1f) << 3) & 0xff;
                     let g = (((pixel >> 5) & 0x3f) << 2) & 0xff;
@@ -557,7 +854,7 @@ pub fn decode_rgb5a3_image(data: &[u8], width: usize, height: usize, rgba_buffer
                     let pixel = u16::from_be_bytes([data[inp], data[inp+1]]) as u32;
                     inp += 2;
 
-                    if x >= width || y >= height { continue }
+                    if y1 >= height || x1 >= width { continue }
 
                     let a; let r; let b; let g;
                     // GXImageConverter.cs:601 (DecodeRGBA3)
@@ -694,12 +991,14 @@ pub fn decode_ci4_image(data: &[u8], palette: &[u32], width: usize, height: usiz
                     let pixel = data[i] as usize;
                     i += 1;
 
-                    if y1 >= height || x1+1 >= width {
+                    if y1 >= height || x1 >= width {
                         continue
                     }
 
                     rgba_buffer[y1 * width + x1] = palette[pixel >> 4];
-                    rgba_buffer[y1 * width + x1 + 1] = palette[pixel & 0x0F];
+                    if x1 + 1 < width {
+                        rgba_buffer[y1 * width + x1 + 1] = palette[pixel & 0x0F];
+                    }
                 }
             }
         }
@@ -728,6 +1027,29 @@ pub fn decode_ci8_image(data: &[u8], palette: &[u32], width: usize, height: usiz
     }
 }
 
+// 16 bits per index, top two bits unused.
+// Palettes can have up to 16384 colours.
+pub fn decode_ci14x2_image(data: &[u8], palette: &[u32], width: usize, height: usize, rgba_buffer: &mut [u32]) {
+    let mut i = 0;
+
+    for y in (0..height).step_by(4) {
+        for x in (0..width).step_by(4) {
+            for y1 in y..(y + 4) {
+                for x1 in x..(x + 4) {
+                    let pixel = u16::from_be_bytes([data[i], data[i+1]]) as usize;
+                    i += 2;
+
+                    if y1 >= height || x1 >= width {
+                        continue
+                    }
+
+                    rgba_buffer[y1 * width + x1] = palette.get(pixel & 0x3FFF).copied().unwrap_or(0);
+                }
+            }
+        }
+    }
+}
+
 // GXImageConverter.cs:1245 (fromCMP)
 //
 // only decodes the first mipmap.
@@ -830,3 +1152,53 @@ fn convert(n: u32) -> u32 {
     | ((n & 0x00ff0000) >> 16)
     | ((n & 0x000000ff) << 16)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dat::test_dat::{build_dat, write_u32};
+    use crate::dat::{encode_data, HSDRawFile};
+
+    fn channels(n: u32) -> [u32; 4] {
+        [n & 0xFF, (n >> 8) & 0xFF, (n >> 16) & 0xFF, n >> 24]
+    }
+
+    #[test]
+    fn image_levels_decode_down_to_one_pixel() {
+        use InternalTextureFormat::*;
+
+        for format in [RGBA8, RGB565, RGB5A3] {
+            // 8x8, 4x4, 2x2 and 1x1, one colour per level
+            let colours = [0xFF20C040u32, 0xFF4080F8, 0xFFF84010, 0xFF08F8F8];
+            let mut buffer = Vec::new();
+            for (level, &colour) in colours.iter().enumerate() {
+                let size = 8 >> level;
+                buffer.extend_from_slice(&encode_data(format, size, size, &vec![colour; size * size], None).data);
+            }
+
+            // HSD_Image @0x00, buffer @0x20
+            let mut data = vec![0u8; 0x20];
+            write_u32(&mut data, 0x00, 0x20);
+            data[0x04..0x08].copy_from_slice(&[0, 8, 0, 8]);
+            write_u32(&mut data, 0x08, format as u32);
+            data[0x0C..0x0E].copy_from_slice(&1u16.to_be_bytes());
+            data[0x14..0x18].copy_from_slice(&3.0f32.to_be_bytes());
+            data.extend_from_slice(&buffer);
+
+            let dat = build_dat(&data, &[0x00], &[(0x00, "image")], &[]);
+            let hsd = HSDRawFile::new(&dat);
+            let levels = decode_image_levels(hsd.roots[0].hsd_struct.clone(), None);
+
+            assert_eq!(levels.len(), 4, "{:?}", format);
+            for (level, (image, &colour)) in levels.iter().zip(colours.iter()).enumerate() {
+                let size = 8 >> level;
+                assert_eq!((image.width, image.height), (size, size), "{:?}", format);
+                for &pixel in image.rgba_data.iter() {
+                    for (a, b) in channels(pixel).into_iter().zip(channels(colour)) {
+                        assert!(a.abs_diff(b) <= 8, "{:?} level {}: {:08x} for {:08x}", format, level, pixel, colour);
+                    }
+                }
+            }
+        }
+    }
+}
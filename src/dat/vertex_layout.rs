@@ -0,0 +1,223 @@
+use crate::dat::{Model, VertexAttributes};
+use half::f16;
+
+/// What a vertex attribute holds.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum VertexSemantic {
+    Position,
+    Normal,
+    Binormal,
+    Tangent,
+
+    /// CLR0 or CLR1
+    Colour(u8),
+
+    /// TEX0 to TEX7
+    UV(u8),
+
+    /// Influences starting at this index, one per component.
+    /// Split six influences over two attributes, e.g. `Weights(0)` and `Weights(3)` as `Float32x3`.
+    Weights(u8),
+    Bones(u8),
+}
+
+/// Same names and sizes as wgpu's `VertexFormat`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum VertexFormat {
+    Uint8x4,
+    Unorm8x4,
+    Snorm8x4,
+    Uint16x2,
+    Uint16x4,
+    Unorm16x2,
+    Unorm16x4,
+    Float16x2,
+    Float16x4,
+    Uint32,
+    Uint32x2,
+    Uint32x3,
+    Uint32x4,
+    Float32,
+    Float32x2,
+    Float32x3,
+    Float32x4,
+}
+
+impl VertexFormat {
+    pub fn components(self) -> usize {
+        use VertexFormat::*;
+        match self {
+            Uint32 | Float32 => 1,
+            Uint16x2 | Unorm16x2 | Float16x2 | Uint32x2 | Float32x2 => 2,
+            Uint32x3 | Float32x3 => 3,
+            Uint8x4 | Unorm8x4 | Snorm8x4 | Uint16x4 | Unorm16x4
+                | Float16x4 | Uint32x4 | Float32x4 => 4,
+        }
+    }
+
+    pub fn component_size(self) -> usize {
+        use VertexFormat::*;
+        match self {
+            Uint8x4 | Unorm8x4 | Snorm8x4 => 1,
+            Uint16x2 | Uint16x4 | Unorm16x2 | Unorm16x4 | Float16x2 | Float16x4 => 2,
+            Uint32 | Uint32x2 | Uint32x3 | Uint32x4
+                | Float32 | Float32x2 | Float32x3 | Float32x4 => 4,
+        }
+    }
+
+    /// Always a multiple of 4.
+    pub fn size(self) -> usize {
+        self.components() * self.component_size()
+    }
+
+    fn write(self, values: &[f32], out: &mut [u8]) {
+        use VertexFormat::*;
+        for (i, &v) in values.iter().take(self.components()).enumerate() {
+            match self {
+                Uint8x4 => out[i] = v as u8,
+                Unorm8x4 => out[i] = (v.clamp(0.0, 1.0) * 255.0).round() as u8,
+                Snorm8x4 => out[i] = (v.clamp(-1.0, 1.0) * 127.0).round() as i8 as u8,
+                Uint16x2 | Uint16x4 => out[i*2..i*2+2].copy_from_slice(&(v as u16).to_ne_bytes()),
+                Unorm16x2 | Unorm16x4 => {
+                    let v = (v.clamp(0.0, 1.0) * 65535.0).round() as u16;
+                    out[i*2..i*2+2].copy_from_slice(&v.to_ne_bytes())
+                }
+                Float16x2 | Float16x4 => out[i*2..i*2+2].copy_from_slice(&f16::from_f32(v).to_ne_bytes()),
+                Uint32 | Uint32x2 | Uint32x3 | Uint32x4 => out[i*4..i*4+4].copy_from_slice(&(v as u32).to_ne_bytes()),
+                Float32 | Float32x2 | Float32x3 | Float32x4 => out[i*4..i*4+4].copy_from_slice(&v.to_ne_bytes()),
+            }
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct VertexAttributeDesc {
+    pub semantic: VertexSemantic,
+    pub format: VertexFormat,
+    pub offset: usize,
+}
+
+/// Describes how `Model::pack_vertices` lays out each vertex.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct VertexLayout {
+    pub attributes: Box<[VertexAttributeDesc]>,
+    pub stride: usize,
+
+    /// Number of bone influences kept.
+    /// If less than six, the largest weights are kept and renormalized.
+    pub influences: usize,
+}
+
+impl VertexLayout {
+    /// Attributes are packed in order with no padding.
+    pub fn new(attributes: &[(VertexSemantic, VertexFormat)]) -> Self {
+        let mut offset = 0;
+        let mut influences = 0;
+        let attributes = attributes.iter().map(|&(semantic, format)| {
+            if let VertexSemantic::Weights(first) | VertexSemantic::Bones(first) = semantic {
+                influences = influences.max(first as usize + format.components()).min(6);
+            }
+
+            let attribute = VertexAttributeDesc { semantic, format, offset };
+            offset += format.size();
+            attribute
+        }).collect();
+
+        VertexLayout { attributes, stride: offset, influences }
+    }
+
+    /// Same layout as `Vertex`, 96 bytes.
+    pub fn full() -> Self {
+        use VertexSemantic::*;
+        use VertexFormat::*;
+        VertexLayout::new(&[
+            (Position, Float32x3),
+            (UV(0), Float32x2),
+            (Normal, Float32x3),
+            (Weights(0), Float32x3),
+            (Weights(3), Float32x3),
+            (Bones(0), Uint32x3),
+            (Bones(3), Uint32x3),
+            (Colour(0), Float32x4),
+        ])
+    }
+
+    /// Four influences and reduced precision, 32 bytes.
+    /// Bone indices are limited to 255.
+    pub fn compact() -> Self {
+        use VertexSemantic::*;
+        use VertexFormat::*;
+        VertexLayout::new(&[
+            (Position, Float32x3),
+            (Normal, Snorm8x4),
+            (UV(0), Float16x2),
+            (Colour(0), Unorm8x4),
+            (Weights(0), Unorm8x4),
+            (Bones(0), Uint8x4),
+        ])
+    }
+
+    /// `out` must be at least `stride` bytes.
+    pub fn write_vertex(&self, v: &VertexAttributes, out: &mut [u8]) {
+        let (weights, bones) = influences(v, self.influences);
+
+        for attr in self.attributes.iter() {
+            let mut values = [0f32; 4];
+            match attr.semantic {
+                VertexSemantic::Position => values[..3].copy_from_slice(&v.pos.to_array()),
+                VertexSemantic::Normal => values[..3].copy_from_slice(&v.normal.to_array()),
+                VertexSemantic::Binormal => values[..3].copy_from_slice(&v.binormal.to_array()),
+                VertexSemantic::Tangent => values[..3].copy_from_slice(&v.tangent.to_array()),
+                VertexSemantic::Colour(n) => if let Some(c) = v.colours.get(n as usize) {
+                    values = c.to_array();
+                },
+                VertexSemantic::UV(n) => if let Some(uv) = v.uvs.get(n as usize) {
+                    values[..2].copy_from_slice(&uv.to_array());
+                },
+                VertexSemantic::Weights(first) => for (i, value) in values.iter_mut().enumerate() {
+                    *value = weights.get(first as usize + i).copied().unwrap_or(0.0);
+                },
+                VertexSemantic::Bones(first) => for (i, value) in values.iter_mut().enumerate() {
+                    *value = bones.get(first as usize + i).copied().unwrap_or(0) as f32;
+                },
+            }
+
+            attr.format.write(&values, &mut out[attr.offset..attr.offset + attr.format.size()]);
+        }
+    }
+}
+
+// keeps the largest `count` weights, in descending order
+pub(crate) fn influences(v: &VertexAttributes, count: usize) -> ([f32; 6], [u32; 6]) {
+    if count >= 6 { return (v.weights, v.bones) }
+
+    let mut order = [0, 1, 2, 3, 4, 5];
+    order.sort_by(|&a, &b| v.weights[b].total_cmp(&v.weights[a]));
+
+    let mut weights = [0f32; 6];
+    let mut bones = [0u32; 6];
+    for (i, &o) in order.iter().take(count).enumerate() {
+        weights[i] = v.weights[o];
+        bones[i] = v.bones[o];
+    }
+
+    let sum: f32 = weights.iter().sum();
+    if sum > 0.0 {
+        for w in weights.iter_mut() { *w /= sum; }
+    }
+
+    (weights, bones)
+}
+
+impl Model {
+    /// Packs `vertex_attributes` with the layout, `layout.stride` bytes per vertex.
+    pub fn pack_vertices(&self, layout: &VertexLayout) -> Vec<u8> {
+        let mut data = vec![0u8; self.vertex_attributes.len() * layout.stride];
+        if layout.stride == 0 { return data }
+
+        for (v, out) in self.vertex_attributes.iter().zip(data.chunks_exact_mut(layout.stride)) {
+            layout.write_vertex(v, out);
+        }
+        data
+    }
+}
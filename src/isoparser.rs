@@ -1,11 +1,16 @@
 use std::io::{Write, Read, Seek, SeekFrom, self};
 use std::fs::File;
 use std::collections::HashMap;
-use crate::dat::DatFile;
+use crate::dat::{DatFile, HSDRawFile};
 use std::rc::Rc;
 
+const OFFSET_DOL_OFFSET: u64 = 0x420;
 const OFFSET_FST_OFFSET: u64 = 0x424;
 
+/// Size of a full GameCube disc image.
+const GC_DISC_SIZE: u64 = 1_459_978_240;
+const FILE_ALIGNMENT: u64 = 0x20;
+
 #[derive(Debug)]
 pub enum ISOParseError {
     FileNotFound,
@@ -26,6 +31,9 @@ pub struct ISODatFiles {
     pub iso: File,
     pub files: HashMap<Box<str>, DatFileLocation>,
     pub open_files: HashMap<DatFileLocation, DatFile>,
+
+    /// root symbol -> filename. Filled on the first call to `find_symbol`.
+    pub symbol_index: HashMap<Box<str>, Box<str>>,
 }
 
 impl ISODatFiles {
@@ -45,6 +53,7 @@ impl ISODatFiles {
             iso: rawiso,
             files: iso_dat_files,
             open_files: HashMap::new(),
+            symbol_index: HashMap::new(),
         })
     }
 
@@ -77,37 +86,222 @@ impl ISODatFiles {
         Ok(dat)
     }
 
+    /// Returns the name of the file that defines the root symbol.
+    /// The root tables of every dat file are indexed on the first call.
+    pub fn find_symbol(&mut self, symbol: &str) -> Result<Option<Box<str>>, ISOParseError> {
+        if self.symbol_index.is_empty() {
+            self.index_symbols()?;
+        }
+
+        Ok(self.symbol_index.get(symbol).cloned())
+    }
+
+    /// Reads the dat files that define the external references of `file`.
+    /// Pass these to a `DatLinker` to resolve the references.
+    /// Symbols that are not defined by any file are skipped.
+    pub fn read_reference_files(&mut self, file: &HSDRawFile) -> Result<Vec<DatFile>, ISOParseError> {
+        let mut filenames: Vec<Box<str>> = Vec::new();
+        for reference in file.references.iter() {
+            if let Some(filename) = self.find_symbol(reference.root_string)? {
+                if !filenames.contains(&filename) {
+                    filenames.push(filename);
+                }
+            }
+        }
+
+        filenames.iter()
+            .map(|filename| self.read_file(filename))
+            .collect()
+    }
+
+    /// Names of every .dat and .usd file, in disc order.
+    pub fn dat_filenames(&self) -> Vec<Box<str>> {
+        self.dat_file_locations().into_iter()
+            .map(|(name, _)| name)
+            .collect()
+    }
+
+    /// Drops the cached contents of a file read with `read_file`.
+    /// `DatFile`s that were already returned stay valid.
+    pub fn close_file(&mut self, name: &str) {
+        if let Some(location) = self.find_file(name) {
+            self.open_files.remove(&location);
+        }
+    }
+
+    fn dat_file_locations(&self) -> Vec<(Box<str>, DatFileLocation)> {
+        let mut dat_files: Vec<(Box<str>, DatFileLocation)> = self.files.iter()
+            .filter(|(name, _)| {
+                let name = name.to_ascii_lowercase();
+                name.ends_with(".dat") || name.ends_with(".usd")
+            })
+            .map(|(name, loc)| (name.clone(), *loc))
+            .collect();
+
+        // read in disc order
+        dat_files.sort_by_key(|(_, loc)| loc.start_offset);
+        dat_files
+    }
+
+    fn index_symbols(&mut self) -> Result<(), ISOParseError> {
+        for (filename, location) in self.dat_file_locations() {
+            for symbol in self.read_root_symbols(location)? {
+                self.symbol_index.entry(symbol).or_insert_with(|| filename.clone());
+            }
+        }
+
+        Ok(())
+    }
+
+    // only reads the header and the root and string tables, not the whole file
+    fn read_root_symbols(&mut self, location: DatFileLocation) -> Result<Vec<Box<str>>, ISOParseError> {
+        if location.size < 0x20 { return Ok(Vec::new()) }
+
+        let mut header = [0u8; 0x20];
+        self.iso.seek(SeekFrom::Start(location.start_offset)).map_err(|_| ISOParseError::InvalidISO)?;
+        self.iso.read_exact(&mut header).map_err(|_| ISOParseError::InvalidISO)?;
+        let word = |i: usize| u32::from_be_bytes(header[i*4..i*4+4].try_into().unwrap()) as usize;
+
+        let fsize = word(0);
+        let reloc_offset = word(1) + 0x20;
+        let reloc_count = word(2);
+        let root_count = word(3);
+        let ref_count = word(4);
+        let table_start = reloc_offset + reloc_count * 4;
+        let string_start = table_start + (root_count + ref_count) * 8;
+
+        // not a dat file
+        if fsize > location.size || string_start > fsize { return Ok(Vec::new()) }
+
+        let mut tables = vec![0u8; fsize - table_start];
+        self.iso.seek(SeekFrom::Start(location.start_offset + table_start as u64)).map_err(|_| ISOParseError::InvalidISO)?;
+        self.iso.read_exact(&mut tables).map_err(|_| ISOParseError::InvalidISO)?;
+
+        let strings = &tables[string_start - table_start..];
+        let mut symbols = Vec::with_capacity(root_count);
+        for i in 0..root_count {
+            let string_offset = u32::from_be_bytes(tables[i*8+4..i*8+8].try_into().unwrap()) as usize;
+            if let Some(symbol) = strings.get(string_offset..).and_then(crate::parse_string) {
+                symbols.push(symbol.into());
+            }
+        }
+
+        Ok(symbols)
+    }
+
     pub fn extract_file(&mut self, name: &str, save_path: &std::path::Path) -> Result<(), io::Error> {
         let dat = self.read_file(name).map_err(<ISOParseError as Into<io::Error>>::into)?;
         std::fs::write(save_path, dat.data)
     }
 
+    /// Files that do not fit in their current location are moved to free space on the disc.
     pub fn write_file(&mut self, file: &str, source: Rc<[u8]>) -> Result<(), ISOParseError> {
-        let mut dst = self.files[file];
+        let src = self.find_file(file).ok_or(ISOParseError::FileNotFound)?;
+        let mut dst = src;
 
-        if dst.size > source.len() {
-            return Err(ISOParseError::ReplacementFileTooLarge);
+        if source.len() > self.file_capacity(src)? {
+            dst.start_offset = self.find_free_space(source.len())?
+                .ok_or(ISOParseError::ReplacementFileTooLarge)?;
         }
 
-        //if self.iso.options.G
-
         dst.size = source.len();
 
-        if let Some(f) = self.open_files.get_mut(&dst) {
+        if let Some(mut f) = self.open_files.remove(&src) {
             f.data = source.clone();
+            self.open_files.insert(dst, f);
+        }
+        if let Some(loc) = self.files.get_mut(file) {
+            *loc = dst;
         }
 
-        // write data
         self.iso.seek(SeekFrom::Start(dst.start_offset)).map_err(|_| ISOParseError::InvalidISO)?;
         self.iso.write_all(&source).map_err(|e| ISOParseError::WriteError(e))?;
 
-        // write file size in header
-        self.iso.seek(SeekFrom::Start(dst.header_offset + 0x8)).map_err(|_| ISOParseError::InvalidISO)?;
+        self.iso.seek(SeekFrom::Start(dst.header_offset + 0x4)).map_err(|_| ISOParseError::InvalidISO)?;
+        let file_offset = (dst.start_offset as u32).to_be_bytes();
+        self.iso.write_all(&file_offset).map_err(ISOParseError::WriteError)?;
         let file_size = (source.len() as u32).to_be_bytes();
         self.iso.write_all(&file_size).map_err(|e| ISOParseError::WriteError(e))?;
 
         Ok(())
     }
+
+    /// Bytes available at the file's location before the next file.
+    fn file_capacity(&mut self, location: DatFileLocation) -> Result<usize, ISOParseError> {
+        let end = self.occupied_ranges()?.iter()
+            .map(|&(start, _)| start)
+            .filter(|&start| start > location.start_offset)
+            .min()
+            .unwrap_or(GC_DISC_SIZE);
+
+        Ok((end - location.start_offset) as usize)
+    }
+
+    /// Returns the offset of the first unused region of the disc that fits the size.
+    fn find_free_space(&mut self, size: usize) -> Result<Option<u64>, ISOParseError> {
+        let ranges = self.occupied_ranges()?;
+
+        let mut gap_start: u64 = 0;
+        for &(start, end) in ranges.iter().chain(std::iter::once(&(GC_DISC_SIZE, GC_DISC_SIZE))) {
+            let aligned = gap_start.next_multiple_of(FILE_ALIGNMENT);
+            if start >= aligned && start - aligned >= size as u64 {
+                return Ok(Some(aligned));
+            }
+            gap_start = gap_start.max(end);
+        }
+
+        Ok(None)
+    }
+
+    /// Sorted (start, end) ranges of the system area, dol, fst and every file in the fst.
+    /// Fails if the fst can't be fully read.
+    fn occupied_ranges(&mut self) -> Result<Vec<(u64, u64)>, ISOParseError> {
+        self.iso.seek(SeekFrom::Start(OFFSET_DOL_OFFSET)).map_err(|_| ISOParseError::InvalidISO)?;
+        let dol_offset = read_u32(&mut self.iso)? as u64;
+        let fst_offset = read_u32(&mut self.iso)? as u64;
+        let fst_size = read_u32(&mut self.iso)? as u64;
+
+        // DOL header: 7 text and 11 data section offsets, followed by their addresses then sizes
+        let mut dol_header = [0u8; 0xD8];
+        self.iso.seek(SeekFrom::Start(dol_offset)).map_err(|_| ISOParseError::InvalidISO)?;
+        self.iso.read_exact(&mut dol_header).map_err(|_| ISOParseError::InvalidISO)?;
+        let word = |i: usize| u32::from_be_bytes(dol_header[i*4..i*4+4].try_into().unwrap()) as u64;
+        let dol_size = (0..18)
+            .map(|i| word(i) + word(0x24 + i))
+            .max()
+            .unwrap_or(0)
+            .max(0x100);
+
+        let mut ranges = vec![
+            (0, fst_offset + fst_size),
+            (dol_offset, dol_offset + dol_size),
+        ];
+
+        // Every file entry of the fst, not `self.files`, which skips folders and merges files
+        // with the same name. If any entry can't be read, nothing can be safely moved.
+        let mut fst = vec![0u8; fst_size as usize];
+        self.iso.seek(SeekFrom::Start(fst_offset)).map_err(|_| ISOParseError::InvalidISO)?;
+        self.iso.read_exact(&mut fst).map_err(|_| ISOParseError::InvalidISO)?;
+        let entry_word = |i: usize, w: usize| fst.get(i*0xC + w*4..i*0xC + w*4 + 4)
+            .map(|b| u32::from_be_bytes(b.try_into().unwrap()) as u64);
+
+        let entry_count = entry_word(0, 2).ok_or(ISOParseError::InvalidISO)? as usize;
+        if entry_count == 0 || entry_count * 0xC > fst.len() { return Err(ISOParseError::InvalidISO) }
+
+        for i in 1..entry_count {
+            let is_folder = fst[i * 0xC] == 1;
+            if is_folder { continue }
+
+            let start = entry_word(i, 1).ok_or(ISOParseError::InvalidISO)?;
+            let end = start + entry_word(i, 2).ok_or(ISOParseError::InvalidISO)?;
+            if end > GC_DISC_SIZE { return Err(ISOParseError::InvalidISO) }
+            ranges.push((start, end));
+        }
+
+        ranges.sort_unstable();
+
+        Ok(ranges)
+    }
 }
 
 fn read_files(
@@ -196,3 +390,36 @@ impl std::ops::Drop for ISODatFiles {
         self.iso.flush().unwrap();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_iso::{TestIso, FIRST_FILE_OFFSET};
+
+    #[test]
+    fn write_file_moves_grown_file() {
+        let a = [0xAAu8; 0x40];
+        let b = [0xBBu8; 0x40];
+        let test_iso = TestIso::new("write_file", &[("a.dat", &a), ("b.dat", &b)]);
+
+        let mut files = ISODatFiles::new(test_iso.open()).unwrap();
+
+        // b.dat is right after a.dat, so this has to move to the gap after the fst
+        let grown: Rc<[u8]> = (0..0x60u8).collect::<Vec<u8>>().into();
+        let free = files.find_free_space(grown.len()).unwrap().unwrap();
+        assert!(free < FIRST_FILE_OFFSET as u64 && free.is_multiple_of(FILE_ALIGNMENT));
+
+        files.write_file("a.dat", grown.clone()).unwrap();
+        assert_eq!(&*files.read_file("a.dat").unwrap().data, &*grown);
+        drop(files);
+
+        assert_eq!(test_iso.fst_entry(0), (free as usize, grown.len()));
+        assert_eq!(test_iso.read(free as usize, grown.len()), &*grown);
+        assert_eq!(test_iso.fst_entry(1), (FIRST_FILE_OFFSET + 0x40, b.len()));
+        assert_eq!(test_iso.read(FIRST_FILE_OFFSET + 0x40, b.len()), b);
+
+        let mut files = ISODatFiles::new(test_iso.open()).unwrap();
+        assert_eq!(&*files.read_file("a.dat").unwrap().data, &*grown);
+        assert_eq!(&*files.read_file("b.dat").unwrap().data, &b);
+    }
+}
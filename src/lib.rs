@@ -5,6 +5,9 @@
 pub mod dat;
 pub mod isoparser;
 
+#[cfg(test)]
+mod test_iso;
+
 use dat::FighterData;
 use isoparser::{ISOParseError, ISODatFiles};
 use slp_parser::{Stage, Character, CharacterColour, character_colours::*};
@@ -37,6 +40,146 @@ pub fn get_fighter_data(
         .ok_or(ISOParseError::InvalidISO)
 }
 
+/// Replaces animations in PlXxAJ.dat by root name and updates the action table in PlXx.dat.
+/// See `dat::rebuild_animation_archive`.
+pub fn replace_fighter_animations(
+    files: &mut ISODatFiles,
+    character: Character,
+    animations: &[dat::DatFile],
+) -> Result<(), ISOParseError> {
+    let data_filename = character_data_filename(character);
+    let anim_filename = character_animation_filename(character);
+
+    let base_dat = files.read_file(data_filename)?;
+    let anim_dat = files.read_file(anim_filename)?;
+
+    let rebuilt = dat::rebuild_animation_archive(&base_dat, &anim_dat, animations)
+        .map_err(|_| ISOParseError::InvalidISO)?;
+
+    files.write_file(anim_filename, rebuilt.anim_dat.data)?;
+    files.write_file(data_filename, rebuilt.fighter_dat.data)?;
+
+    Ok(())
+}
+
+/// Replaces a texture in a dat file on the ISO. See `dat::replace_texture`.
+pub fn replace_dat_texture(
+    files: &mut ISODatFiles,
+    filename: &str,
+    target: dat::TextureTarget,
+    image: &dat::Image,
+    format: Option<dat::InternalTextureFormat>,
+) -> Result<(), ISOParseError> {
+    let dat = files.read_file(filename)?;
+    let new_dat = dat::replace_texture(&dat, target, image, format)
+        .map_err(|_| ISOParseError::InvalidISO)?;
+    files.write_file(filename, new_dat.data)
+}
+
+/// Returned by `extract_all_textures` and `dump_dolphin_textures`.
+#[cfg(feature = "png")]
+#[derive(Clone, Debug, Default)]
+pub struct TextureDump {
+    /// Number of textures saved.
+    pub saved: usize,
+
+    /// Files on the ISO that failed `dat::DatFile::check_header` and were skipped.
+    pub skipped: Vec<Box<str>>,
+}
+
+/// Saves every texture in every dat file on the ISO as `{dat name}_{TOBJ offset:08X}.png`.
+/// The offset can be passed to `replace_dat_texture` as `TextureTarget::TOBJOffset`.
+/// TOBJs that share an image are only saved once.
+#[cfg(feature = "png")]
+pub fn extract_all_textures<P: AsRef<std::path::Path>>(
+    files: &mut ISODatFiles,
+    out_dir: P,
+) -> std::io::Result<TextureDump> {
+    let out_dir = out_dir.as_ref();
+    std::fs::create_dir_all(out_dir)?;
+
+    let mut saved = 0;
+    let mut saved_images = std::collections::HashSet::new();
+    let skipped = for_each_iso_tobj(files, |dat_name, offset, tobj| {
+        let image_ptr = match tobj.image_buffer() {
+            Some(image) => image.as_ptr(),
+            None => return Ok(()),
+        };
+        if !saved_images.insert((dat_name.to_string(), image_ptr)) { return Ok(()) }
+
+        if let Some(texture) = tobj.texture() {
+            texture.save_png(out_dir.join(format!("{}_{:08X}.png", dat_name, offset)))?;
+            saved += 1;
+        }
+
+        Ok(())
+    })?;
+
+    Ok(TextureDump { saved, skipped })
+}
+
+/// Saves every texture in every dat file on the ISO under Dolphin's custom texture names.
+/// See `dat::DolphinTextureID`. Mip levels are saved as `{name}_mip{level}.png`.
+/// Put the files in Dolphin's `Load/Textures/GALE01` folder to build a texture pack.
+#[cfg(feature = "png")]
+pub fn dump_dolphin_textures<P: AsRef<std::path::Path>>(
+    files: &mut ISODatFiles,
+    out_dir: P,
+) -> std::io::Result<TextureDump> {
+    let out_dir = out_dir.as_ref();
+    std::fs::create_dir_all(out_dir)?;
+
+    let mut saved = std::collections::HashSet::new();
+    let skipped = for_each_iso_tobj(files, |_, _, tobj| {
+        let id = match tobj.dolphin_texture_id() {
+            Some(id) => id,
+            None => return Ok(()),
+        };
+        if saved.contains(&id) { return Ok(()) }
+
+        if let Some(texture) = tobj.texture() {
+            texture.save_png(out_dir.join(format!("{}.png", id)))?;
+            for (i, mip) in texture.mipmaps.iter().enumerate() {
+                mip.save_png(out_dir.join(format!("{}.png", id.mip_name(i + 1))))?;
+            }
+            saved.insert(id);
+        }
+
+        Ok(())
+    })?;
+
+    Ok(TextureDump { saved: saved.len(), skipped })
+}
+
+/// Calls `f` with the dat name (without extension), offset and TOBJ of every TOBJ on the ISO.
+/// Returns the names of files skipped because they failed `dat::DatFile::check_header`.
+#[cfg(feature = "png")]
+fn for_each_iso_tobj(
+    files: &mut ISODatFiles,
+    mut f: impl FnMut(&str, usize, &dat::TOBJ<'_>) -> std::io::Result<()>,
+) -> std::io::Result<Vec<Box<str>>> {
+    let mut skipped = Vec::new();
+    for filename in files.dat_filenames() {
+        let dat = files.read_file(&filename).map_err(<ISOParseError as Into<std::io::Error>>::into)?;
+        files.close_file(&filename);
+
+        let hsd_file = match dat::HSDRawFile::try_new(&dat) {
+            Ok(hsd_file) => hsd_file,
+            Err(_) => {
+                skipped.push(filename);
+                continue;
+            }
+        };
+        let dat_name = filename.rsplit_once('.').map(|(name, _)| name).unwrap_or(&filename);
+
+        for (offset, tobj) in dat::find_all_tobjs(&dat, &hsd_file) {
+            f(dat_name, offset, &tobj)?;
+        }
+    }
+
+    Ok(skipped)
+}
+
 pub fn get_common_model(files: &mut ISODatFiles, model_idx: usize) -> Option<dat::Model> {
     let dat = files.read_file("EfCoData.dat").unwrap();
     let hsd_ef_dat = dat::HSDRawFile::new(&dat);
@@ -68,11 +211,33 @@ pub fn get_common_models_and_animations(files: &mut ISODatFiles) -> Box<[(dat::M
 // only extracts 24x24 icons (skips master hand, giga bowser)
 // all stock icons are in CI4 format
 pub fn extract_stock_icons(files: &mut ISODatFiles) -> Option<Box<[[u32; 24*24]]>> {
-    let mut icons: Vec<[u32; 24*24]> = Vec::with_capacity(128);
+    let dat = files.read_file("IfAll.dat").ok()?;
+    let hsd_if_dat = dat::HSDRawFile::new(&dat);
+
+    let icons = stock_icon_images(&hsd_if_dat).into_iter()
+        .map(|(hsd_image, tlut)| {
+            let mut icon = [0u32; 24*24];
+            dat::decode_image_preallocated(hsd_image, tlut, &mut icon);
+            icon
+        })
+        .collect();
 
+    Some(icons)
+}
+
+/// Same order as `extract_stock_icons`, with the palettes kept separate. Stock icons are CI4.
+pub fn extract_stock_icons_indexed(files: &mut ISODatFiles) -> Option<Box<[dat::IndexedImage]>> {
     let dat = files.read_file("IfAll.dat").ok()?;
     let hsd_if_dat = dat::HSDRawFile::new(&dat);
 
+    stock_icon_images(&hsd_if_dat).into_iter()
+        .map(|(hsd_image, tlut)| dat::decode_indexed_image(hsd_image, tlut?))
+        .collect()
+}
+
+fn stock_icon_images<'a>(hsd_if_dat: &dat::HSDRawFile<'a>) -> Vec<(dat::HSDStruct<'a>, Option<dat::TLUT<'a>>)> {
+    let mut images = Vec::with_capacity(128);
+
     // root is HSD_SOBJ
     let root = hsd_if_dat.roots.iter()
         .find(|r| r.root_string == "Stc_scemdls")
@@ -97,14 +262,12 @@ pub fn extract_stock_icons(files: &mut ISODatFiles) -> Option<Box<[[u32; 24*24]]
             if width == 24 {
                 let tlut = tlut_buffers.try_get_reference(i * 4)
                     .map(dat::TLUT::new);
-                icons.push([0u32; 24*24]);
-                let icon: &mut [u32] = icons.last_mut().unwrap();
-                dat::decode_image_preallocated(hsd_image, tlut, icon);
+                images.push((hsd_image, tlut));
             }
         }
     }
 
-    Some(icons.into_boxed_slice())
+    images
 }
 
 /// first 10 are numbers 0 to 9 (32 x 36).
@@ -639,3 +802,68 @@ pub const fn character_stock_icon_index(character: CharacterColour) -> u16 {
         Ganondorf     (GanondorfColour     ::Lavender ) => 124,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dat::test_dat::{build_dat, write_u32};
+    use crate::test_iso::{TestIso, FIRST_FILE_OFFSET};
+
+    #[test]
+    fn replace_fighter_animations_moves_grown_archive() {
+        // root @0x00 -> action table @0x20, two 0x18 byte actions named by the strings @0x50 and @0x60
+        let mut data = vec![0u8; 0x70];
+        write_u32(&mut data, 0x0C, 0x20);
+        write_u32(&mut data, 0x20, 0x50);
+        write_u32(&mut data, 0x24, 0x00);
+        write_u32(&mut data, 0x28, 0x20);
+        write_u32(&mut data, 0x38, 0x60);
+        write_u32(&mut data, 0x3C, 0x20);
+        write_u32(&mut data, 0x40, 0x20);
+        data[0x50..0x57].copy_from_slice(b"Wait1_a");
+        data[0x60..0x67].copy_from_slice(b"Walk1_a");
+        let fighter_dat = build_dat(&data, &[0x0C, 0x20, 0x38], &[(0, "ftDataMario")], &[]);
+
+        let mut anim_dat = vec![0x11u8; 0x20];
+        anim_dat.extend_from_slice(&[0x22u8; 0x20]);
+
+        let replacement = build_dat(&[0x33u8; 0x40], &[], &[(0, "Wait1_a")], &[]);
+
+        let data_filename = character_data_filename(Character::Mario);
+        let anim_filename = character_animation_filename(Character::Mario);
+        let test_iso = TestIso::new("replace_fighter_animations", &[
+            (anim_filename, &anim_dat),
+            (data_filename, &fighter_dat.data),
+        ]);
+
+        let mut files = ISODatFiles::new(test_iso.open()).unwrap();
+        replace_fighter_animations(&mut files, Character::Mario, std::slice::from_ref(&replacement)).unwrap();
+        drop(files);
+
+        // the archive grew past the fighter dat, the fighter dat kept its size and slot
+        let (anim_offset, anim_size) = test_iso.fst_entry(0);
+        assert_ne!(anim_offset, FIRST_FILE_OFFSET);
+        let wait_size = replacement.data.len();
+        assert_eq!(anim_size, wait_size.next_multiple_of(0x20) + 0x20);
+        assert_eq!(test_iso.fst_entry(1), (FIRST_FILE_OFFSET + 0x40, fighter_dat.data.len()));
+
+        let mut files = ISODatFiles::new(test_iso.open()).unwrap();
+        let new_anim = files.read_file(anim_filename).unwrap();
+        let new_fighter = files.read_file(data_filename).unwrap();
+
+        let action_table = 0x20 + 0x20;
+        let word = |o: usize| u32::from_be_bytes(new_fighter.data[o..o + 4].try_into().unwrap()) as usize;
+        assert_eq!((word(action_table + 0x04), word(action_table + 0x08)), (0, wait_size));
+        let walk_offset = wait_size.next_multiple_of(0x20);
+        assert_eq!((word(action_table + 0x1C), word(action_table + 0x20)), (walk_offset, 0x20));
+
+        assert_eq!(&new_anim.data[..wait_size], &*replacement.data);
+        assert_eq!(&new_anim.data[walk_offset..walk_offset + 0x20], &anim_dat[0x20..]);
+
+        // everything else in the fighter dat is unchanged
+        let mut expected = fighter_dat.data.to_vec();
+        expected[action_table + 0x04..action_table + 0x0C].copy_from_slice(&new_fighter.data[action_table + 0x04..action_table + 0x0C]);
+        expected[action_table + 0x1C..action_table + 0x24].copy_from_slice(&new_fighter.data[action_table + 0x1C..action_table + 0x24]);
+        assert_eq!(&*new_fighter.data, &expected[..]);
+    }
+}
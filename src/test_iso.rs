@@ -0,0 +1,76 @@
+use std::fs::File;
+use std::path::PathBuf;
+
+const DOL_OFFSET: usize = 0x2000;
+const FST_OFFSET: usize = 0x2400;
+pub(crate) const FIRST_FILE_OFFSET: usize = 0x3000;
+
+/// A disc image in the temp directory, deleted on drop.
+pub(crate) struct TestIso {
+    pub path: PathBuf,
+}
+
+impl TestIso {
+    /// Builds a disc with an empty dol and a root folder holding `files`.
+    /// Files are stored in order from `FIRST_FILE_OFFSET`, each padded to 0x20 bytes,
+    /// so a file can't grow in place unless it is the last one.
+    pub(crate) fn new(name: &str, files: &[(&str, &[u8])]) -> Self {
+        let mut strings = Vec::new();
+        let mut fst = Vec::new();
+
+        // root folder entry: flag, name offset, parent, entry count
+        fst.extend_from_slice(&[1, 0, 0, 0]);
+        fst.extend_from_slice(&0u32.to_be_bytes());
+        fst.extend_from_slice(&(files.len() as u32 + 1).to_be_bytes());
+
+        let mut file_offset = FIRST_FILE_OFFSET;
+        let mut contents = Vec::new();
+        for &(filename, data) in files {
+            fst.extend_from_slice(&(strings.len() as u32).to_be_bytes());
+            fst.extend_from_slice(&(file_offset as u32).to_be_bytes());
+            fst.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            strings.extend_from_slice(filename.as_bytes());
+            strings.push(0);
+
+            contents.push((file_offset, data));
+            file_offset += data.len().next_multiple_of(0x20);
+        }
+        fst.extend_from_slice(&strings);
+
+        let mut iso = vec![0u8; file_offset];
+        iso[0x420..0x424].copy_from_slice(&(DOL_OFFSET as u32).to_be_bytes());
+        iso[0x424..0x428].copy_from_slice(&(FST_OFFSET as u32).to_be_bytes());
+        iso[0x428..0x42C].copy_from_slice(&(fst.len() as u32).to_be_bytes());
+        iso[0x42C..0x430].copy_from_slice(&(fst.len() as u32).to_be_bytes());
+        iso[FST_OFFSET..FST_OFFSET + fst.len()].copy_from_slice(&fst);
+        for (offset, data) in contents {
+            iso[offset..offset + data.len()].copy_from_slice(data);
+        }
+
+        let path = std::env::temp_dir().join(format!("dat_tools_{}_{}.iso", name, std::process::id()));
+        std::fs::write(&path, iso).unwrap();
+        TestIso { path }
+    }
+
+    pub(crate) fn open(&self) -> File {
+        File::options().read(true).write(true).open(&self.path).unwrap()
+    }
+
+    /// (offset, size) stored in the fst entry of the nth file.
+    pub(crate) fn fst_entry(&self, n: usize) -> (usize, usize) {
+        let iso = std::fs::read(&self.path).unwrap();
+        let entry = FST_OFFSET + (n + 1) * 0xC;
+        let word = |i: usize| u32::from_be_bytes(iso[entry + i..entry + i + 4].try_into().unwrap()) as usize;
+        (word(4), word(8))
+    }
+
+    pub(crate) fn read(&self, offset: usize, size: usize) -> Vec<u8> {
+        std::fs::read(&self.path).unwrap()[offset..offset + size].to_vec()
+    }
+}
+
+impl Drop for TestIso {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}